@@ -1,13 +1,19 @@
 use crate::config::Cli;
-use crate::{config, llm, spinner, utils};
+use crate::format::{ResponseFormat, ResponseParser};
+use crate::usage::{Usage, UsageLog, token_cost};
+use crate::{
+    clipboard, config, export, filecache, llm, lock, models, provider, schema, spinner, stream,
+    templates, tools, utils,
+};
 use reqwest;
 use serde_json;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 use std::path::Path;
 use std::time::Instant;
+use tracing::{Level, debug, trace};
 
 #[derive(Debug)]
 pub enum LlmpalError {
@@ -16,6 +22,12 @@ pub enum LlmpalError {
     NetworkError(String),
     ParseError(String),
     FileError(String),
+    BudgetExceeded(String),
+    ValidationError(Vec<String>),
+    UsageError(String),
+    ChangesProposed(usize),
+    DisallowedWrite(Vec<String>),
+    AuthError(String),
 }
 
 impl std::fmt::Display for LlmpalError {
@@ -26,21 +38,288 @@ impl std::fmt::Display for LlmpalError {
             LlmpalError::NetworkError(e) => write!(f, "{}", e),
             LlmpalError::ParseError(e) => write!(f, "{}", e),
             LlmpalError::FileError(e) => write!(f, "{}", e),
+            LlmpalError::BudgetExceeded(e) => write!(f, "Cost budget exceeded: {}", e),
+            LlmpalError::UsageError(e) => write!(f, "{}", e),
+            LlmpalError::ChangesProposed(count) => {
+                write!(
+                    f,
+                    "--no-write: the model returned {} file(s) to write",
+                    count
+                )
+            }
+            LlmpalError::DisallowedWrite(files) => write!(
+                f,
+                "--strict: the model attempted to write disallowed file(s): {}",
+                files.join(", ")
+            ),
+            LlmpalError::AuthError(e) => write!(f, "Authentication failed: {}", e),
+            LlmpalError::ValidationError(problems) => {
+                write!(f, "Cannot run llmpal:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl Error for LlmpalError {}
 
+impl LlmpalError {
+    /// Appends the run's request id to network and parse errors, for
+    /// correlating them with provider-side logs.
+    fn with_request_id(self, request_id: &str) -> Self {
+        match self {
+            LlmpalError::NetworkError(e) => {
+                LlmpalError::NetworkError(format!("{} (request id: {})", e, request_id))
+            }
+            LlmpalError::ParseError(e) => {
+                LlmpalError::ParseError(format!("{} (request id: {})", e, request_id))
+            }
+            LlmpalError::AuthError(e) => {
+                LlmpalError::AuthError(format!("{} (request id: {})", e, request_id))
+            }
+            other => other,
+        }
+    }
+}
+
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
 pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
-    let config = config::get_config();
-    let rules = config.rules.clone().unwrap_or_default();
+    run_with_request_id(args, &new_request_id()).await
+}
+
+/// Runs llmpal with `request_id` sent as the `X-Request-Id` header of every
+/// API request and appended to network and parse errors.
+pub async fn run_with_request_id(args: &config::Cli, request_id: &str) -> Result<(), LlmpalError> {
+    run_inner(args, request_id)
+        .await
+        .map_err(|e| e.with_request_id(request_id))
+}
+
+async fn run_inner(args: &config::Cli, request_id: &str) -> Result<(), LlmpalError> {
+    if args.no_spinner {
+        spinner::disable();
+    }
+    let output_encoding = output_encoding(args)?;
+    if let Some(target) = args.init {
+        let path = config::Config::default_config_path(target).ok_or_else(|| {
+            LlmpalError::FileError("No home directory found for the config".to_string())
+        })?;
+        config::write_example_config(&path).map_err(LlmpalError::FileError)?;
+        eprintln!("# Wrote example config to {}", path.display());
+        return Ok(());
+    }
+
+    if args.list_templates {
+        let names = templates::templates_dir()
+            .map(|dir| templates::list(&dir))
+            .unwrap_or_default();
+        if names.is_empty() {
+            eprintln!("# No templates in ~/.llmpal/templates");
+        }
+        for name in names {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    let args_with_clipboard;
+    let args = if args.clipboard {
+        let mut expanded = args.clone();
+        expanded.instruction = Some(clipboard::read().map_err(LlmpalError::UsageError)?);
+        args_with_clipboard = expanded;
+        &args_with_clipboard
+    } else {
+        args
+    };
+
+    let args_with_template;
+    let args = match &args.template {
+        Some(name) => {
+            let dir = templates::templates_dir().ok_or_else(|| {
+                LlmpalError::FileError("No home directory found for templates".to_string())
+            })?;
+            let mut expanded = args.clone();
+            expanded.instruction = Some(
+                templates::render(&dir, name, args.instruction.as_deref())
+                    .map_err(LlmpalError::FileError)?,
+            );
+            args_with_template = expanded;
+            &args_with_template
+        }
+        None => args,
+    };
+
+    let args_with_files0;
+    let args = match &args.files0_from {
+        Some(source) => {
+            args_with_files0 = with_files0_from(args, source)?;
+            &args_with_files0
+        }
+        None => args,
+    };
+
+    let config =
+        config::apply_profile(args, config::get_config().await).map_err(LlmpalError::UsageError)?;
+    let mut rules = config::get_rules(args, &config);
+    if args.line_numbers {
+        rules.push(llm::LINE_NUMBERS_RULE.to_string());
+    }
+
+    if args.list_rules {
+        if rules.is_empty() {
+            eprintln!("# No rules configured");
+        }
+        for (index, rule) in rules.iter().enumerate() {
+            println!("{}. {}", index + 1, rule);
+        }
+        return Ok(());
+    }
+
+    let mut model_config = config::get_model_config(args, &config);
+
+    if args.fetch_models {
+        return list_models(&model_config).await;
+    }
+
+    if args.forecast {
+        if config.auto_pricing.unwrap_or_default()
+            && (model_config.prompt_cost.is_none() || model_config.completion_cost.is_none())
+        {
+            fill_pricing_from_api(&mut model_config).await;
+        }
+        return forecast(args, &model_config);
+    }
 
-    let model_config = config::get_model_config(args, &config);
+    let args_with_output;
+    let args = match &args.output {
+        Some(output) if output.contains('{') => {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let mut expanded = args.clone();
+            expanded.output = Some(
+                utils::expand_output_template(output, &model_config.code, timestamp)
+                    .map_err(LlmpalError::UsageError)?,
+            );
+            args_with_output = expanded;
+            &args_with_output
+        }
+        _ => args,
+    };
+
+    let args_with_lock_timeout;
+    let args = match (args.lock_timeout, config.lock_timeout) {
+        (None, Some(timeout)) => {
+            let mut expanded = args.clone();
+            expanded.lock_timeout = Some(timeout);
+            args_with_lock_timeout = expanded;
+            &args_with_lock_timeout
+        }
+        _ => args,
+    };
+
+    validate_inputs(args, &model_config)?;
+    if args.context_window_auto {
+        fill_context_window_from_api(&mut model_config).await;
+    }
+    if args.check_provider {
+        check_providers(&model_config).await?;
+    }
+    let schema = args
+        .schema
+        .as_deref()
+        .map(schema::load_schema)
+        .transpose()?;
+
+    if config.auto_pricing.unwrap_or_default()
+        && (model_config.prompt_cost.is_none() || model_config.completion_cost.is_none())
+    {
+        fill_pricing_from_api(&mut model_config).await;
+    }
 
     let mut diagnostic_log = String::new();
+    let mut timings = PhaseTimings::default();
+
+    let read_start = Instant::now();
+    let (mut input_files, mut allowed_files) = prepare_files(&args)?;
+    if !args.force {
+        check_input_file_count(
+            input_files.len(),
+            args.max_input_files.or(config.max_input_files),
+        )?;
+    }
+    if let Some(action) = args.verify_utf8_input {
+        let invalid = find_invalid_inputs(&input_files);
+        if !invalid.is_empty() {
+            let list = invalid
+                .iter()
+                .map(|(path, reason)| format!("  {}: {}", path, reason))
+                .collect::<Vec<_>>()
+                .join("\n");
+            if action == config::InvalidInputAction::Abort {
+                return Err(LlmpalError::FileError(format!(
+                    "{} input file(s) are not readable UTF-8:\n{}\nRemove them or pass --verify-utf8-input=skip",
+                    invalid.len(),
+                    list
+                )));
+            }
+            eprintln!(
+                "# Skipping {} input file(s) that are not readable UTF-8:\n{}",
+                invalid.len(),
+                list
+            );
+            input_files.retain(|path| !invalid.iter().any(|(bad, _)| bad == path));
+            allowed_files.retain(|path| !invalid.iter().any(|(bad, _)| bad == path));
+        }
+    }
+    let mut input_contents = read_input_files(&input_files, &args.output)?;
+
+    if args.stdin_files {
+        for (path, content) in utils::read_tar_archive(io::stdin().lock())? {
+            if !allowed_files.contains(&path)
+                && (args.writable.is_empty() || args.writable.contains(&path))
+            {
+                allowed_files.push(path.clone());
+            }
+            input_files.push(path.clone());
+            input_contents.push((path, content));
+        }
+    }
+    timings.read = read_start.elapsed();
+    check_explanation_file(args, &input_files, &allowed_files)?;
+    let build_start = Instant::now();
+
+    let file_cache = args.skip_unchanged.then(filecache::cache_path).flatten();
+    let unchanged = match &file_cache {
+        Some(path) => filecache::unchanged_files(&filecache::load(path), &input_contents),
+        None => Vec::new(),
+    };
+    if !unchanged.is_empty() {
+        eprintln!(
+            "# Sending {} file(s) unchanged since the last run as references: {}",
+            unchanged.len(),
+            unchanged.join(", ")
+        );
+        allowed_files.retain(|path| !unchanged.contains(path));
+        rules.push(llm::UNCHANGED_FILES_RULE.to_string());
+    }
 
-    let (input_files, allowed_files) = prepare_files(&args)?;
+    if !args.allow_under.is_empty() {
+        rules.push(llm::allow_under_rule(&args.allow_under));
+    }
+    if input_files
+        .iter()
+        .any(|path| utils::split_line_range(path).is_some())
+    {
+        rules.push(llm::LINE_RANGE_RULE.to_string());
+    }
 
     let instruction = match (&args.instruction, &args.instruction_file) {
         (Some(instr), None) => instr.clone(),
@@ -64,41 +343,312 @@ pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
         }
     };
 
-    let api_key = model_config
-        .api_key
-        .or_else(|| std::env::var("OPENROUTER_API_KEY").ok())
-        .ok_or(LlmpalError::ApiKeyMissing)?;
+    let api_key = resolve_api_key(&model_config).ok_or(LlmpalError::ApiKeyMissing)?;
+
+    let response_format = model_config.output_format.unwrap_or_default();
+    let provider = provider::for_format(model_config.api_format.unwrap_or_default());
+    let use_tools = response_format == ResponseFormat::Tools && schema.is_none();
+    if use_tools
+        && (args.stream
+            || args.keep_going_past_max_tokens
+            || args.per_file
+            || args.chunk_tokens.is_some()
+            || !args.sweep_temperature.is_empty()
+            || args.bench.is_some())
+    {
+        return Err(LlmpalError::UsageError(
+            "The tools response format cannot be combined with --stream, --keep-going-past-max-tokens, --per-file, --chunk-tokens, --sweep-temperature or --bench".to_string(),
+        ));
+    }
+    let parser = response_format.parser();
+    let system_prompt = match &schema {
+        Some(_) => llm::build_schema_system_prompt(&rules),
+        None => llm::build_system_prompt(&allowed_files, &rules, parser.as_ref()),
+    };
+    let since_commit_diff = match &args.since_commit {
+        Some(reference) => {
+            let patch = utils::git_diff(reference)?;
+            if patch.trim().is_empty() {
+                eprintln!("> Warning: No changes found since '{}'.", reference);
+            }
+            Some((reference.as_str(), patch))
+        }
+        None => None,
+    };
 
-    let system_prompt = llm::build_system_prompt(&allowed_files, &rules);
-    let user_prompt = llm::build_user_prompt(&instruction, &input_files, &args.output);
+    let command_outputs = args
+        .context_cmd
+        .iter()
+        .map(|command| {
+            utils::command_output(command, MAX_CONTEXT_CMD_BYTES)
+                .map(|output| (command.clone(), output))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let body = build_request(
-        &model_config.model,
-        model_config.provider.as_deref(),
-        &system_prompt,
-        &user_prompt,
-        model_config
-            .max_tokens
-            .unwrap_or(config::DEFAULT_MAX_TOKENS),
-        model_config.api_url.is_none(),
-    )
-    .map_err(|e| LlmpalError::SerializeError(e.to_string()))?;
+    let tree = args.with_tree.then(|| utils::format_tree(&input_files));
+    let file_diffs: Vec<(String, String)> = if args.with_file_diff {
+        input_files
+            .iter()
+            .filter_map(|path| utils::git_file_diff(path).map(|patch| (path.clone(), patch)))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let adjusted_contents: Vec<(String, String)>;
+    let prompt_contents = if args.line_numbers || !unchanged.is_empty() {
+        adjusted_contents = input_contents
+            .iter()
+            .map(|(path, content)| {
+                let content = if unchanged.contains(path) {
+                    llm::UNCHANGED_FILE_PLACEHOLDER.to_string()
+                } else if args.line_numbers {
+                    llm::number_lines(content)
+                } else {
+                    content.clone()
+                };
+                (path.clone(), content)
+            })
+            .collect();
+        &adjusted_contents
+    } else {
+        &input_contents
+    };
+    let user_prompt = llm::build_user_prompt(
+        &instruction,
+        prompt_contents,
+        &args.output,
+        since_commit_diff
+            .as_ref()
+            .map(|(reference, patch)| (*reference, patch.as_str())),
+        tree.as_deref(),
+        &command_outputs,
+        &file_diffs,
+    );
 
-    if args.trace {
-        eprintln!("::DEBUG:: === RAW LLM REQUEST ===");
+    let mut max_tokens = if args.no_max_tokens {
+        None
+    } else {
+        model_config.max_tokens()
+    };
+    // Every request of the run is clamped the same way, so warn only once.
+    if let Some(requested) = max_tokens
+        && let Some(capped) = capped_max_tokens(max_tokens, model_config.max_completion_cap)
+        && capped != requested
+    {
         eprintln!(
-            "::DEBUG:: {}",
-            serde_json::to_string_pretty(
-                &serde_json::from_str::<serde_json::Value>(&body).unwrap()
-            )
-            .unwrap()
+            "> Warning: max_tokens {} exceeds the model's max_completion_cap; sending {}",
+            requested, capped
         );
     }
-    if args.verbose {
-        eprintln!("::DEBUG:: === SYSTEM PROMPT ===");
-        eprintln!("::DEBUG:: {}", system_prompt);
-        eprintln!("::DEBUG:: === USER PROMPT ===");
-        eprintln!("::DEBUG:: {}", user_prompt);
+
+    let mut request_options = RequestOptions {
+        model: model_config.request_model(),
+        provider: model_config.provider.as_deref(),
+        ignore_providers: model_config.ignore_providers.as_deref(),
+        max_tokens,
+        max_completion_cap: model_config.max_completion_cap,
+        temperature: request_temperature(args, &model_config, &config, &allowed_files),
+        stop: model_config.stop_sequences(args),
+        stream: args.stream,
+        is_default_api_url: model_config.api_url.is_none(),
+        no_provider_block: args.no_provider_block,
+        data_collection: args.data_collection,
+        response_schema: schema.as_ref(),
+        reasoning: args
+            .reasoning
+            .as_deref()
+            .or(model_config.reasoning_effort.as_deref()),
+        examples: config.examples.as_deref().unwrap_or_default(),
+        no_system: args.no_system || model_config.no_system.unwrap_or_default(),
+        split_system: config.split_system_prompt.unwrap_or_default(),
+        extra_body: model_config.extra_body.as_ref(),
+        extra_body_override: model_config.extra_body_override.unwrap_or_default(),
+        tools: use_tools,
+    };
+
+    let mut budget = Budget {
+        model_config: &model_config,
+        max_cost: args.max_cost.or(config.max_cost),
+        failed: 0.0,
+    };
+
+    let api_url = model_config
+        .api_url
+        .clone()
+        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
+    let client = http_client(&model_config, request_id)?;
+    let endpoint = Endpoint {
+        client: &client,
+        api_key: &api_key,
+        api_url: &api_url,
+        provider: provider.as_ref(),
+    };
+
+    if args.per_file {
+        let diff = since_commit_diff
+            .as_ref()
+            .map(|(reference, patch)| (*reference, patch.as_str()));
+        let mut jobs = Vec::new();
+        let mut estimated_tokens = 0;
+        for (path, content) in prompt_contents {
+            let system_prompt =
+                llm::build_system_prompt(std::slice::from_ref(path), &rules, parser.as_ref());
+            let user_prompt = llm::build_user_prompt(
+                &instruction,
+                &[(path.clone(), content.clone())],
+                &None,
+                diff,
+                tree.as_deref(),
+                &command_outputs,
+                &file_diffs,
+            );
+            estimated_tokens +=
+                estimate_token_count(&system_prompt) + estimate_token_count(&user_prompt);
+            let body = provider.build_body(&request_options, &system_prompt, &user_prompt)?;
+            jobs.push((path.clone(), body));
+        }
+        // The requests run concurrently, so all of them must fit the budget.
+        budget.check(
+            &UsageLog::default(),
+            jobs.iter()
+                .map(|(_, body)| budget.request_cost(estimate_token_count(body), max_tokens))
+                .sum(),
+        )?;
+
+        eprintln!(
+            "# Model: {} | Per-file requests: {} (concurrency {}) | Estimated input tokens: {}",
+            model_config.model,
+            jobs.len(),
+            args.concurrency,
+            estimated_tokens
+        );
+        if args.confirm && !confirm(args, "Proceed? [y/N]: ")? {
+            return Ok(());
+        }
+        return run_per_file(
+            jobs,
+            &endpoint,
+            (&config, &model_config),
+            parser.as_ref(),
+            args.concurrency,
+            args,
+        )
+        .await;
+    }
+
+    if let Some(chunk_tokens) = args.chunk_tokens {
+        let diff = since_commit_diff
+            .as_ref()
+            .map(|(reference, patch)| (*reference, patch.as_str()));
+        let chunks = chunk_files(prompt_contents, chunk_tokens);
+        let mut bodies = Vec::new();
+        let mut estimated_tokens = 0;
+        for chunk in &chunks {
+            // A chunk may only write the files it was sent, and the output.
+            let chunk_allowed: Vec<String> = allowed_files
+                .iter()
+                .filter(|path| {
+                    args.output_file() == Some(*path)
+                        || chunk.iter().any(|(input, _)| input == *path)
+                })
+                .cloned()
+                .collect();
+            let system_prompt = match &schema {
+                Some(_) => llm::build_schema_system_prompt(&rules),
+                None => llm::build_system_prompt(&chunk_allowed, &rules, parser.as_ref()),
+            };
+            let user_prompt = llm::build_user_prompt(
+                &instruction,
+                chunk,
+                &args.output,
+                diff,
+                tree.as_deref(),
+                &command_outputs,
+                &file_diffs,
+            );
+            estimated_tokens +=
+                estimate_token_count(&system_prompt) + estimate_token_count(&user_prompt);
+            let body = provider.build_body(&request_options, &system_prompt, &user_prompt)?;
+            bodies.push((body, chunk_allowed));
+        }
+
+        eprintln!(
+            "# Model: {} | Chunks: {} (up to {} tokens each) | Estimated input tokens: {}",
+            model_config.model,
+            bodies.len(),
+            chunk_tokens,
+            estimated_tokens
+        );
+        if args.confirm && !confirm(args, "Proceed? [y/N]: ")? {
+            return Ok(());
+        }
+        return run_chunked(
+            bodies,
+            &endpoint,
+            &config,
+            (&budget, max_tokens),
+            parser.as_ref(),
+            args,
+        )
+        .await;
+    }
+
+    if args.no_provider_block
+        && (model_config.provider.is_some() || model_config.ignore_providers.is_some())
+    {
+        eprintln!(
+            "> Warning: --no-provider-block ignores the configured provider and ignore_providers settings."
+        );
+    }
+
+    let estimated_input_tokens =
+        estimate_token_count(&system_prompt) + estimate_token_count(&user_prompt);
+
+    if let Some(context_window) = model_config.context_window {
+        if estimated_input_tokens >= context_window {
+            eprintln!(
+                "\n# WARNING: Estimated input tokens ({}) exceed the context window of {}",
+                estimated_input_tokens, context_window
+            );
+            if !confirm(args, "Proceed anyway? (y/N): ")? {
+                return Ok(());
+            }
+        } else {
+            let limit = capped_max_tokens(max_tokens, model_config.max_completion_cap);
+            let fitted = fit_context_window(estimated_input_tokens, limit, context_window);
+            if fitted != limit {
+                eprintln!(
+                    "> Warning: max_tokens reduced to {} to fit the context window of {} tokens",
+                    fitted.unwrap_or_default(),
+                    context_window
+                );
+                max_tokens = fitted;
+                request_options.max_tokens = fitted;
+            }
+        }
+    }
+
+    let mut body = provider.build_body(&request_options, &system_prompt, &user_prompt)?;
+    timings.build = build_start.elapsed();
+
+    if tracing::enabled!(Level::TRACE) {
+        let mut request = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        if args.trace_redact_files {
+            redact_json_strings(&mut request, parser.as_ref());
+        }
+        trace!(
+            "=== RAW LLM REQUEST ===\n{}",
+            serde_json::to_string_pretty(&request).unwrap()
+        );
+    }
+    debug!("=== SYSTEM PROMPT ===\n{}", system_prompt);
+    if args.trace_redact_files {
+        debug!(
+            "=== USER PROMPT ===\n{}",
+            llm::redact_file_contents(&user_prompt)
+        );
+    } else {
+        debug!("=== USER PROMPT ===\n{}", user_prompt);
     }
 
     if config.diagnostic.unwrap_or_default() {
@@ -112,31 +662,14 @@ pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
         ));
     }
 
-    let api_url = model_config
-        .api_url
-        .clone()
-        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
-
-    let estimated_input_tokens =
-        estimate_token_count(&system_prompt) + estimate_token_count(&user_prompt);
-
-    let max_tokens_allowed = model_config
-        .max_tokens
-        .unwrap_or(config::DEFAULT_MAX_TOKENS);
-
-    if estimated_input_tokens > max_tokens_allowed {
+    if let Some(max_tokens_allowed) = max_tokens
+        && estimated_input_tokens > max_tokens_allowed
+    {
         eprintln!(
             "\n# WARNING: Estimated token count ({}) exceeds max token limit ({})",
             estimated_input_tokens, max_tokens_allowed
         );
-        eprint!("Proceed anyway? (y/N): ");
-        io::stdout().flush().unwrap();
-
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        let input = input.trim().to_lowercase();
-
-        if input != "y" && input != "yes" {
+        if !confirm(args, "Proceed anyway? (y/N): ")? {
             return Ok(());
         }
     }
@@ -147,8 +680,8 @@ pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
             model_config.model,
             provider,
             api_url,
-            model_config.prompt_cost,
-            model_config.completion_cost,
+            model_config.prompt_cost(),
+            model_config.completion_cost(),
             estimated_input_tokens
         )
     } else {
@@ -156,39 +689,243 @@ pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
             "# Model: {} | URL: {} | Cost: ${:.4}/1M prompt, ${:.4}/1M completion | Estimated input tokens: {}",
             model_config.model,
             api_url,
-            model_config.prompt_cost,
-            model_config.completion_cost,
+            model_config.prompt_cost(),
+            model_config.completion_cost(),
             estimated_input_tokens
         )
     };
 
     eprintln!("{}", log_output);
+
+    if args.confirm && !confirm(args, "Proceed? [y/N]: ")? {
+        return Ok(());
+    }
+
+    if !args.sweep_temperature.is_empty() {
+        let mut bodies = Vec::new();
+        for temperature in &args.sweep_temperature {
+            let options = RequestOptions {
+                temperature: Some(*temperature),
+                ..request_options.clone()
+            };
+            let body = provider.build_body(&options, &system_prompt, &user_prompt)?;
+            bodies.push((*temperature, body));
+        }
+        // The requests run concurrently, so all of them must fit the budget.
+        budget.check(
+            &UsageLog::default(),
+            bodies.len() as f64 * budget.request_cost(estimated_input_tokens, max_tokens),
+        )?;
+        return run_temperature_sweep(
+            bodies,
+            &endpoint,
+            &input_contents,
+            &model_config,
+            parser.as_ref(),
+        )
+        .await;
+    }
+
+    if let Some(runs) = args.bench {
+        let run_cost = budget.request_cost(estimated_input_tokens, max_tokens);
+        return run_bench(runs, args, &body, &endpoint, (budget, run_cost)).await;
+    }
+
     let start_time = Instant::now();
 
     let loading = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
-    let spinner_handle = spinner::setup_spinner(loading.clone(), Some("Waiting for LLM response"));
+    // The explanation of a streamed response is printed as it arrives, so no spinner.
+    // Dedenting needs the whole explanation, so it is printed after the response.
+    let live_explanation = args.stream
+        && schema.is_none()
+        && response_format == ResponseFormat::Tags
+        && !args.dedent_explanation;
+    let spinner_handle = (!live_explanation)
+        .then(|| spinner::setup_spinner(loading.clone(), Some("Waiting for LLM response")));
+    let mut printed_explanation = None;
 
-    let res = send_api_request(&api_key, &api_url, &body)
-        .await
-        .map_err(|e| LlmpalError::NetworkError(e))?;
+    let mut attempt_cost = budget.request_cost(estimated_input_tokens, max_tokens);
+    let mut attempt = 0;
+
+    let retry_deadline = args.retry_deadline.map(std::time::Duration::from_secs);
+    let max_retries = args.retries.unwrap_or(if retry_deadline.is_some() {
+        u32::MAX
+    } else {
+        0
+    });
+
+    let mut usage_log = UsageLog::default();
+    let mut context_retried = false;
+    let res = loop {
+        if let Err(e) = budget.check(&usage_log, attempt_cost) {
+            break Err(e);
+        }
+        let mut live_parser = live_explanation.then(llm::StreamingParser::default);
+        let mut printed_lines = 0;
+        let request = async {
+            if args.stream {
+                let mut print_explanation = |delta: &str| {
+                    if let Some(parser) = live_parser.as_mut() {
+                        for line in parser.feed(delta) {
+                            print_explanation_line(&line);
+                            printed_lines += 1;
+                        }
+                    }
+                };
+                stream::read_stream(
+                    &client,
+                    &api_key,
+                    &api_url,
+                    &body,
+                    args.timeout_stream_idle.map(std::time::Duration::from_secs),
+                    &mut print_explanation,
+                )
+                .await
+                .map(|(accumulator, bytes)| (accumulator.into_response(), bytes))
+            } else {
+                post_request(&endpoint, args, &body).await
+            }
+        };
+        let result = match retry_deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_sub(start_time.elapsed());
+                tokio::time::timeout(remaining, request)
+                    .await
+                    .unwrap_or_else(|_| Err("retry deadline exceeded".to_string()))
+            }
+            None => request.await,
+        };
+        match result {
+            Ok(res) => {
+                printed_explanation = live_parser
+                    .and_then(|parser| parser.finish().ok())
+                    .map(|parsed| parsed.explanation);
+                if let Ok((_, usage)) = provider.extract(&res.0) {
+                    usage_log.record("request", usage);
+                }
+                break Ok(res);
+            }
+            Err(e) if is_auth_failure(&e) => break Err(api_error(&model_config, e)),
+            Err(e) => {
+                // A retry streams the explanation again from the start.
+                if printed_lines > 0 {
+                    eprintln!("# The explanation above was cut off by the failed request");
+                }
+                if !context_retried
+                    && let Some(fitted) = context_length_retry_tokens(
+                        &e,
+                        estimated_input_tokens,
+                        model_config.context_window,
+                    )
+                {
+                    context_retried = true;
+                    budget.failed += attempt_cost;
+                    eprintln!(
+                        "# Request exceeded the context window - retrying once with max_tokens {}",
+                        fitted
+                    );
+                    max_tokens = Some(fitted);
+                    request_options.max_tokens = max_tokens;
+                    attempt_cost = budget.request_cost(estimated_input_tokens, max_tokens);
+                    body = provider.build_body(&request_options, &system_prompt, &user_prompt)?;
+                    continue;
+                }
+                let Some(delay) =
+                    next_retry_delay(attempt, max_retries, retry_deadline, start_time.elapsed())
+                        .filter(|_| is_retryable(&e))
+                else {
+                    break Err(LlmpalError::NetworkError(e));
+                };
+                budget.failed += attempt_cost;
+                attempt += 1;
+                match args.retries {
+                    Some(retries) => eprintln!(
+                        "# Request failed: {} - retrying ({}/{})",
+                        e, attempt, retries
+                    ),
+                    None => eprintln!("# Request failed: {} - retrying ({})", e, attempt),
+                }
+                tokio::time::sleep(delay).await;
+            }
+        }
+    };
+
+    let res = match (res, args.min_tokens.or(config.min_completion_tokens)) {
+        (Ok(response), Some(min_tokens)) => {
+            follow_up_short_completion(
+                &endpoint,
+                &body,
+                args,
+                response,
+                min_tokens,
+                (&budget, max_tokens),
+                &mut usage_log,
+            )
+            .await
+        }
+        (res, _) => res,
+    };
+
+    let res = match res {
+        Ok(response) if args.keep_going_past_max_tokens => {
+            continue_truncated(
+                &endpoint,
+                &body,
+                args,
+                response,
+                (&budget, max_tokens),
+                &mut usage_log,
+            )
+            .await
+        }
+        res => res,
+    };
+
+    let res = match res {
+        Ok(response) if use_tools => {
+            let is_allowed =
+                |path: &str| allowed_path(&allowed_files, &args.allow_under, path).is_some();
+            run_tool_calls(
+                &endpoint,
+                &body,
+                response,
+                args,
+                &is_allowed,
+                (&budget, max_tokens),
+                &mut usage_log,
+            )
+            .await
+        }
+        res => res,
+    };
 
     let duration = start_time.elapsed();
+    timings.network = duration;
+    let parse_start = Instant::now();
     loading.store(false, std::sync::atomic::Ordering::Relaxed);
-    spinner_handle.join().unwrap();
+    if let Some(handle) = spinner_handle {
+        handle.join();
+    }
+
+    let (mut res, mut response_bytes) = res?;
 
-    if args.trace {
-        eprintln!("::DEBUG:: === RAW LLM RESPONSE ===");
-        eprintln!("::DEBUG:: {}", serde_json::to_string_pretty(&res).unwrap());
+    if tracing::enabled!(Level::TRACE) {
+        let mut response = res.clone();
+        if args.trace_redact_files {
+            redact_json_strings(&mut response, parser.as_ref());
+        }
+        trace!(
+            "=== RAW LLM RESPONSE ===\n{}",
+            serde_json::to_string_pretty(&response).unwrap()
+        );
     }
 
-    let resp_text = res["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| LlmpalError::ParseError("Invalid response format from API".to_string()))?
-        .to_string();
+    let (resp_text, mut last_usage) = provider.extract(&res)?;
 
-    if args.verbose {
-        eprintln!("::DEBUG:: === RAW LLM OUTPUT ===");
-        eprintln!("::DEBUG:: {}", resp_text);
+    if args.trace_redact_files {
+        debug!("=== RAW LLM OUTPUT ===\n{}", parser.redact(&resp_text));
+    } else {
+        debug!("=== RAW LLM OUTPUT ===\n{}", resp_text);
     }
 
     if config.diagnostic.unwrap_or_default() {
@@ -196,239 +933,4099 @@ pub async fn run(args: &config::Cli) -> Result<(), LlmpalError> {
         utils::write_diagnostic_log(&diagnostic_log)?;
     }
 
-    let parse_result = llm::parse_llm_response(&resp_text);
-    let (comments, files, _) = match parse_result {
-        Ok(result) => result,
-        Err(e) => {
-            return handle_parse_error(&resp_text, e);
+    if let Some(dump_path) = &args.dump_response {
+        let dump = if args.trace {
+            serde_json::to_string_pretty(&res).unwrap_or_default()
+        } else {
+            resp_text.clone()
+        };
+        match utils::write_dump_to(Path::new(dump_path), &dump) {
+            Ok(()) => eprintln!("# Saved response to {}", dump_path),
+            Err(e) => eprintln!("{}", e),
         }
-    };
-
-    if comments.is_empty() && files.is_empty() {
-        return handle_parse_error(
-            &resp_text,
-            "Missing or malformed LLM response. See dump log for details.".to_string(),
-        );
     }
 
-    let mut disallowed_files = Vec::new();
-    for (path, _) in &files {
-        if !allowed_files.contains(path) {
-            disallowed_files.push(path);
-        }
-    }
+    let mut proposed_changes = 0;
+    // The final response, and whether its changes were written, for --export-jsonl.
+    let mut exported_response = resp_text.clone();
+    let mut applied = false;
+    if let Some(schema) = &schema {
+        write_schema_output(args, schema, &resp_text)?;
+        applied = !args.summary_only;
+    } else {
+        let resp_text = unwrap_json_encoded(parser.as_ref(), resp_text);
+        let parsed = match parser.parse(&resp_text) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return handle_parse_error(&resp_text, e);
+            }
+        };
+        let files = parsed.file_pairs();
+        let (comments, remaining) = (parsed.explanation, parsed.extra);
 
-    if !disallowed_files.is_empty() {
-        return handle_parse_error(
-            &resp_text,
-            format!(
-                "attempting to write to disallowed file(s): {} - see dump log for details",
-                disallowed_files
+        let missing_output = args.output_file().filter(|output| {
+            args.require_output
+                && !files
                     .iter()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<&str>>()
-                    .join(", ")
-            ),
-        );
-    }
+                    .any(|(path, _)| utils::paths_match(path, output))
+        });
+        let (resp_text, comments, mut files, remaining) = match missing_output {
+            Some(output) => {
+                eprintln!(
+                    "# The response does not contain {} - asking the model for it",
+                    output
+                );
+                let follow_up_body = provider.with_follow_up(
+                    &body,
+                    &resp_text,
+                    &llm::missing_output_follow_up(output),
+                )?;
+                budget.check(
+                    &usage_log,
+                    budget.request_cost(estimate_token_count(&follow_up_body), max_tokens),
+                )?;
+                (res, response_bytes) = send_body(&endpoint, args, &follow_up_body).await?;
+                let (resp_text, usage) = provider.extract(&res)?;
+                usage_log.record("missing output follow-up", usage);
+                last_usage = usage;
+                let resp_text = unwrap_json_encoded(parser.as_ref(), resp_text);
+                let parsed = match parser.parse(&resp_text) {
+                    Ok(parsed) => parsed,
+                    Err(e) => return handle_parse_error(&resp_text, e),
+                };
+                let files = parsed.file_pairs();
+                let (comments, remaining) = (parsed.explanation, parsed.extra);
+                if !files
+                    .iter()
+                    .any(|(path, _)| utils::paths_match(path, output))
+                {
+                    return handle_parse_error(
+                        &resp_text,
+                        format!(
+                            "the model did not produce the required output file '{}'",
+                            output
+                        ),
+                    );
+                }
+                (resp_text, comments, files, remaining)
+            }
+            None => (resp_text, comments, files, remaining),
+        };
+        exported_response.clone_from(&resp_text);
+        let comments = explanation_text(args, comments);
 
-    if !comments.is_empty() {
-        println!("{}", comments);
-    }
-
-    for (path, content) in files.iter() {
-        fs::write(path, content)
-            .map_err(|e| LlmpalError::FileError(format!("writing file '{}': {}", path, e)))?;
-    }
-
-    let usage = &res["usage"];
-    let provider_response = res.get("provider").and_then(|p| p.as_str());
-    if let Some(prompt_tokens) = usage["prompt_tokens"].as_u64() {
-        if let Some(completion_tokens) = usage["completion_tokens"].as_u64() {
-            let prompt_cost_val = prompt_tokens as f64 * model_config.prompt_cost / 1_000_000.0;
-            let completion_cost_val =
-                completion_tokens as f64 * model_config.completion_cost / 1_000_000.0;
-            let total_cost = prompt_cost_val + completion_cost_val;
-            let tokens_per_second =
-                (prompt_tokens + completion_tokens) as f64 / duration.as_secs_f64();
-            let model_string = if let Some(provider_name) = provider_response {
-                format!("{} [provider: {}]", model_config.model, provider_name)
-            } else {
-                model_config.model.clone()
-            };
-            eprintln!(
-                "# Model: {} | Prompt tokens: {} (${:.4}) | Completion tokens: {} (${:.4}) | Total tokens: {} (${:.4}) | Time: {:.2}s | Speed: {:.2} tokens/s",
-                model_string,
-                prompt_tokens,
-                prompt_cost_val,
-                completion_tokens,
-                completion_cost_val,
-                prompt_tokens + completion_tokens,
-                total_cost,
-                duration.as_secs_f64(),
-                tokens_per_second
+        if let Some(extra) = extra_section(args, &remaining) {
+            eprintln!("{}", extra);
+        }
+
+        if comments.is_empty() && files.is_empty() {
+            return handle_parse_error(
+                &resp_text,
+                "Missing or malformed LLM response. See dump log for details.".to_string(),
             );
+        }
 
-            let max_tokens_allowed = model_config
-                .max_tokens
-                .unwrap_or(config::DEFAULT_MAX_TOKENS) as u64;
-            if completion_tokens >= max_tokens_allowed {
+        if !args.no_strip_fences {
+            for (path, content) in files.iter_mut() {
+                *content = llm::strip_wrapping_fence(path, content);
+            }
+        }
+        if args.line_numbers {
+            for (_, content) in files.iter_mut() {
+                *content = llm::strip_line_numbers(content);
+            }
+        }
+        if args.strip_trailing_ws {
+            for (_, content) in files.iter_mut() {
+                *content = utils::strip_trailing_whitespace(content);
+            }
+        }
+        for (path, content) in &files {
+            let had_replacement = input_contents
+                .iter()
+                .any(|(input_path, old)| input_path == path && old.contains('\u{FFFD}'));
+            if content.contains('\u{FFFD}') && !had_replacement {
                 eprintln!(
-                    "# Warning: Completion tokens ({}) equal or exceed max token limit ({}). Output might be missing or incomplete.",
-                    completion_tokens, max_tokens_allowed
+                    "> Warning: {} contains U+FFFD replacement characters, likely from undecodable model output",
+                    path
                 );
             }
         }
-    }
 
-    Ok(())
-}
+        let mut disallowed_files = Vec::new();
+        for (path, _) in files.iter_mut() {
+            match allowed_path(&allowed_files, &args.allow_under, path) {
+                Some(allowed) => *path = allowed,
+                None => disallowed_files.push(path.clone()),
+            }
+        }
 
-fn handle_parse_error(resp_text: &str, error_msg: String) -> Result<(), LlmpalError> {
-    match utils::write_dump_log(&resp_text) {
-        Ok(filename) => eprintln!("# Created dump file: {}", filename),
-        Err(e) => eprintln!("{}", e),
-    }
-    Err(LlmpalError::ParseError(error_msg))
-}
+        if !disallowed_files.is_empty() {
+            return reject_disallowed(args, &resp_text, disallowed_files);
+        }
 
-fn prepare_files(args: &&Cli) -> Result<(Vec<String>, Vec<String>), LlmpalError> {
-    let mut allowed_files_set: HashSet<String> = HashSet::new();
-    let mut input_files: Vec<String> = Vec::new();
+        let protected_files: Vec<&str> = files
+            .iter()
+            .filter(|(path, content)| {
+                input_contents
+                    .iter()
+                    .find(|(input_path, _)| input_path == path)
+                    .is_some_and(|(_, old)| utils::protected_regions_changed(old, content))
+            })
+            .map(|(path, _)| path.as_str())
+            .collect();
+        if !protected_files.is_empty() {
+            return handle_parse_error(
+                &resp_text,
+                format!(
+                    "protected region(s) changed in: {} - see dump log for details",
+                    protected_files.join(", ")
+                ),
+            );
+        }
 
-    for file in &args.files {
-        let path = Path::new(file);
-        if path.is_dir() {
-            let entries = fs::read_dir(path).map_err(|e| {
-                LlmpalError::FileError(format!("Cannot read directory '{}': {}", file, e))
-            })?;
-            for entry in entries {
-                let entry = entry.map_err(|e| {
-                    LlmpalError::FileError(format!("Error reading entry in '{}': {}", file, e))
-                })?;
-                let entry_path = entry.path();
-                if entry_path.is_dir() {
-                    continue;
-                }
-                if let Some(entry_str) = entry_path.as_os_str().to_str() {
-                    allowed_files_set.insert(entry_str.to_string());
-                    input_files.push(entry_str.to_string());
-                }
+        if !comments.is_empty() && printed_explanation.as_deref() != Some(comments.as_str()) {
+            eprintln!("{}", comments);
+        }
+        // A captured transcript shows the explanation the cost table belongs to.
+        if args.show_cost_in_output && !args.output_to_stdout() && !comments.is_empty() {
+            println!("{}", comments);
+        }
+
+        if let Some(explanation_file) = &args.explanation_file {
+            utils::write_atomic(explanation_file, &format!("{}\n", comments.trim_end()))?;
+            eprintln!("# Wrote explanation to {}", explanation_file);
+        }
+
+        timings.parse = parse_start.elapsed();
+        let write_start = Instant::now();
+        if args.summary_only {
+            print_planned_files(&input_contents, &files);
+        } else if args.no_write {
+            let changed = print_proposed_changes(&input_contents, &files);
+            if changed < files.len() {
+                eprintln!(
+                    "# {} returned file(s) match their current content",
+                    files.len() - changed
+                );
             }
+            // Any returned file fails the check, even an identical rewrite.
+            proposed_changes = files.len();
+        } else if let Some(patch_path) = &args.diff_only_output {
+            write_patch(patch_path, &input_contents, &files)?;
         } else {
-            allowed_files_set.insert(file.clone());
-            input_files.push(file.clone());
+            if !args.output_to_stdout()
+                && !args.force_shrink
+                && !confirm_shrunk_files(args, &config, &input_contents, &files)?
+            {
+                return Ok(());
+            }
+
+            if args.stdin_files {
+                utils::write_tar_archive(io::stdout().lock(), &files)?;
+            } else if args.output_to_stdout() {
+                print_files(&files);
+            } else {
+                let targets: Vec<&str> = files
+                    .iter()
+                    .map(|(path, _)| path.as_str())
+                    .filter(|path| *path != config::CLIPBOARD_OUTPUT)
+                    .collect();
+                let _locks = lock_targets(args, &targets).await?;
+                for (path, content) in files.iter() {
+                    if args.output_to_clipboard() && path == config::CLIPBOARD_OUTPUT {
+                        clipboard::write(content).map_err(LlmpalError::FileError)?;
+                        eprintln!(
+                            "# Copied output to the clipboard ({})",
+                            utils::format_size(content.len())
+                        );
+                        continue;
+                    }
+                    let old = input_contents
+                        .iter()
+                        .find(|(input, _)| input == path)
+                        .map(|(_, old)| old.clone())
+                        .or_else(|| utils::read_text_file(path).ok());
+                    utils::create_parent_dirs(path)?;
+                    utils::write_encoded(path, content, output_encoding)?;
+                    eprintln!("{}", write_report(path, old.as_deref(), content));
+                }
+            }
+            applied = true;
+        }
+        timings.write = write_start.elapsed();
+
+        if !args.output_to_stdout()
+            && !args.no_write
+            && !args.summary_only
+            && args.diff_only_output.is_none()
+            && (!files.is_empty() || !input_files.is_empty())
+        {
+            let summary = summarize_changes(&input_files, &files);
+            eprintln!("{}", summary);
         }
     }
 
-    if let Some(output) = &args.output {
-        allowed_files_set.insert(output.clone());
+    // Only a run whose changes were applied marks its inputs as seen.
+    if applied
+        && let Some(path) = &file_cache
+        && let Err(e) = lock::with_global(|| filecache::save(path, &input_contents)).await
+    {
+        eprintln!("> Warning: {}", e);
     }
-    let allowed_files: Vec<String> = allowed_files_set.into_iter().collect();
-    Ok((input_files, allowed_files))
+
+    // Totals over every request of the run; follow-ups and continuations
+    // are paid for too.
+    if let Some(usage) = usage_log.total() {
+        let prompt_tokens = usage.prompt_tokens;
+        let cost = usage.cost(&model_config);
+        let tokens_per_second = usage.total_tokens() as f64 / duration.as_secs_f64();
+        let model_string = served_model_label(&model_config, &res);
+        let completion_label = usage.completion_label();
+        eprintln!(
+            "# Model: {} | Prompt tokens: {} (${:.4}) | Completion tokens: {} (${:.4}) | Total tokens: {} (${:.4}) | Time: {:.2}s | Speed: {:.2} tokens/s | Request: {} bytes | Response: {} bytes",
+            model_string,
+            usage.prompt_label(),
+            cost.prompt,
+            completion_label,
+            cost.completion,
+            usage.total_tokens(),
+            cost.total,
+            duration.as_secs_f64(),
+            tokens_per_second,
+            body.len(),
+            response_bytes
+        );
+        if usage_log.len() > 1 {
+            eprintln!("# Requests: {}", usage_log.len());
+        }
+        if args.verbose {
+            eprint!("{}", usage_log.breakdown(&model_config));
+        }
+        if args.show_cost_in_output && !args.output_to_stdout() {
+            print!(
+                "{}",
+                cost_footer(
+                    &model_string,
+                    prompt_tokens,
+                    &completion_label,
+                    cost.total,
+                    duration
+                )
+            );
+        }
+        // The estimate covers the prompt of the first request only.
+        let first_prompt_tokens = usage_log.first().map_or(0, |first| first.prompt_tokens);
+        if let Some(error) =
+            estimate_error_percent(estimated_input_tokens as u64, first_prompt_tokens)
+        {
+            eprintln!(
+                "# Token estimate: {} vs {} actual prompt tokens ({:+.1}%)",
+                estimated_input_tokens, first_prompt_tokens, error
+            );
+        }
+
+        if let Some(max_tokens_allowed) =
+            capped_max_tokens(max_tokens, model_config.max_completion_cap).map(|m| m as u64)
+            && last_usage.completion_tokens >= max_tokens_allowed
+        {
+            eprintln!(
+                "# Warning: Completion tokens ({}) equal or exceed max token limit ({}). Output might be missing or incomplete.",
+                last_usage.completion_tokens, max_tokens_allowed
+            );
+        }
+    }
+
+    if let Some(export_path) = &args.export_jsonl
+        && (applied || !args.export_applied_only)
+    {
+        let record = export::fine_tune_record(&system_prompt, &user_prompt, &exported_response);
+        export::append_record(Path::new(export_path), &record).map_err(LlmpalError::FileError)?;
+        eprintln!("# Exported the run to {}", export_path);
+    }
+
+    if args.verbose {
+        eprintln!("{}", timings);
+    }
+
+    if proposed_changes > 0 {
+        return Err(LlmpalError::ChangesProposed(proposed_changes));
+    }
+
+    Ok(())
 }
 
-pub async fn send_api_request(
-    api_key: &str,
-    api_url: &str,
-    body: &str,
-) -> Result<serde_json::Value, String> {
-    let client = reqwest::Client::new();
+/// Wall-clock time of each phase of a run, printed with `--verbose`.
+#[derive(Debug, Default)]
+struct PhaseTimings {
+    /// Collecting and reading the input files.
+    read: std::time::Duration,
+    /// Everything else up to the serialized request: diffs, `--context-cmd` output, prompts.
+    build: std::time::Duration,
+    /// The API request, including retries.
+    network: std::time::Duration,
+    /// Parsing and checking the response.
+    parse: std::time::Duration,
+    /// Writing or printing the files.
+    write: std::time::Duration,
+}
 
-    let response = client
-        .post(api_url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .header("HTTP-Referer", "https://github.com/00dev-org/llmpal")
-        .header("X-Title", "llmpal")
-        .body(body.to_string())
-        .send()
-        .await
-        .map_err(|e| format!("Failed to send request: {}", e))?;
+impl std::fmt::Display for PhaseTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "# Timing: read {:.3}s | prompt {:.3}s | network {:.3}s | parse {:.3}s | write {:.3}s",
+            self.read.as_secs_f64(),
+            self.build.as_secs_f64(),
+            self.network.as_secs_f64(),
+            self.parse.as_secs_f64(),
+            self.write.as_secs_f64()
+        )
+    }
+}
 
-    let status_code = response.status();
-    if !status_code.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .map_err(|e| format!("Failed to read error response: {}", e))?;
-        return Err(format!(
-            "API request failed with status {}: {}",
-            status_code, error_text
+/// The `--show-cost-in-output` footer: a markdown table after the run's output.
+fn cost_footer(
+    model: &str,
+    prompt_tokens: u64,
+    completion_label: &str,
+    total_cost: f64,
+    duration: std::time::Duration,
+) -> String {
+    format!(
+        "\n| Model | Prompt tokens | Completion tokens | Cost | Time |\n\
+         |---|---|---|---|---|\n\
+         | {} | {} | {} | ${:.4} | {:.2}s |\n",
+        model,
+        prompt_tokens,
+        completion_label,
+        total_cost,
+        duration.as_secs_f64()
+    )
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ChangeSummary {
+    pub modified: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub created: Vec<String>,
+}
+
+impl std::fmt::Display for ChangeSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |paths: &[String]| {
+            if paths.is_empty() {
+                "-".to_string()
+            } else {
+                paths.join(", ")
+            }
+        };
+        write!(
+            f,
+            "# Modified: {} | Unchanged: {} | Created: {}",
+            list(&self.modified),
+            list(&self.unchanged),
+            list(&self.created)
+        )
+    }
+}
+
+pub fn summarize_changes(input_files: &[String], files: &[(String, String)]) -> ChangeSummary {
+    let written: HashSet<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+    let mut summary = ChangeSummary::default();
+
+    for input in input_files {
+        if written.contains(input.as_str()) {
+            summary.modified.push(input.clone());
+        } else {
+            summary.unchanged.push(input.clone());
+        }
+    }
+
+    for (path, _) in files {
+        if !input_files.contains(path) && !summary.created.contains(path) {
+            summary.created.push(path.clone());
+        }
+    }
+
+    summary
+}
+
+/// Warns about files that shrank below the configured threshold and asks whether
+/// to write them anyway; refuses outright when there is no terminal to ask on.
+fn confirm_shrunk_files(
+    args: &Cli,
+    config: &config::Config,
+    originals: &[(String, String)],
+    files: &[(String, String)],
+) -> Result<bool, LlmpalError> {
+    let threshold = config
+        .shrink_threshold
+        .unwrap_or(config::DEFAULT_SHRINK_THRESHOLD);
+    let shrunk = find_shrunk_files(originals, files, threshold);
+    if shrunk.is_empty() {
+        return Ok(true);
+    }
+
+    eprintln!(
+        "\n# WARNING: The following files shrank below {:.0}% of their original size. The response may be truncated:",
+        threshold * 100.0
+    );
+    for (path, old_len, new_len) in &shrunk {
+        eprintln!("#   {} ({} -> {} bytes)", path, old_len, new_len);
+    }
+    if !can_prompt(args) {
+        return Err(LlmpalError::UsageError(
+            "refusing to write shrunk files; rerun with --force-shrink to write them anyway"
+                .to_string(),
         ));
     }
+    confirm(args, "Write anyway? [y/N]: ")
+}
+
+/// Lists the files the model would modify or create, without their content.
+fn print_planned_files(originals: &[(String, String)], files: &[(String, String)]) {
+    for (path, content) in files {
+        match originals.iter().find(|(input, _)| input == path) {
+            Some((_, old)) => {
+                let (added, removed) = utils::line_delta(old, content);
+                println!("# Would modify {} (+{} -{} lines)", path, added, removed);
+            }
+            None => println!(
+                "# Would create {} ({} lines)",
+                path,
+                content.lines().count()
+            ),
+        }
+    }
+}
+
+/// `--temperature`, else the model's temperature, else `code_temperature`
+/// when any writable file is source code and it is not set to `null`;
+/// otherwise the provider default.
+fn request_temperature(
+    args: &Cli,
+    model_config: &config::ModelConfig,
+    config: &config::Config,
+    allowed_files: &[String],
+) -> Option<f64> {
+    args.temperature.or(model_config.temperature).or_else(|| {
+        allowed_files
+            .iter()
+            .any(|path| utils::is_code_file(path))
+            .then(|| {
+                config
+                    .code_temperature
+                    .unwrap_or(Some(config::DEFAULT_CODE_TEMPERATURE))
+            })
+            .flatten()
+    })
+}
+
+/// The encoding forced with `--output-encoding`.
+fn output_encoding(args: &Cli) -> Result<Option<&'static encoding_rs::Encoding>, LlmpalError> {
+    args.output_encoding
+        .as_deref()
+        .map(utils::parse_encoding)
+        .transpose()
+}
 
-    response
-        .json()
+/// Locks the target files for the write phase, waiting up to `--lock-timeout`
+/// for another run writing the same files.
+async fn lock_targets(args: &Cli, paths: &[&str]) -> Result<Vec<lock::FileLock>, LlmpalError> {
+    let timeout = args
+        .lock_timeout
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(lock::DEFAULT_LOCK_TIMEOUT);
+    lock::lock_targets(paths, timeout)
         .await
-        .map_err(|e| format!("Failed to parse JSON response: {}", e))
+        .map_err(LlmpalError::FileError)
 }
 
-pub fn build_request(
-    model: &str,
-    provider: Option<&str>,
-    system_prompt: &str,
-    user_prompt: &str,
-    max_tokens: usize,
-    is_default_api_url: bool,
-) -> Result<String, Box<dyn Error>> {
-    let mut body = serde_json::Map::new();
+/// One line per written file: line delta and new size, or `Created` when
+/// there was no previous content.
+fn write_report(path: &str, old: Option<&str>, new: &str) -> String {
+    let size = utils::format_size(new.len());
+    match old {
+        Some(old) => {
+            let (added, removed) = utils::line_delta(old, new);
+            format!("# Wrote {} (+{} -{} lines, {})", path, added, removed, size)
+        }
+        None => format!(
+            "# Created {} ({} lines, {})",
+            path,
+            new.lines().count(),
+            size
+        ),
+    }
+}
 
-    body.insert(
-        "model".to_string(),
-        serde_json::Value::String(model.to_string()),
-    );
-    body.insert(
-        "max_tokens".to_string(),
-        serde_json::Value::Number(max_tokens.into()),
-    );
-    body.insert(
-        "messages".to_string(),
-        serde_json::Value::Array(vec![
-            serde_json::json!({
-                "role": "system",
-                "content": system_prompt
-            }),
-            serde_json::json!({
-                "role": "user",
-                "content": user_prompt
-            }),
-        ]),
-    );
+/// Prints a diff for every file the model would change and returns how many there are.
+fn print_proposed_changes(originals: &[(String, String)], files: &[(String, String)]) -> usize {
+    let mut changed = 0;
+    for (path, content) in files {
+        let original = originals
+            .iter()
+            .find(|(input, _)| input == path)
+            .map_or("", |(_, original)| original.as_str());
+        let diff = utils::unified_diff(path, original, content);
+        if !diff.is_empty() {
+            print!("{}", diff);
+            changed += 1;
+        }
+    }
+    changed
+}
 
-    let mut provider_obj: Option<serde_json::Map<String, serde_json::Value>> = None;
+/// Writes the changes as one `git apply` patch to `patch_path` (`-` for stdout)
+/// instead of editing the files.
+fn write_patch(
+    patch_path: &str,
+    originals: &[(String, String)],
+    files: &[(String, String)],
+) -> Result<(), LlmpalError> {
+    let mut patch = String::new();
+    let mut changed = 0;
+    for (path, content) in files {
+        if path == config::CLIPBOARD_OUTPUT {
+            continue;
+        }
+        let old = originals
+            .iter()
+            .find(|(input, _)| input == path)
+            .map(|(_, old)| old.clone())
+            .or_else(|| utils::read_text_file(path).ok());
+        let diff = utils::git_patch(&utils::normalize_path(path, false), old.as_deref(), content);
+        if !diff.is_empty() {
+            patch.push_str(&diff);
+            changed += 1;
+        }
+    }
 
-    if let Some(provider_name) = provider {
-        let mut p = serde_json::Map::new();
-        p.insert(
-            "only".to_string(),
-            serde_json::Value::Array(vec![serde_json::Value::String(provider_name.to_string())]),
-        );
-        provider_obj = Some(p);
+    if patch_path == config::STDOUT_OUTPUT {
+        print!("{}", patch);
+    } else {
+        utils::write_atomic(patch_path, &patch)?;
+        eprintln!("# Wrote a patch for {} file(s) to {}", changed, patch_path);
     }
+    Ok(())
+}
 
-    if is_default_api_url {
-        if provider_obj.is_none() {
-            provider_obj = Some(serde_json::Map::new());
+/// Files whose new content is smaller than `threshold` times their original size,
+/// as `(path, original bytes, new bytes)`.
+fn find_shrunk_files(
+    originals: &[(String, String)],
+    files: &[(String, String)],
+    threshold: f64,
+) -> Vec<(String, usize, usize)> {
+    files
+        .iter()
+        .filter_map(|(path, content)| {
+            let (_, original) = originals.iter().find(|(input, _)| input == path)?;
+            let (old_len, new_len) = (original.len(), content.len());
+            ((new_len as f64) < old_len as f64 * threshold)
+                .then(|| (path.clone(), old_len, new_len))
+        })
+        .collect()
+}
+
+fn write_schema_output(
+    args: &Cli,
+    schema: &serde_json::Value,
+    resp_text: &str,
+) -> Result<(), LlmpalError> {
+    let value = match schema::parse_schema_response(schema, resp_text) {
+        Ok(value) => value,
+        Err(e) => return handle_parse_error(resp_text, e),
+    };
+    let json = serde_json::to_string_pretty(&value)
+        .map_err(|e| LlmpalError::SerializeError(e.to_string()))?;
+
+    // --summary-only previews the JSON instead of writing it.
+    if args.summary_only || args.output_to_stdout() {
+        println!("{}", json);
+    } else if args.output_to_clipboard() {
+        clipboard::write(&json).map_err(LlmpalError::FileError)?;
+        eprintln!("# Copied validated JSON to the clipboard");
+    } else if let Some(output) = args.output_file() {
+        utils::write_atomic(output, &format!("{}\n", json))?;
+        eprintln!("# Wrote validated JSON to {}", output);
+    }
+    Ok(())
+}
+
+fn print_files(files: &[(String, String)]) {
+    for (path, content) in files {
+        if files.len() > 1 {
+            println!("=== {} ===", path);
         }
-        let p = provider_obj.as_mut().unwrap();
-        p.insert(
-            "data_collection".to_string(),
-            serde_json::Value::String("deny".to_string()),
-        );
+        println!("{}", content);
     }
+}
 
-    if let Some(provider_obj) = provider_obj {
-        body.insert(
-            "provider".to_string(),
-            serde_json::Value::Object(provider_obj),
-        );
+/// Some gateways return the content as a JSON string literal (quoted, with
+/// escaped newlines). When the text yields neither an explanation nor files
+/// but decodes as such a literal, the decoded text is used instead.
+fn unwrap_json_encoded(parser: &dyn ResponseParser, resp_text: String) -> String {
+    let parsed_nothing = parser.parse(&resp_text).map_or(true, |parsed| {
+        parsed.explanation.is_empty() && parsed.files.is_empty()
+    });
+    let trimmed = resp_text.trim();
+    if parsed_nothing
+        && trimmed.len() >= 2
+        && trimmed.starts_with('"')
+        && trimmed.ends_with('"')
+        && let Ok(decoded) = serde_json::from_str::<String>(trimmed)
+    {
+        debug!("Response content was a JSON-encoded string, decoded it");
+        return decoded;
     }
+    resp_text
+}
 
-    let json_value = serde_json::Value::Object(body);
-    Ok(serde_json::to_string(&json_value)?)
+/// The path to write for a file of the response: its allow-list entry, or the
+/// path itself when it lies inside an `--allow-under` directory.
+fn allowed_path(allowed_files: &[String], allow_under: &[String], path: &str) -> Option<String> {
+    allowed_files
+        .iter()
+        .find(|allowed| utils::paths_match(allowed, path))
+        .cloned()
+        .or_else(|| {
+            allow_under
+                .iter()
+                .any(|dir| utils::is_under(path, dir))
+                .then(|| path.to_string())
+        })
 }
 
-fn estimate_token_count(text: &str) -> usize {
-    text.chars().count() / 4
+/// Files of a `--per-file` response other than the one it was sent for.
+fn extra_files(files: &[(String, String)], path: &str) -> Vec<String> {
+    files
+        .iter()
+        .filter(|(file_path, _)| !utils::paths_match(file_path, path))
+        .map(|(file_path, _)| file_path.clone())
+        .collect()
+}
+
+/// Fails on files outside the allow-list: with `--strict` immediately and
+/// without a dump, otherwise after dumping the response.
+fn reject_disallowed(
+    args: &Cli,
+    resp_text: &str,
+    disallowed_files: Vec<String>,
+) -> Result<(), LlmpalError> {
+    if args.strict {
+        return Err(LlmpalError::DisallowedWrite(disallowed_files));
+    }
+    handle_parse_error(
+        resp_text,
+        format!(
+            "attempting to write to disallowed file(s): {} - see dump log for details",
+            disallowed_files.join(", ")
+        ),
+    )
+}
+
+fn handle_parse_error(resp_text: &str, error_msg: String) -> Result<(), LlmpalError> {
+    match utils::write_dump_log(resp_text) {
+        Ok(filename) => eprintln!("# Created dump file: {}", filename),
+        Err(e) => eprintln!("{}", e),
+    }
+    Err(LlmpalError::ParseError(error_msg))
+}
+
+fn forecast(args: &Cli, model_config: &config::ModelConfig) -> Result<(), LlmpalError> {
+    let (input_files, _) = prepare_files(&args)?;
+    let contents = read_input_files(&input_files, &args.output)?;
+    let mut estimates: Vec<(usize, &str)> = contents
+        .iter()
+        .map(|(path, content)| (estimate_token_count(content), path.as_str()))
+        .collect();
+    estimates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    print!("{}", format_forecast(&estimates, model_config));
+    Ok(())
+}
+
+fn format_forecast(estimates: &[(usize, &str)], model_config: &config::ModelConfig) -> String {
+    let mut table = format!("{:>10}  File\n", "Tokens");
+    for (tokens, path) in estimates {
+        table.push_str(&format!("{:>10}  {}\n", tokens, path));
+    }
+    let total: usize = estimates.iter().map(|(tokens, _)| tokens).sum();
+    table.push_str(&format!(
+        "# Model: {} | Files: {} | Estimated input tokens: {} | Projected input cost: ${:.4} (${:.4}/1M prompt)\n",
+        model_config.model,
+        estimates.len(),
+        total,
+        token_cost(total as u64, model_config.prompt_cost()),
+        model_config.prompt_cost()
+    ));
+    table
+}
+
+async fn list_models(model_config: &config::ModelConfig) -> Result<(), LlmpalError> {
+    let api_url = model_config
+        .api_url
+        .clone()
+        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
+    let api_key = resolve_api_key(model_config);
+
+    eprintln!("# Fetching models from {}", models::models_url(&api_url));
+    let available = models::fetch_models(&api_url, api_key.as_deref())
+        .await
+        .map_err(LlmpalError::NetworkError)?;
+
+    for model in &available {
+        println!("{}", models::format_model(model));
+    }
+    Ok(())
+}
+
+async fn check_providers(model_config: &config::ModelConfig) -> Result<(), LlmpalError> {
+    let configured: Vec<&String> = model_config
+        .provider
+        .iter()
+        .chain(model_config.ignore_providers.iter().flatten())
+        .collect();
+    if configured.is_empty() {
+        return Ok(());
+    }
+
+    let api_url = model_config
+        .api_url
+        .clone()
+        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
+    let api_key = resolve_api_key(model_config);
+    let known = models::lookup_providers(&api_url, api_key.as_deref())
+        .await
+        .map_err(LlmpalError::NetworkError)?;
+
+    let problems: Vec<String> = configured
+        .into_iter()
+        .filter_map(|name| match models::match_provider(name, &known) {
+            Ok(()) => None,
+            Err(Some(suggestion)) => Some(format!(
+                "Unknown provider '{}'. Did you mean '{}'?",
+                name, suggestion
+            )),
+            Err(None) => Some(format!("Unknown provider '{}'", name)),
+        })
+        .collect();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(LlmpalError::ValidationError(problems))
+    }
+}
+
+async fn fill_pricing_from_api(model_config: &mut config::ModelConfig) {
+    let api_url = model_config
+        .api_url
+        .clone()
+        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
+    let api_key = resolve_api_key(model_config);
+
+    match models::lookup_pricing(&api_url, model_config.request_model(), api_key.as_deref()).await {
+        Some(pricing) => {
+            model_config.prompt_cost.get_or_insert(pricing.prompt_cost);
+            model_config
+                .completion_cost
+                .get_or_insert(pricing.completion_cost);
+        }
+        None => eprintln!(
+            "> Warning: No pricing found for model '{}'. Using default costs.",
+            model_config.model
+        ),
+    }
+}
+
+/// Sets the context window from the `/models` endpoint, keeping the
+/// configured `context_window` when the lookup fails.
+async fn fill_context_window_from_api(model_config: &mut config::ModelConfig) {
+    let api_url = model_config
+        .api_url
+        .clone()
+        .unwrap_or_else(|| config::OPEN_ROUTER_URL.to_string());
+    let api_key = resolve_api_key(model_config);
+
+    match models::lookup_pricing(&api_url, model_config.request_model(), api_key.as_deref())
+        .await
+        .and_then(|info| info.context_length)
+    {
+        Some(context_length) => model_config.context_window = Some(context_length),
+        None => eprintln!(
+            "> Warning: No context length found for model '{}'.{}",
+            model_config.model,
+            match model_config.context_window {
+                Some(window) => format!(" Using the configured {} tokens.", window),
+                None => String::new(),
+            }
+        ),
+    }
+}
+
+/// `max_tokens` reduced so that input and completion fit in `context_window`.
+fn fit_context_window(
+    estimated_input_tokens: usize,
+    max_tokens: Option<usize>,
+    context_window: usize,
+) -> Option<usize> {
+    let available = context_window.saturating_sub(estimated_input_tokens);
+    max_tokens.map(|max_tokens| max_tokens.min(available))
+}
+
+/// Whether `error` is a provider's context-length error, e.g. OpenAI's
+/// "maximum context length is 8192 tokens. However, you requested 9000 tokens
+/// (5000 in the messages, 4000 in the completion)" or Anthropic's "input length
+/// and `max_tokens` exceed context limit: 5000 + 4000 > 8192".
+fn is_context_length_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    ["context length", "context_length_exceeded", "context limit"]
+        .iter()
+        .any(|marker| error.contains(marker))
+}
+
+/// The leading number of the text after the first `marker` in `text`.
+fn number_after(text: &str, marker: &str) -> Option<usize> {
+    let rest = &text[text.find(marker)? + marker.len()..];
+    let rest = rest.trim_start().trim_start_matches("about ");
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// The `max_tokens` to retry a context-length failure with: the context window
+/// minus the input, both taken from the error message when it reports them.
+/// `None` when `error` is another kind of error or nothing would be left for
+/// the completion.
+fn context_length_retry_tokens(
+    error: &str,
+    estimated_input_tokens: usize,
+    context_window: Option<usize>,
+) -> Option<usize> {
+    if !is_context_length_error(error) {
+        return None;
+    }
+    let context_window = number_after(error, "maximum context length is")
+        .or_else(|| number_after(error, " > "))
+        .or(context_window)?;
+    let input_tokens = number_after(error, "tokens (")
+        .or_else(|| number_after(error, "context limit:"))
+        .unwrap_or(estimated_input_tokens);
+    Some(context_window.saturating_sub(input_tokens)).filter(|&tokens| tokens > 0)
+}
+
+/// Applies `--trace-redact-files` to every string in a traced request or
+/// response: the blocks of the user prompt, and the files in the layout of
+/// the response format (which also covers few-shot examples and tool calls).
+fn redact_json_strings(value: &mut serde_json::Value, parser: &dyn ResponseParser) {
+    match value {
+        serde_json::Value::String(text) => {
+            *text = parser.redact(&llm::redact_file_contents(text));
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                redact_json_strings(item, parser);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for field in map.values_mut() {
+                redact_json_strings(field, parser);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether the user can answer a prompt: stdin is a terminal and not the
+/// file list of `--stdin-files`.
+fn can_prompt(args: &Cli) -> bool {
+    !args.stdin_files && io::stdin().is_terminal()
+}
+
+/// Asks `question` on the terminal. Fails instead of assuming an answer when
+/// there is no terminal to ask on.
+fn confirm(args: &Cli, question: &str) -> Result<bool, LlmpalError> {
+    if !can_prompt(args) {
+        return Err(LlmpalError::UsageError(format!(
+            "cannot ask \"{}\" without a terminal on stdin",
+            question.trim_end()
+        )));
+    }
+    eprint!("{}", question);
+    io::stderr().flush().unwrap();
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    let input = input.trim().to_lowercase();
+
+    Ok(input == "y" || input == "yes")
+}
+
+fn resolve_api_key(model_config: &config::ModelConfig) -> Option<String> {
+    model_config
+        .api_key
+        .clone()
+        .or_else(|| std::env::var("OPENROUTER_API_KEY").ok())
+}
+
+/// Where the API key of `model_config` came from, for auth error messages.
+fn api_key_source(model_config: &config::ModelConfig) -> String {
+    match &model_config.api_key {
+        Some(_) => format!("the api_key of model '{}' in the config", model_config.code),
+        None => "the OPENROUTER_API_KEY environment variable".to_string(),
+    }
+}
+
+/// Whether a failed request may succeed when sent again: transport errors,
+/// timeouts (408), rate limits (429) and server errors (5xx). Any other
+/// status would fail the same way again.
+fn is_retryable(error: &str) -> bool {
+    match number_after(error, "API request failed with status") {
+        Some(status) => matches!(status, 408 | 429 | 500..=599),
+        None => true,
+    }
+}
+
+/// Whether a request error is a 401/403 rejection of the API key.
+fn is_auth_failure(error: &str) -> bool {
+    ["401", "403"]
+        .iter()
+        .any(|status| error.starts_with(&format!("API request failed with status {}", status)))
+}
+
+/// `NetworkError` for a failed request, or an `AuthError` naming the key
+/// source when the provider rejected the API key.
+fn api_error(model_config: &config::ModelConfig, error: String) -> LlmpalError {
+    if !is_auth_failure(&error) {
+        return LlmpalError::NetworkError(error);
+    }
+    LlmpalError::AuthError(format!(
+        "the provider rejected the API key from {}. Check that it is set, not expired and valid for {}.\n{}",
+        api_key_source(model_config),
+        model_config
+            .api_url
+            .as_deref()
+            .unwrap_or(config::OPEN_ROUTER_URL),
+        error
+    ))
+}
+
+fn with_files0_from(args: &Cli, source: &str) -> Result<Cli, LlmpalError> {
+    let paths = if source == "-" {
+        if args.stdin_files {
+            return Err(LlmpalError::UsageError(
+                "--files0-from - cannot be combined with --stdin-files".to_string(),
+            ));
+        }
+        utils::read_null_delimited_paths(io::stdin().lock())?
+    } else {
+        let file = fs::File::open(source).map_err(|e| {
+            LlmpalError::FileError(format!("Cannot read file list '{}': {}", source, e))
+        })?;
+        utils::read_null_delimited_paths(file)?
+    };
+
+    let mut args = args.clone();
+    args.files.extend(paths);
+    Ok(args)
+}
+
+const MAX_CONTEXT_CMD_BYTES: usize = 64 * 1024;
+const COMPRESS_THRESHOLD_BYTES: usize = 64 * 1024;
+const REASONING_EFFORTS: [&str; 4] = ["minimal", "low", "medium", "high"];
+
+fn validate_inputs(args: &Cli, model_config: &config::ModelConfig) -> Result<(), LlmpalError> {
+    let mut problems: Vec<LlmpalError> = Vec::new();
+
+    match (&args.instruction, &args.instruction_file) {
+        (None, None) => problems.push(LlmpalError::FileError(
+            "Instructions must be provided either as positional argument or via -i flag"
+                .to_string(),
+        )),
+        (None, Some(file_path)) => {
+            if let Err(e) = fs::File::open(file_path) {
+                problems.push(LlmpalError::FileError(format!(
+                    "Cannot read instruction file '{}': {}",
+                    file_path, e
+                )));
+            }
+        }
+        _ => {}
+    }
+
+    if resolve_api_key(model_config).is_none() {
+        problems.push(LlmpalError::ApiKeyMissing);
+    }
+
+    for file in &args.files {
+        if args.output.as_ref() == Some(file) {
+            continue;
+        }
+        let path =
+            Path::new(utils::split_line_range(file).map_or(file.as_str(), |(path, _, _)| path));
+        let result = if path.is_dir() {
+            fs::read_dir(path).map(|_| ())
+        } else {
+            fs::File::open(path).map(|_| ())
+        };
+        if let Err(e) = result {
+            problems.push(LlmpalError::FileError(format!(
+                "Cannot read input '{}': {}",
+                file, e
+            )));
+        }
+    }
+
+    if args.confirm && !io::stdin().is_terminal() {
+        problems.push(LlmpalError::UsageError(
+            "--confirm requires an interactive terminal on stdin".to_string(),
+        ));
+    }
+
+    if args.output_to_clipboard() && !clipboard::is_available() {
+        problems.push(LlmpalError::UsageError(
+            "-o clipboard: requires llmpal built with the 'clipboard' feature".to_string(),
+        ));
+    }
+
+    if args.schema.is_some()
+        && args.output_file().is_none()
+        && !args.output_to_stdout()
+        && !args.output_to_clipboard()
+    {
+        problems.push(LlmpalError::UsageError(
+            "--schema requires an output: -o <FILE>, -o - or --output-stdout".to_string(),
+        ));
+    }
+    if args.schema.is_some() && !args.sweep_temperature.is_empty() {
+        problems.push(LlmpalError::UsageError(
+            "--schema cannot be combined with --sweep-temperature".to_string(),
+        ));
+    }
+
+    if let Some(reasoning) = args
+        .reasoning
+        .as_deref()
+        .or(model_config.reasoning_effort.as_deref())
+        && !REASONING_EFFORTS.contains(&reasoning)
+        && reasoning.parse::<u64>().is_err()
+    {
+        problems.push(LlmpalError::UsageError(format!(
+            "invalid reasoning effort '{}': expected one of {} or a token budget",
+            reasoning,
+            REASONING_EFFORTS.join(", ")
+        )));
+    }
+
+    let stop = model_config.stop_sequences(args);
+    if stop.len() > config::MAX_STOP_SEQUENCES {
+        problems.push(LlmpalError::UsageError(format!(
+            "at most {} stop sequences are supported, got {}",
+            config::MAX_STOP_SEQUENCES,
+            stop.len()
+        )));
+    }
+    if stop.iter().any(|s| s.is_empty()) {
+        problems.push(LlmpalError::UsageError(
+            "stop sequences must not be empty".to_string(),
+        ));
+    }
+
+    if args.per_file && args.output_to_stdout() {
+        problems.push(LlmpalError::UsageError(
+            "--per-file writes each input file and cannot print output to stdout".to_string(),
+        ));
+    }
+    if args.chunk_tokens.is_some() && args.output_to_stdout() {
+        problems.push(LlmpalError::UsageError(
+            "--chunk-tokens writes its files and cannot print output to stdout".to_string(),
+        ));
+    }
+
+    if args.stdin_files && args.output_to_stdout() {
+        problems.push(LlmpalError::UsageError(
+            "--stdin-files cannot be combined with printing output to stdout".to_string(),
+        ));
+    }
+
+    if let Some(output) = args.output_file()
+        && let Err(e) = utils::ensure_output_writable(output, args.mkdir)
+    {
+        problems.push(e);
+    }
+
+    if let Some(explanation_file) = &args.explanation_file {
+        if args.files.contains(explanation_file)
+            || args.writable.contains(explanation_file)
+            || args.output.as_ref() == Some(explanation_file)
+        {
+            problems.push(LlmpalError::UsageError(format!(
+                "--explanation-file '{}' must not be an input or output file",
+                explanation_file
+            )));
+        } else if let Err(e) = utils::ensure_output_writable(explanation_file, args.mkdir) {
+            problems.push(e);
+        }
+    }
+
+    match problems.len() {
+        0 => Ok(()),
+        1 => Err(problems.remove(0)),
+        _ => Err(LlmpalError::ValidationError(
+            problems.iter().map(|e| e.to_string()).collect(),
+        )),
+    }
+}
+
+/// Rejects an `--explanation-file` that is one of the files sent to or
+/// written by the model, including the files found in `-f` directories.
+fn check_explanation_file(
+    args: &Cli,
+    input_files: &[String],
+    allowed_files: &[String],
+) -> Result<(), LlmpalError> {
+    let Some(explanation_file) = &args.explanation_file else {
+        return Ok(());
+    };
+    let clashes = input_files
+        .iter()
+        .chain(allowed_files)
+        .any(|path| utils::paths_match(path, explanation_file))
+        || args
+            .allow_under
+            .iter()
+            .any(|dir| utils::is_under(explanation_file, dir));
+    if clashes {
+        return Err(LlmpalError::UsageError(format!(
+            "--explanation-file '{}' must not be an input or output file",
+            explanation_file
+        )));
+    }
+    Ok(())
+}
+
+fn prepare_files(args: &&Cli) -> Result<(Vec<String>, Vec<String>), LlmpalError> {
+    let mut allowed_files_set: HashSet<String> = HashSet::new();
+    let mut input_files: Vec<String> = Vec::new();
+
+    for file in &args.files {
+        let path = Path::new(file);
+        if path.is_dir() {
+            let entries = fs::read_dir(path).map_err(|e| {
+                LlmpalError::FileError(format!("Cannot read directory '{}': {}", file, e))
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|e| {
+                    LlmpalError::FileError(format!("Error reading entry in '{}': {}", file, e))
+                })?;
+                let entry_path = entry.path();
+                if entry_path.is_dir() {
+                    continue;
+                }
+                if let Some(entry_str) = entry_path.as_os_str().to_str() {
+                    allowed_files_set.insert(entry_str.to_string());
+                    input_files.push(entry_str.to_string());
+                }
+            }
+        } else if utils::split_line_range(file).is_some() {
+            // A partial view is never writable.
+            input_files.push(file.clone());
+        } else {
+            allowed_files_set.insert(file.clone());
+            input_files.push(file.clone());
+        }
+    }
+
+    // `read_dir` order depends on the filesystem; sorting keeps the prompt
+    // identical across machines and runs.
+    input_files.sort();
+    input_files.dedup();
+
+    if !args.writable.is_empty() {
+        allowed_files_set = args.writable.iter().cloned().collect();
+    }
+    if let Some(output) = &args.output {
+        allowed_files_set.insert(output.clone());
+    }
+    let mut allowed_files: Vec<String> = allowed_files_set.into_iter().collect();
+    allowed_files.sort();
+    Ok((input_files, allowed_files))
+}
+
+const DEFAULT_MAX_INPUT_FILES: usize = 200;
+
+/// Guards against sending a whole tree by accident, e.g. `-f .` in a large
+/// repository: more than `limit` (default 200) input files is an error.
+fn check_input_file_count(count: usize, limit: Option<usize>) -> Result<(), LlmpalError> {
+    let limit = limit.unwrap_or(DEFAULT_MAX_INPUT_FILES);
+    if count > limit {
+        return Err(LlmpalError::UsageError(format!(
+            "{} input files collected, more than the limit of {}. Narrow the file list, or raise the limit with --max-input-files (or max_input_files in the config) or --force",
+            count, limit
+        )));
+    }
+    Ok(())
+}
+
+/// Every input that cannot be read or is not valid UTF-8, with the reason,
+/// so they can be reported together instead of failing on the first one.
+fn find_invalid_inputs(input_files: &[String]) -> Vec<(String, String)> {
+    input_files
+        .iter()
+        .filter_map(|spec| {
+            let path = utils::split_line_range(spec).map_or(spec.as_str(), |(path, _, _)| path);
+            let reason = match fs::read(path) {
+                Ok(bytes) => std::str::from_utf8(&bytes)
+                    .err()
+                    .map(|e| format!("not valid UTF-8 at byte {}", e.valid_up_to())),
+                Err(e) => Some(e.to_string()),
+            };
+            reason.map(|reason| (spec.clone(), reason))
+        })
+        .collect()
+}
+
+fn read_input_files(
+    input_files: &[String],
+    output: &Option<String>,
+) -> Result<Vec<(String, String)>, LlmpalError> {
+    let mut progress = spinner::FileProgress::new();
+    let mut contents = Vec::new();
+    for path in input_files
+        .iter()
+        .filter(|path| output.as_ref() != Some(*path))
+    {
+        let content = match utils::split_line_range(path) {
+            Some((file, start, end)) => utils::read_line_range(file, start, end),
+            None => utils::read_text_file(path).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| LlmpalError::FileError(format!("Cannot read file '{}': {}", path, e)))?;
+        progress.add(content.len());
+        contents.push((path.clone(), content));
+    }
+    progress.finish();
+    Ok(contents)
+}
+
+/// HTTP client sending the User-Agent, the `HTTP-Referer`/`X-Title`
+/// attribution headers (unless `no_identify`) and the run's `X-Request-Id`
+/// with every request.
+pub fn http_client(
+    model_config: &config::ModelConfig,
+    request_id: &str,
+) -> Result<reqwest::Client, LlmpalError> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    let mut values = vec![("X-Request-Id", request_id)];
+    if !model_config.no_identify.unwrap_or_default() {
+        values.push(("HTTP-Referer", model_config.http_referer()));
+        values.push(("X-Title", model_config.x_title()));
+    }
+    for (name, value) in values {
+        let value = reqwest::header::HeaderValue::from_str(value).map_err(|e| {
+            LlmpalError::UsageError(format!("invalid {} header value '{}': {}", name, value, e))
+        })?;
+        headers.insert(name, value);
+    }
+    reqwest::Client::builder()
+        .user_agent(config::USER_AGENT)
+        .default_headers(headers)
+        .build()
+        .map_err(|e| LlmpalError::NetworkError(e.to_string()))
+}
+
+pub async fn send_api_request(
+    client: &reqwest::Client,
+    api_key: &str,
+    api_url: &str,
+    body: &str,
+) -> Result<(serde_json::Value, usize), String> {
+    read_api_response(api_request(client, api_key, api_url).body(body.to_string())).await
+}
+
+/// Sends `body` with `Content-Encoding: gzip`. If compressing or the compressed
+/// request fails, the request is sent again uncompressed.
+pub async fn send_compressed_api_request(
+    client: &reqwest::Client,
+    api_key: &str,
+    api_url: &str,
+    body: &str,
+) -> Result<(serde_json::Value, usize), String> {
+    match gzip(body) {
+        Ok(compressed) => {
+            debug!(
+                "Compressed request body from {} to {} bytes",
+                body.len(),
+                compressed.len()
+            );
+            let request = api_request(client, api_key, api_url)
+                .header("Content-Encoding", "gzip")
+                .body(compressed);
+            match read_api_response(request).await {
+                Ok(response) => return Ok(response),
+                Err(e) => eprintln!(
+                    "> Warning: compressed request failed ({}), retrying uncompressed",
+                    e
+                ),
+            }
+        }
+        Err(e) => eprintln!("> Warning: cannot compress request body: {}", e),
+    }
+    send_api_request(client, api_key, api_url, body).await
+}
+
+fn gzip(body: &str) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    encoder.finish()
+}
+
+fn api_request(client: &reqwest::Client, api_key: &str, api_url: &str) -> reqwest::RequestBuilder {
+    client
+        .post(api_url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+}
+
+async fn read_api_response(
+    request: reqwest::RequestBuilder,
+) -> Result<(serde_json::Value, usize), String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to send request: {}", e))?;
+
+    let status_code = response.status();
+    if !status_code.is_success() {
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read error response: {}", e))?;
+        return Err(format!(
+            "API request failed with status {}: {}",
+            status_code, error_text
+        ));
+    }
+
+    let response_text = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+    let json = serde_json::from_str(&response_text)
+        .map_err(|e| format!("Failed to parse JSON response: {}", e))?;
+    Ok((json, response_text.len()))
+}
+
+async fn run_temperature_sweep(
+    bodies: Vec<(f64, String)>,
+    endpoint: &Endpoint<'_>,
+    input_contents: &[(String, String)],
+    model_config: &config::ModelConfig,
+    parser: &dyn ResponseParser,
+) -> Result<(), LlmpalError> {
+    let loading = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let spinner_handle = spinner::setup_spinner(loading.clone(), Some("Waiting for LLM responses"));
+
+    let mut requests = tokio::task::JoinSet::new();
+    for (index, (temperature, body)) in bodies.into_iter().enumerate() {
+        let client = endpoint.client.clone();
+        let api_key = endpoint.api_key.to_string();
+        let api_url = endpoint.api_url.to_string();
+        requests.spawn(async move {
+            let result = send_api_request(&client, &api_key, &api_url, &body).await;
+            (index, temperature, result)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(joined) = requests.join_next().await {
+        match joined {
+            Ok(result) => results.push(result),
+            Err(e) => eprintln!("# Sweep request task failed: {}", e),
+        }
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+
+    loading.store(false, std::sync::atomic::Ordering::Relaxed);
+    spinner_handle.join();
+
+    for (_, temperature, result) in results {
+        println!("=== temperature {} ===", temperature);
+        let (res, _) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                println!("# Request failed: {}", e);
+                continue;
+            }
+        };
+
+        let (text, usage) = match endpoint.provider.extract(&res) {
+            Ok(extracted) => extracted,
+            Err(e) => {
+                println!("# {}", e);
+                continue;
+            }
+        };
+        let (files, comments) = match parser.parse(&text) {
+            Ok(parsed) => (parsed.file_pairs(), parsed.explanation),
+            Err(e) => {
+                println!("# {}", e);
+                continue;
+            }
+        };
+
+        if !comments.is_empty() {
+            println!("{}", comments);
+        }
+        print_planned_files(input_contents, &files);
+
+        if usage != Usage::default() {
+            println!(
+                "# Tokens: {} prompt, {} completion (${:.4})",
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.cost(model_config).total
+            );
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// The `--show-extra` section for text outside the response format, if any.
+fn extra_section(args: &Cli, remaining: &str) -> Option<String> {
+    (args.show_extra && !remaining.trim().is_empty())
+        .then(|| format!("::EXTRA::\n{}", remaining.trim()))
+}
+
+/// The explanation as printed, dedented with `--dedent-explanation`.
+fn explanation_text(args: &Cli, explanation: String) -> String {
+    if args.dedent_explanation {
+        llm::dedent(&explanation)
+    } else {
+        explanation
+    }
+}
+
+/// Prints one line of an explanation while it streams in.
+fn print_explanation_line(line: &str) {
+    eprintln!("{}", line);
+}
+
+/// Timing of one `--bench` request.
+struct BenchSample {
+    first_token: Option<std::time::Duration>,
+    total: std::time::Duration,
+    completion_tokens: u64,
+}
+
+/// Sends `body` `runs` times, one after another, and prints latency statistics.
+/// Stops early when the next run, estimated at `run_cost`, would exceed the budget.
+async fn run_bench(
+    runs: usize,
+    args: &Cli,
+    body: &str,
+    endpoint: &Endpoint<'_>,
+    (mut budget, run_cost): (Budget<'_>, f64),
+) -> Result<(), LlmpalError> {
+    let model_config = budget.model_config;
+    if runs == 0 {
+        return Err(LlmpalError::UsageError(
+            "--bench needs at least one run".to_string(),
+        ));
+    }
+    let (client, api_key, api_url) = (endpoint.client, endpoint.api_key, endpoint.api_url);
+    let idle_timeout = args.timeout_stream_idle.map(std::time::Duration::from_secs);
+    let mut samples = Vec::new();
+    let mut failed = 0;
+    let mut usage_log = UsageLog::default();
+    for run in 1..=runs {
+        if let Err(e) = budget.check(&usage_log, run_cost) {
+            eprintln!("# Stopping after {} run(s): {}", run - 1, e);
+            break;
+        }
+        let start = Instant::now();
+        let result = if args.stream {
+            stream::read_stream(client, api_key, api_url, body, idle_timeout, &mut |_| {})
+                .await
+                .map(|(accumulator, _)| {
+                    let first_token = accumulator.first_token.map(|at| at - start);
+                    (accumulator.into_response(), first_token)
+                })
+        } else {
+            send_api_request(client, api_key, api_url, body)
+                .await
+                .map(|(res, _)| (res, None))
+        };
+        let total = start.elapsed();
+        match result {
+            Ok((res, first_token)) => {
+                let (text, usage) = endpoint.provider.extract(&res).unwrap_or_default();
+                usage_log.record(format!("run {}", run), usage);
+                eprintln!("# Run {}/{}: {:.2}s", run, runs, total.as_secs_f64());
+                samples.push(BenchSample {
+                    first_token,
+                    total,
+                    completion_tokens: completion_tokens(&text, usage),
+                });
+            }
+            Err(e) => {
+                eprintln!("# Run {}/{} failed: {}", run, runs, e);
+                failed += 1;
+                budget.failed += run_cost;
+            }
+        }
+    }
+
+    println!("# Model: {} | URL: {}", model_config.model, api_url);
+    print!("{}", format_bench(&samples, failed));
+    if samples.is_empty() {
+        return Err(LlmpalError::NetworkError(
+            "All benchmark requests failed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The nearest-rank percentile of sorted, non-empty `values`.
+fn percentile(sorted: &[std::time::Duration], percent: usize) -> std::time::Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn format_latencies(label: &str, mut values: Vec<std::time::Duration>) -> String {
+    if values.is_empty() {
+        return String::new();
+    }
+    values.sort();
+    format!(
+        "{:<12} p50 {:.2}s | p95 {:.2}s | min {:.2}s | max {:.2}s\n",
+        label,
+        percentile(&values, 50).as_secs_f64(),
+        percentile(&values, 95).as_secs_f64(),
+        values[0].as_secs_f64(),
+        values[values.len() - 1].as_secs_f64()
+    )
+}
+
+fn format_bench(samples: &[BenchSample], failed: usize) -> String {
+    let mut report = format!("Runs: {} ({} failed)\n", samples.len() + failed, failed);
+    report.push_str(&format_latencies(
+        "Total:",
+        samples.iter().map(|s| s.total).collect(),
+    ));
+    report.push_str(&format_latencies(
+        "First token:",
+        samples.iter().filter_map(|s| s.first_token).collect(),
+    ));
+    let seconds: f64 = samples.iter().map(|s| s.total.as_secs_f64()).sum();
+    if seconds > 0.0 {
+        let tokens: u64 = samples.iter().map(|s| s.completion_tokens).sum();
+        report.push_str(&format!(
+            "{:<12} {:.1} completion tokens/s\n",
+            "Throughput:",
+            tokens as f64 / seconds
+        ));
+    }
+    report
+}
+
+/// The model label for the summary line, noting the model actually served
+/// (when a router picked a different one) and the provider from the response.
+fn served_model_label(model_config: &config::ModelConfig, res: &serde_json::Value) -> String {
+    let mut label = model_config.model.clone();
+    if let Some(served) = res.get("model").and_then(|m| m.as_str())
+        && served != model_config.request_model()
+    {
+        label.push_str(&format!(" [served: {}]", served));
+    }
+    if let Some(provider_name) = res.get("provider").and_then(|p| p.as_str()) {
+        label.push_str(&format!(" [provider: {}]", provider_name));
+    }
+    label
+}
+
+/// Groups files in order so that each group's estimated tokens stay within
+/// `chunk_tokens`. A file larger than that on its own gets a group by itself.
+fn chunk_files(files: &[(String, String)], chunk_tokens: usize) -> Vec<Vec<(String, String)>> {
+    let mut chunks: Vec<Vec<(String, String)>> = Vec::new();
+    let mut current_tokens = 0;
+    for (path, content) in files {
+        let tokens = estimate_token_count(content);
+        if tokens > chunk_tokens {
+            eprintln!(
+                "> Warning: {} alone is estimated at {} tokens, above the chunk size of {}",
+                path, tokens, chunk_tokens
+            );
+        }
+        match chunks.last_mut() {
+            Some(chunk) if current_tokens + tokens <= chunk_tokens => {
+                chunk.push((path.clone(), content.clone()));
+                current_tokens += tokens;
+            }
+            _ => {
+                chunks.push(vec![(path.clone(), content.clone())]);
+                current_tokens = tokens;
+            }
+        }
+    }
+    chunks
+}
+
+/// Sends one request per `(body, allowed files)` chunk, in order, then applies
+/// the files of all responses together; a path written by several chunks gets
+/// the last version. A chunk may only write the allowed files it was built for.
+async fn run_chunked(
+    bodies: Vec<(String, Vec<String>)>,
+    endpoint: &Endpoint<'_>,
+    config: &config::Config,
+    (budget, max_tokens): (&Budget<'_>, Option<usize>),
+    parser: &dyn ResponseParser,
+    args: &Cli,
+) -> Result<(), LlmpalError> {
+    let model_config = budget.model_config;
+    let output_encoding = output_encoding(args)?;
+
+    let total = bodies.len();
+    let mut usage_log = UsageLog::default();
+    let mut all_files = Vec::new();
+    for (index, (body, allowed_files)) in bodies.iter().enumerate() {
+        budget.check(
+            &usage_log,
+            budget.request_cost(estimate_token_count(body), max_tokens),
+        )?;
+        eprintln!("# Chunk {}/{}", index + 1, total);
+        let (res, _) = post_request(endpoint, args, body)
+            .await
+            .map_err(|e| api_error(model_config, e))?;
+        let (resp_text, usage) = endpoint.provider.extract(&res)?;
+        usage_log.record(format!("chunk {}/{}", index + 1, total), usage);
+
+        let resp_text = resp_text.as_str();
+        let (files, comments) = match parser.parse(resp_text) {
+            Ok(parsed) => (
+                parsed.file_pairs(),
+                explanation_text(args, parsed.explanation),
+            ),
+            Err(e) => return handle_parse_error(resp_text, e),
+        };
+        if !comments.is_empty() {
+            eprintln!("{}", comments);
+        }
+        for (path, content) in files {
+            match allowed_path(allowed_files, &args.allow_under, &path) {
+                Some(allowed) => all_files.push((allowed, content)),
+                None => return reject_disallowed(args, resp_text, vec![path]),
+            }
+        }
+    }
+
+    let all_files: Vec<(String, String)> = llm::dedup_files(all_files)
+        .into_iter()
+        .map(|(path, content)| {
+            let content = if args.no_strip_fences {
+                content
+            } else {
+                llm::strip_wrapping_fence(&path, &content)
+            };
+            let content = if args.line_numbers {
+                llm::strip_line_numbers(&content)
+            } else {
+                content
+            };
+            let content = if args.strip_trailing_ws {
+                utils::strip_trailing_whitespace(&content)
+            } else {
+                content
+            };
+            (path, content)
+        })
+        .collect();
+    let targets: Vec<&str> = all_files.iter().map(|(path, _)| path.as_str()).collect();
+    let _locks = lock_targets(args, &targets).await?;
+    let originals: Vec<(String, String)> = all_files
+        .iter()
+        .filter_map(|(path, _)| Some((path.clone(), utils::read_text_file(path).ok()?)))
+        .collect();
+    for (path, old) in &originals {
+        if all_files
+            .iter()
+            .any(|(file, content)| file == path && utils::protected_regions_changed(old, content))
+        {
+            return Err(LlmpalError::ParseError(format!(
+                "protected region(s) changed in: {}",
+                path
+            )));
+        }
+    }
+    if !args.force_shrink && !confirm_shrunk_files(args, config, &originals, &all_files)? {
+        return Ok(());
+    }
+    for (path, content) in &all_files {
+        let old = originals
+            .iter()
+            .find(|(original, _)| original == path)
+            .map(|(_, old)| old.as_str());
+        utils::create_parent_dirs(path)?;
+        utils::write_encoded(path, content, output_encoding)?;
+        eprintln!("{}", write_report(path, old, content));
+    }
+
+    let usage = usage_log.total().unwrap_or_default();
+    let cost = usage.cost(model_config).total;
+    eprintln!(
+        "# Model: {} | Chunks: {} | Prompt tokens: {} | Completion tokens: {} | Total cost: ${:.4}",
+        model_config.model, total, usage.prompt_tokens, usage.completion_tokens, cost
+    );
+    if args.verbose {
+        eprint!("{}", usage_log.breakdown(model_config));
+    }
+    Ok(())
+}
+
+/// Sends one request per `(path, body)` job, at most `concurrency` at a time,
+/// and writes each file from the response of its own request.
+async fn run_per_file(
+    jobs: Vec<(String, String)>,
+    endpoint: &Endpoint<'_>,
+    (config, model_config): (&config::Config, &config::ModelConfig),
+    parser: &dyn ResponseParser,
+    concurrency: usize,
+    args: &Cli,
+) -> Result<(), LlmpalError> {
+    let output_encoding = output_encoding(args)?;
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+
+    let loading = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let spinner_handle = spinner::setup_spinner(loading.clone(), Some("Waiting for LLM responses"));
+
+    let total = jobs.len();
+    let mut requests = tokio::task::JoinSet::new();
+    for (index, (path, body)) in jobs.into_iter().enumerate() {
+        let client = endpoint.client.clone();
+        let semaphore = semaphore.clone();
+        let api_key = endpoint.api_key.to_string();
+        let api_url = endpoint.api_url.to_string();
+        requests.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let result = send_api_request(&client, &api_key, &api_url, &body).await;
+            (index, path, result)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(joined) = requests.join_next().await {
+        match joined {
+            Ok(result) => results.push(result),
+            Err(e) => eprintln!("# Per-file request task failed: {}", e),
+        }
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+
+    loading.store(false, std::sync::atomic::Ordering::Relaxed);
+    spinner_handle.join();
+
+    let lost = total - results.len();
+    let mut usage_log = UsageLog::default();
+    let mut succeeded = Vec::new();
+    let mut failures = Vec::new();
+    let mut results = results.into_iter();
+    for (_, path, result) in results.by_ref() {
+        eprintln!("=== {} ===", path);
+        let outcome = match result {
+            Ok((res, _)) => match endpoint.provider.extract(&res) {
+                Ok((text, usage)) => {
+                    usage_log.record(path.clone(), usage);
+                    apply_per_file_response(args, config, parser, &path, &text, output_encoding)
+                        .await
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => Err(format!("Request failed: {}", api_error(model_config, e))),
+        };
+        match outcome {
+            Ok(()) => succeeded.push(path),
+            Err(e) => {
+                eprintln!("# {}", e);
+                failures.push(path);
+                if !args.continue_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    // Responses after the first failure are not applied, but were paid for.
+    let skipped: Vec<String> = results
+        .map(|(_, path, result)| {
+            if let Ok((res, _)) = result
+                && let Ok((_, usage)) = endpoint.provider.extract(&res)
+            {
+                usage_log.record(path.clone(), usage);
+            }
+            path
+        })
+        .collect();
+
+    let usage = usage_log.total().unwrap_or_default();
+    let cost = usage.cost(model_config).total;
+    eprintln!(
+        "# Model: {} | Files: {}/{} succeeded | Prompt tokens: {} | Completion tokens: {} | Total cost: ${:.4}",
+        model_config.model,
+        succeeded.len(),
+        total,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        cost
+    );
+    if args.verbose {
+        eprint!("{}", usage_log.breakdown(model_config));
+    }
+
+    let failed = total - succeeded.len();
+    if failed > 0 {
+        if !succeeded.is_empty() {
+            eprintln!("# Succeeded: {}", succeeded.join(", "));
+        }
+        if !failures.is_empty() {
+            eprintln!("# Failed: {}", failures.join(", "));
+        }
+        if !skipped.is_empty() {
+            eprintln!(
+                "# Not applied after the first failure (use --continue-on-error): {}",
+                skipped.join(", ")
+            );
+        }
+        if lost > 0 {
+            eprintln!("# {} request task(s) did not complete", lost);
+        }
+        return Err(LlmpalError::NetworkError(format!(
+            "{} of {} per-file requests failed",
+            failed, total
+        )));
+    }
+    Ok(())
+}
+
+/// Parses and writes the response for `path` in `--per-file` mode. `Err`
+/// describes why the file failed.
+async fn apply_per_file_response(
+    args: &Cli,
+    config: &config::Config,
+    parser: &dyn ResponseParser,
+    path: &str,
+    resp_text: &str,
+    output_encoding: Option<&'static encoding_rs::Encoding>,
+) -> Result<(), String> {
+    let parsed = parser.parse(resp_text)?;
+    let (files, comments) = (
+        parsed.file_pairs(),
+        explanation_text(args, parsed.explanation),
+    );
+
+    if !comments.is_empty() {
+        eprintln!("{}", comments);
+    }
+    let extra = extra_files(&files, path);
+    if args.fail_on_extra_files && !extra.is_empty() {
+        return Err(format!(
+            "Rejected {}: response also contains disallowed file(s): {}",
+            path,
+            extra.join(", ")
+        ));
+    }
+    for (file_path, content) in &files {
+        if !utils::paths_match(file_path, path) {
+            eprintln!("# Skipped disallowed file: {}", file_path);
+            continue;
+        }
+        let content = if args.no_strip_fences {
+            content.clone()
+        } else {
+            llm::strip_wrapping_fence(path, content)
+        };
+        let content = if args.line_numbers {
+            llm::strip_line_numbers(&content)
+        } else {
+            content
+        };
+        let content = if args.strip_trailing_ws {
+            utils::strip_trailing_whitespace(&content)
+        } else {
+            content
+        };
+        let _lock = lock_targets(args, &[path])
+            .await
+            .map_err(|e| format!("Rejected {}: {}", path, e))?;
+        let old = utils::read_text_file(path).ok();
+        if let Some(old) = &old
+            && utils::protected_regions_changed(old, &content)
+        {
+            return Err(format!("Rejected {}: protected region changed", path));
+        }
+        if let Some(old) = &old
+            && !args.force_shrink
+            && !confirm_shrunk_files(
+                args,
+                config,
+                &[(path.to_string(), old.clone())],
+                &[(path.to_string(), content.clone())],
+            )
+            .map_err(|e| format!("Rejected {}: {}", path, e))?
+        {
+            return Err(format!("Rejected {}: shrunk file not written", path));
+        }
+        utils::write_encoded(path, &content, output_encoding)
+            .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+        eprintln!("{}", write_report(path, old.as_deref(), &content));
+    }
+    Ok(())
+}
+
+#[derive(Default, Clone)]
+pub struct RequestOptions<'a> {
+    pub model: &'a str,
+    pub provider: Option<&'a str>,
+    pub ignore_providers: Option<&'a [String]>,
+    pub max_tokens: Option<usize>,
+    pub max_completion_cap: Option<usize>,
+    pub temperature: Option<f64>,
+    pub stop: &'a [String],
+    pub stream: bool,
+    pub is_default_api_url: bool,
+    pub no_provider_block: bool,
+    /// Forces `provider.data_collection`; by default `deny` is sent to OpenRouter only.
+    pub data_collection: Option<config::DataCollection>,
+    pub response_schema: Option<&'a serde_json::Value>,
+    pub reasoning: Option<&'a str>,
+    pub examples: &'a [config::Example],
+    pub no_system: bool,
+    pub split_system: bool,
+    pub extra_body: Option<&'a serde_json::Map<String, serde_json::Value>>,
+    pub extra_body_override: bool,
+    /// Declares the `write_file` tool for the `tools` response format.
+    pub tools: bool,
+}
+
+/// `max_tokens` limited to the model's completion cap, when one is configured.
+fn capped_max_tokens(max_tokens: Option<usize>, cap: Option<usize>) -> Option<usize> {
+    match (max_tokens, cap) {
+        (Some(max_tokens), Some(cap)) => Some(max_tokens.min(cap)),
+        (max_tokens, _) => max_tokens,
+    }
+}
+
+pub fn build_request(
+    options: &RequestOptions,
+    system_prompt: &str,
+    user_prompt: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut body = serde_json::Map::new();
+
+    body.insert(
+        "model".to_string(),
+        serde_json::Value::String(options.model.to_string()),
+    );
+    if let Some(max_tokens) = capped_max_tokens(options.max_tokens, options.max_completion_cap) {
+        body.insert(
+            "max_tokens".to_string(),
+            serde_json::Value::Number(max_tokens.into()),
+        );
+    }
+    if let Some(temperature) = options.temperature {
+        body.insert("temperature".to_string(), serde_json::json!(temperature));
+    }
+    if let Some(reasoning) = options.reasoning {
+        match reasoning.parse::<u64>() {
+            Ok(budget) => body.insert(
+                "reasoning".to_string(),
+                serde_json::json!({ "max_tokens": budget }),
+            ),
+            Err(_) if options.is_default_api_url => body.insert(
+                "reasoning".to_string(),
+                serde_json::json!({ "effort": reasoning }),
+            ),
+            Err(_) => body.insert("reasoning_effort".to_string(), serde_json::json!(reasoning)),
+        };
+    }
+    if let Some(response_schema) = options.response_schema {
+        body.insert(
+            "response_format".to_string(),
+            schema::response_format(response_schema),
+        );
+    }
+    if !options.stop.is_empty() {
+        body.insert("stop".to_string(), serde_json::json!(options.stop));
+    }
+    if options.tools {
+        body.insert("tools".to_string(), tools::definitions());
+        body.insert("tool_choice".to_string(), serde_json::json!("auto"));
+    }
+    if options.stream {
+        body.insert("stream".to_string(), serde_json::Value::Bool(true));
+        body.insert(
+            "stream_options".to_string(),
+            serde_json::json!({ "include_usage": true }),
+        );
+    }
+    let mut messages = Vec::new();
+    if !options.no_system {
+        let parts = if options.split_system {
+            llm::split_system_prompt(system_prompt)
+        } else {
+            vec![system_prompt]
+        };
+        for part in parts {
+            messages.push(serde_json::json!({
+                "role": "system",
+                "content": part
+            }));
+        }
+    }
+    for example in options.examples {
+        messages.push(serde_json::json!({ "role": "user", "content": example.user }));
+        messages.push(serde_json::json!({ "role": "assistant", "content": example.assistant }));
+    }
+    messages.push(serde_json::json!({
+        "role": "user",
+        "content": user_prompt
+    }));
+    if options.no_system {
+        // Endpoints without a system role get the system prompt at the
+        // start of the first user message.
+        let first_user = &mut messages[0]["content"];
+        *first_user = serde_json::json!(format!(
+            "{}\n\n{}",
+            system_prompt,
+            first_user.as_str().unwrap_or_default()
+        ));
+    }
+    body.insert("messages".to_string(), serde_json::Value::Array(messages));
+
+    let mut provider_obj: Option<serde_json::Map<String, serde_json::Value>> = None;
+
+    if let Some(provider_name) = options.provider {
+        let mut p = serde_json::Map::new();
+        p.insert(
+            "only".to_string(),
+            serde_json::Value::Array(vec![serde_json::Value::String(provider_name.to_string())]),
+        );
+        provider_obj = Some(p);
+    }
+
+    if let Some(ignored) = options.ignore_providers
+        && !ignored.is_empty()
+    {
+        let p = provider_obj.get_or_insert_with(serde_json::Map::new);
+        p.insert(
+            "ignore".to_string(),
+            serde_json::Value::Array(
+                ignored
+                    .iter()
+                    .map(|name| serde_json::Value::String(name.clone()))
+                    .collect(),
+            ),
+        );
+    }
+
+    let data_collection = options.data_collection.or(options
+        .is_default_api_url
+        .then_some(config::DataCollection::Deny));
+    if let Some(data_collection) = data_collection {
+        if provider_obj.is_none() {
+            provider_obj = Some(serde_json::Map::new());
+        }
+        let p = provider_obj.as_mut().unwrap();
+        p.insert(
+            "data_collection".to_string(),
+            serde_json::Value::String(data_collection.as_str().to_string()),
+        );
+    }
+
+    if let Some(provider_obj) = provider_obj
+        && !options.no_provider_block
+    {
+        body.insert(
+            "provider".to_string(),
+            serde_json::Value::Object(provider_obj),
+        );
+    }
+
+    for (key, value) in options.extra_body.into_iter().flatten() {
+        if body.contains_key(key) && !options.extra_body_override {
+            eprintln!(
+                "> Warning: extra_body key '{}' ignored; set extra_body_override to replace it",
+                key
+            );
+            continue;
+        }
+        body.insert(key.clone(), value.clone());
+    }
+
+    let json_value = serde_json::Value::Object(body);
+    Ok(serde_json::to_string(&json_value)?)
+}
+
+const MAX_LENGTH_FLOOR_RETRIES: u32 = 2;
+
+/// The reported completion tokens, or an estimate from `text` when the
+/// response reports none.
+fn completion_tokens(text: &str, usage: Usage) -> u64 {
+    if usage.completion_tokens > 0 {
+        usage.completion_tokens
+    } else {
+        estimate_token_count(text) as u64
+    }
+}
+
+/// Asks again for the full output while the completion stays below
+/// `min_tokens`, up to MAX_LENGTH_FLOOR_RETRIES times and while the next
+/// request fits the budget, and returns the last response.
+async fn follow_up_short_completion(
+    endpoint: &Endpoint<'_>,
+    body: &str,
+    args: &Cli,
+    (mut res, mut response_bytes): (serde_json::Value, usize),
+    min_tokens: u64,
+    (budget, max_tokens): (&Budget<'_>, Option<usize>),
+    usage_log: &mut UsageLog,
+) -> Result<(serde_json::Value, usize), LlmpalError> {
+    let provider = endpoint.provider;
+    let (mut answer, mut usage) = provider.extract(&res)?;
+    for retry in 1..=MAX_LENGTH_FLOOR_RETRIES {
+        let completion = completion_tokens(&answer, usage);
+        if completion >= min_tokens {
+            break;
+        }
+        let next_body = provider.with_follow_up(body, &answer, llm::LENGTH_FLOOR_FOLLOW_UP)?;
+        let next_cost = budget.request_cost(estimate_token_count(&next_body), max_tokens);
+        if let Err(e) = budget.check(usage_log, next_cost) {
+            eprintln!(
+                "> Warning: the completion of {} tokens is below the minimum of {}; not asking again: {}",
+                completion, min_tokens, e
+            );
+            break;
+        }
+        eprintln!(
+            "# Completion of {} tokens is below the minimum of {} - asking for the full output ({}/{})",
+            completion, min_tokens, retry, MAX_LENGTH_FLOOR_RETRIES
+        );
+        (res, response_bytes) = send_body(endpoint, args, &next_body).await?;
+        (answer, usage) = endpoint.provider.extract(&res)?;
+        usage_log.record(format!("min-tokens follow-up {}", retry), usage);
+    }
+    Ok((res, response_bytes))
+}
+
+const DEFAULT_MAX_CONTINUATIONS: u32 = 3;
+
+/// Asks the model to continue while its completion is cut off at max_tokens,
+/// sending everything so far as its answer, for at most `--max-continuations`
+/// requests and while the next request, estimated at its whole prompt plus
+/// `max_tokens`, fits the budget. The last response is returned with the
+/// joined content.
+async fn continue_truncated(
+    endpoint: &Endpoint<'_>,
+    body: &str,
+    args: &Cli,
+    (mut res, mut response_bytes): (serde_json::Value, usize),
+    (budget, max_tokens): (&Budget<'_>, Option<usize>),
+    usage_log: &mut UsageLog,
+) -> Result<(serde_json::Value, usize), LlmpalError> {
+    let max_continuations = args.max_continuations.unwrap_or(DEFAULT_MAX_CONTINUATIONS);
+    let provider = endpoint.provider;
+    let (mut text, _) = provider.extract(&res)?;
+    for continuation in 1..=max_continuations {
+        if !provider.is_truncated(&res) {
+            break;
+        }
+        let next_body = provider.with_follow_up(body, &text, llm::CONTINUE_FOLLOW_UP)?;
+        let next_cost = budget.request_cost(estimate_token_count(&next_body), max_tokens);
+        if let Err(e) = budget.check(usage_log, next_cost) {
+            eprintln!(
+                "> Warning: the response was cut off at max_tokens; not continuing: {}",
+                e
+            );
+            break;
+        }
+        eprintln!(
+            "# The response was cut off at max_tokens - asking the model to continue ({}/{})",
+            continuation, max_continuations
+        );
+        (res, response_bytes) = send_body(endpoint, args, &next_body).await?;
+        let (more, usage) = provider.extract(&res)?;
+        usage_log.record(format!("continuation {}", continuation), usage);
+        text.push_str(&more);
+    }
+    if provider.is_truncated(&res) {
+        eprintln!(
+            "> Warning: the response is still cut off after {} continuation(s)",
+            max_continuations
+        );
+    }
+    provider.set_text(&mut res, &text);
+    Ok((res, response_bytes))
+}
+
+/// The API a run sends its requests to, and the provider that builds them
+/// and reads the responses.
+struct Endpoint<'a> {
+    client: &'a reqwest::Client,
+    api_key: &'a str,
+    api_url: &'a str,
+    provider: &'a dyn provider::Provider,
+}
+
+/// Sends `body`, streamed with `--stream`.
+async fn send_body(
+    endpoint: &Endpoint<'_>,
+    args: &Cli,
+    body: &str,
+) -> Result<(serde_json::Value, usize), LlmpalError> {
+    if args.stream {
+        stream::send_streaming_request(
+            endpoint.client,
+            endpoint.api_key,
+            endpoint.api_url,
+            body,
+            args.timeout_stream_idle.map(std::time::Duration::from_secs),
+        )
+        .await
+    } else {
+        post_request(endpoint, args, body).await
+    }
+    .map_err(LlmpalError::NetworkError)
+}
+
+/// Sends a non-streaming request, gzipped with `--compress` when `body` is large.
+async fn post_request(
+    endpoint: &Endpoint<'_>,
+    args: &Cli,
+    body: &str,
+) -> Result<(serde_json::Value, usize), String> {
+    if args.compress && body.len() >= COMPRESS_THRESHOLD_BYTES {
+        send_compressed_api_request(endpoint.client, endpoint.api_key, endpoint.api_url, body).await
+    } else {
+        send_api_request(endpoint.client, endpoint.api_key, endpoint.api_url, body).await
+    }
+}
+
+/// Continues a `tools` response format conversation: each `write_file` call
+/// is recorded and answered with a tool result until the model stops calling
+/// tools, for at most `tools::MAX_TOOL_STEPS` requests. Calls for files that
+/// are not allowed are answered with an error and not recorded, unless
+/// `--strict` is set. Each step re-sends the whole conversation, so the
+/// conversation also stops when the next step would exceed the budget. The
+/// last response is returned with the recorded files as its `json` format
+/// content.
+async fn run_tool_calls(
+    endpoint: &Endpoint<'_>,
+    body: &str,
+    (mut res, mut response_bytes): (serde_json::Value, usize),
+    args: &Cli,
+    is_allowed: &dyn Fn(&str) -> bool,
+    (budget, max_tokens): (&Budget<'_>, Option<usize>),
+    usage_log: &mut UsageLog,
+) -> Result<(serde_json::Value, usize), LlmpalError> {
+    let provider = endpoint.provider;
+    let mut body = body.to_string();
+    let mut explanations = Vec::new();
+    let mut files = Vec::new();
+    for step in 1..=tools::MAX_TOOL_STEPS {
+        let (text, _) = provider.extract(&res)?;
+        if !text.trim().is_empty() {
+            explanations.push(text.trim().to_string());
+        }
+        let calls = provider.tool_calls(&res);
+        if calls.is_empty() {
+            break;
+        }
+
+        let mut results = Vec::new();
+        for call in &calls {
+            let result = match tools::write_file_args(call) {
+                Ok((path, _)) if !is_allowed(&path) && !args.strict => {
+                    eprintln!(
+                        "# Rejected {} call for disallowed file: {}",
+                        tools::WRITE_FILE,
+                        path
+                    );
+                    format!(
+                        "Error: {} is not in the list of files you may write; it was not written",
+                        path
+                    )
+                }
+                Ok((path, content)) => {
+                    let result = format!("Recorded {}", path);
+                    files.push((path, content));
+                    result
+                }
+                Err(e) => e,
+            };
+            results.push((call.id.clone(), result));
+        }
+        if step == tools::MAX_TOOL_STEPS {
+            eprintln!(
+                "> Warning: stopped the tool conversation after {} requests",
+                tools::MAX_TOOL_STEPS
+            );
+            break;
+        }
+        body = provider.with_tool_results(&body, &res, &results)?;
+        let step_cost = budget.request_cost(estimate_token_count(&body), max_tokens);
+        if let Err(e) = budget.check(usage_log, step_cost) {
+            eprintln!(
+                "> Warning: stopped the tool conversation after {} requests: {}",
+                step, e
+            );
+            break;
+        }
+        eprintln!(
+            "# Tool step {}: {} call(s), continuing the conversation",
+            step,
+            calls.len()
+        );
+        (res, response_bytes) = post_request(endpoint, args, &body)
+            .await
+            .map_err(LlmpalError::NetworkError)?;
+        let (_, usage) = provider.extract(&res)?;
+        usage_log.record(format!("tool step {}", step + 1), usage);
+    }
+
+    provider.set_text(
+        &mut res,
+        &tools::response_text(&explanations.join("\n\n"), &files),
+    );
+    Ok((res, response_bytes))
+}
+
+/// `--max-cost` accounting over the requests of a run: completed requests
+/// count with their reported cost, failed attempts with their estimate.
+struct Budget<'a> {
+    model_config: &'a config::ModelConfig,
+    max_cost: Option<f64>,
+    /// Estimated cost of the attempts that failed, which may be billed too.
+    failed: f64,
+}
+
+impl Budget<'_> {
+    /// The cost of a request with `input_tokens` of prompt that uses its whole
+    /// completion allowance: `max_tokens`, or the context window without one.
+    fn request_cost(&self, input_tokens: usize, max_tokens: Option<usize>) -> f64 {
+        let completion_tokens = capped_max_tokens(max_tokens, self.model_config.max_completion_cap)
+            .or(self.model_config.context_window)
+            .unwrap_or_default();
+        token_cost(input_tokens as u64, self.model_config.prompt_cost())
+            + token_cost(
+                completion_tokens as u64,
+                self.model_config.completion_cost(),
+            )
+    }
+
+    /// Fails when a request estimated at `request_cost` would bring the run
+    /// above `--max-cost`.
+    fn check(&self, usage_log: &UsageLog, request_cost: f64) -> Result<(), LlmpalError> {
+        check_budget(
+            self.failed + usage_log.cost(self.model_config),
+            request_cost,
+            self.max_cost,
+        )
+    }
+}
+
+fn check_budget(spent: f64, attempt_cost: f64, max_cost: Option<f64>) -> Result<(), LlmpalError> {
+    match max_cost {
+        Some(limit) if spent + attempt_cost > limit => Err(LlmpalError::BudgetExceeded(format!(
+            "next attempt would bring the estimated cost to ${:.4}, limit is ${:.4}",
+            spent + attempt_cost,
+            limit
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn retry_backoff(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(1 << attempt.min(5))
+}
+
+/// The backoff before the next retry, or `None` once either the retry count
+/// or the deadline (measured from the first attempt) would be exceeded.
+fn next_retry_delay(
+    attempts_made: u32,
+    max_retries: u32,
+    deadline: Option<std::time::Duration>,
+    elapsed: std::time::Duration,
+) -> Option<std::time::Duration> {
+    if attempts_made >= max_retries {
+        return None;
+    }
+    let delay = retry_backoff(attempts_made + 1);
+    match deadline {
+        Some(deadline) if elapsed + delay >= deadline => None,
+        _ => Some(delay),
+    }
+}
+
+/// How far the estimate is off from the actual count, as a signed percentage
+/// of the actual count (positive when overestimated).
+fn estimate_error_percent(estimate: u64, actual: u64) -> Option<f64> {
+    (actual > 0).then(|| (estimate as f64 - actual as f64) / actual as f64 * 100.0)
+}
+
+fn estimate_token_count(text: &str) -> usize {
+    text.chars().count() / 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_build_request_ignore_providers() {
+        let ignored = vec!["SlowCo".to_string(), "PriceyAI".to_string()];
+        let options = RequestOptions {
+            model: "m",
+            ignore_providers: Some(&ignored),
+            max_tokens: Some(100),
+            is_default_api_url: true,
+            ..Default::default()
+        };
+        let body = build_request(&options, "sys", "user").unwrap();
+        let json = request_json(&body);
+        assert_eq!(
+            json["provider"]["ignore"],
+            serde_json::json!(["SlowCo", "PriceyAI"])
+        );
+        assert_eq!(json["provider"]["data_collection"], "deny");
+        assert!(json["provider"].get("only").is_none());
+    }
+
+    #[test]
+    fn test_build_request_without_provider_settings() {
+        let options = RequestOptions {
+            model: "m",
+            max_tokens: Some(100),
+            ..Default::default()
+        };
+        let body = build_request(&options, "sys", "user").unwrap();
+        let json = request_json(&body);
+        assert!(json.get("provider").is_none());
+        assert!(json.get("temperature").is_none());
+        assert_eq!(json["max_tokens"], 100);
+    }
+
+    #[test]
+    fn test_build_request_data_collection_override() {
+        let request = |is_default_api_url, data_collection| {
+            let options = RequestOptions {
+                model: "m",
+                is_default_api_url,
+                data_collection,
+                ..Default::default()
+            };
+            request_json(&build_request(&options, "sys", "user").unwrap())
+        };
+        assert_eq!(
+            request(false, Some(config::DataCollection::Deny))["provider"]["data_collection"],
+            "deny"
+        );
+        assert_eq!(
+            request(true, Some(config::DataCollection::Allow))["provider"]["data_collection"],
+            "allow"
+        );
+        assert!(request(false, None).get("provider").is_none());
+    }
+
+    #[test]
+    fn test_build_request_with_temperature() {
+        let options = RequestOptions {
+            model: "m",
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        assert_eq!(json["temperature"], 0.5);
+    }
+
+    #[test]
+    fn test_build_request_clamps_to_completion_cap() {
+        let request = |max_tokens, max_completion_cap| {
+            let options = RequestOptions {
+                model: "m",
+                max_tokens,
+                max_completion_cap,
+                ..Default::default()
+            };
+            let body = build_request(&options, "system", "user").unwrap();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        assert_eq!(request(Some(16384), Some(4096))["max_tokens"], 4096);
+        assert_eq!(request(Some(1000), Some(4096))["max_tokens"], 1000);
+        assert_eq!(request(Some(16384), None)["max_tokens"], 16384);
+        assert!(request(None, Some(4096)).get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_build_request_extra_body() {
+        let extra_body = serde_json::json!({ "min_p": 0.05, "model": "other" });
+        let request = |extra_body_override| {
+            let options = RequestOptions {
+                model: "m",
+                extra_body: extra_body.as_object(),
+                extra_body_override,
+                ..Default::default()
+            };
+            let body = build_request(&options, "system", "user").unwrap();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        let json = request(false);
+        assert_eq!(json["min_p"], 0.05);
+        assert_eq!(json["model"], "m");
+        assert_eq!(request(true)["model"], "other");
+    }
+
+    #[test]
+    fn test_build_request_without_max_tokens() {
+        let options = RequestOptions {
+            model: "m",
+            ..Default::default()
+        };
+        let body = build_request(&options, "sys", "user").unwrap();
+        let json = request_json(&body);
+        assert!(json.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_redact_json_strings_tool_calls() {
+        let mut response = serde_json::json!({
+            "choices": [{ "message": {
+                "content": "Updating a.rs.",
+                "tool_calls": [{ "function": {
+                    "name": "write_file",
+                    "arguments": r#"{"path":"a.rs","content":"fn secret() {}"}"#
+                }}]
+            }}]
+        });
+        redact_json_strings(&mut response, &crate::format::ToolParser);
+        let traced = response.to_string();
+        assert!(!traced.contains("secret"));
+        assert!(traced.contains("[REDACTED 14 bytes]"));
+        assert!(traced.contains("Updating a.rs."));
+    }
+
+    #[test]
+    fn test_check_budget() {
+        assert!(check_budget(0.0, 0.5, None).is_ok());
+        assert!(check_budget(0.5, 0.5, Some(1.0)).is_ok());
+        assert!(matches!(
+            check_budget(0.75, 0.5, Some(1.0)),
+            Err(LlmpalError::BudgetExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_budget_counts_completion_and_usage() {
+        let model_config = config::ModelConfig {
+            prompt_cost: Some(1.0),
+            completion_cost: Some(10.0),
+            ..Default::default()
+        };
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: Some(0.02),
+            failed: 0.0,
+        };
+        // 1000 prompt tokens at $1/1M plus 1000 completion tokens at $10/1M.
+        assert!((budget.request_cost(1000, Some(1000)) - 0.011).abs() < 1e-9);
+
+        let mut usage_log = UsageLog::default();
+        assert!(budget.check(&usage_log, 0.011).is_ok());
+        usage_log.record(
+            "request",
+            Usage {
+                prompt_tokens: 1000,
+                completion_tokens: 1000,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            budget.check(&usage_log, 0.011),
+            Err(LlmpalError::BudgetExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable("Failed to send request: connection reset"));
+        assert!(is_retryable("retry deadline exceeded"));
+        assert!(is_retryable(
+            "API request failed with status 429 Too Many Requests: slow down"
+        ));
+        assert!(is_retryable(
+            "API request failed with status 408 Request Timeout: "
+        ));
+        assert!(is_retryable(
+            "API request failed with status 503 Service Unavailable: overloaded"
+        ));
+        assert!(!is_retryable(
+            "API request failed with status 400 Bad Request: invalid model"
+        ));
+        assert!(!is_retryable(
+            "API request failed with status 404 Not Found: "
+        ));
+    }
+
+    #[test]
+    fn test_next_retry_delay() {
+        use std::time::Duration;
+
+        assert_eq!(
+            next_retry_delay(0, 2, None, Duration::ZERO),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(next_retry_delay(2, 2, None, Duration::ZERO), None);
+        assert_eq!(
+            next_retry_delay(
+                5,
+                u32::MAX,
+                Some(Duration::from_secs(60)),
+                Duration::from_secs(10)
+            ),
+            Some(Duration::from_secs(32))
+        );
+        assert_eq!(
+            next_retry_delay(
+                5,
+                u32::MAX,
+                Some(Duration::from_secs(60)),
+                Duration::from_secs(30)
+            ),
+            None
+        );
+        assert_eq!(
+            next_retry_delay(1, 1, Some(Duration::from_secs(60)), Duration::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn test_estimate_error_percent() {
+        assert_eq!(estimate_error_percent(110, 100), Some(10.0));
+        assert_eq!(estimate_error_percent(75, 100), Some(-25.0));
+        assert_eq!(estimate_error_percent(10, 0), None);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(1).as_secs(), 2);
+        assert_eq!(retry_backoff(2).as_secs(), 4);
+        assert_eq!(retry_backoff(10).as_secs(), 32);
+    }
+
+    #[test]
+    fn test_validate_inputs_collects_all_problems() {
+        let args = Cli {
+            files: vec!["/nonexistent/input.rs".to_string()],
+            output: Some("/nonexistent/dir/out.txt".to_string()),
+            ..Default::default()
+        };
+        let model_config = config::ModelConfig {
+            api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        match validate_inputs(&args, &model_config) {
+            Err(LlmpalError::ValidationError(problems)) => {
+                assert_eq!(problems.len(), 3);
+                assert!(problems[0].contains("Instructions must be provided"));
+                assert!(problems[1].contains("/nonexistent/input.rs"));
+                assert!(problems[2].contains("/nonexistent/dir"));
+            }
+            _ => panic!("expected a combined validation error"),
+        }
+    }
+
+    #[test]
+    fn test_validate_inputs_single_problem() {
+        let args = Cli {
+            instruction: Some("do it".to_string()),
+            files: vec!["/nonexistent/input.rs".to_string()],
+            ..Default::default()
+        };
+        let model_config = config::ModelConfig {
+            api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_inputs(&args, &model_config),
+            Err(LlmpalError::FileError(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_inputs_explanation_file_not_writable_input() {
+        let model_config = config::ModelConfig {
+            api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        let args = Cli {
+            instruction: Some("do it".to_string()),
+            files: vec!["src/main.rs".to_string()],
+            explanation_file: Some("src/main.rs".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_inputs(&args, &model_config),
+            Err(LlmpalError::UsageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_explanation_file_in_input_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "").unwrap();
+        fs::write(dir.path().join("notes.md"), "").unwrap();
+        let dir_path = dir.path().to_string_lossy().into_owned();
+        let args = Cli {
+            files: vec![dir_path.clone()],
+            explanation_file: Some(format!("{}/./notes.md", dir_path)),
+            ..Default::default()
+        };
+        let (input_files, allowed_files) = prepare_files(&&args).unwrap();
+        assert!(matches!(
+            check_explanation_file(&args, &input_files, &allowed_files),
+            Err(LlmpalError::UsageError(_))
+        ));
+
+        let elsewhere = Cli {
+            explanation_file: Some("notes.md".to_string()),
+            ..args
+        };
+        assert!(check_explanation_file(&elsewhere, &input_files, &allowed_files).is_ok());
+    }
+
+    #[test]
+    fn test_validate_inputs_stdout_output_skips_write_check() {
+        let args = Cli {
+            instruction: Some("do it".to_string()),
+            output: Some("-".to_string()),
+            ..Default::default()
+        };
+        let model_config = config::ModelConfig {
+            api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_inputs(&args, &model_config).is_ok());
+
+        let args = Cli {
+            stdin_files: true,
+            ..args
+        };
+        assert!(matches!(
+            validate_inputs(&args, &model_config),
+            Err(LlmpalError::UsageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_request_no_provider_block() {
+        let ignored = vec!["SlowCo".to_string()];
+        let options = RequestOptions {
+            model: "m",
+            provider: Some("Fireworks"),
+            ignore_providers: Some(&ignored),
+            is_default_api_url: true,
+            no_provider_block: true,
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        assert!(json.get("provider").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_api_request_identifying_headers() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_header("user-agent", config::USER_AGENT)
+            .match_header("http-referer", "https://example.org")
+            .match_header("x-title", "org-tool")
+            .match_header("x-request-id", "run-1")
+            .with_status(200)
+            .with_body("{}")
+            .create_async()
+            .await;
+
+        let model_config = config::ModelConfig {
+            http_referer: Some("https://example.org".to_string()),
+            x_title: Some("org-tool".to_string()),
+            ..Default::default()
+        };
+        let client = http_client(&model_config, "run-1").unwrap();
+        send_api_request(&client, "key", &server.url(), "{}")
+            .await
+            .unwrap();
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_api_request_no_identify() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_header("http-referer", mockito::Matcher::Missing)
+            .match_header("x-title", mockito::Matcher::Missing)
+            .match_header("x-request-id", "run-1")
+            .with_status(200)
+            .with_body("{}")
+            .create_async()
+            .await;
+
+        let model_config = config::ModelConfig {
+            no_identify: Some(true),
+            ..Default::default()
+        };
+        let client = http_client(&model_config, "run-1").unwrap();
+        send_api_request(&client, "key", &server.url(), "{}")
+            .await
+            .unwrap();
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_compressed_api_request_falls_back() {
+        let mut server = mockito::Server::new_async().await;
+        let compressed = server
+            .mock("POST", "/")
+            .match_header("content-encoding", "gzip")
+            .with_status(415)
+            .create_async()
+            .await;
+        let plain = server
+            .mock("POST", "/")
+            .match_header("content-encoding", mockito::Matcher::Missing)
+            .match_body("{\"model\":\"m\"}")
+            .with_status(200)
+            .with_body("{\"ok\": true}")
+            .create_async()
+            .await;
+
+        let (res, _) = send_compressed_api_request(
+            &reqwest::Client::new(),
+            "key",
+            &server.url(),
+            "{\"model\":\"m\"}",
+        )
+        .await
+        .unwrap();
+        assert_eq!(res["ok"], true);
+        compressed.assert_async().await;
+        plain.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_per_file_writes_each_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let model_config = config::ModelConfig {
+            api_url: Some(server.url()),
+            ..Default::default()
+        };
+
+        let mut jobs = Vec::new();
+        let mut mocks = Vec::new();
+        for name in ["a.txt", "b.txt"] {
+            let path = dir.path().join(name).to_string_lossy().to_string();
+            fs::write(&path, "old").unwrap();
+            let content = format!(
+                "<explain>\nEdited {}\n</explain>\n<file path=\"{}\">\nnew {}\n</file>",
+                name, path, name
+            );
+            mocks.push(
+                server
+                    .mock("POST", "/")
+                    .match_body(mockito::Matcher::Regex(format!("marker-{}", name)))
+                    .with_status(200)
+                    .with_body(
+                        serde_json::json!({
+                            "choices": [{ "message": { "content": content } }],
+                            "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+                        })
+                        .to_string(),
+                    )
+                    .create_async()
+                    .await,
+            );
+            jobs.push((path, format!("{{\"marker\": \"marker-{}\"}}", name)));
+        }
+
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+        let parser = crate::format::TagParser;
+        run_per_file(
+            jobs.clone(),
+            &endpoint,
+            (&config::Config::default(), &model_config),
+            &parser,
+            1,
+            &Cli::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read_to_string(&jobs[0].0).unwrap(), "new a.txt");
+        assert_eq!(fs::read_to_string(&jobs[1].0).unwrap(), "new b.txt");
+    }
+
+    #[tokio::test]
+    async fn test_run_per_file_continue_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let model_config = config::ModelConfig {
+            api_url: Some(server.url()),
+            ..Default::default()
+        };
+        let _failing = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("marker-a".to_string()))
+            .with_status(500)
+            .create_async()
+            .await;
+        let path = dir.path().join("b.txt").to_string_lossy().to_string();
+        fs::write(&path, "old").unwrap();
+        let content = format!("<file path=\"{}\">\nnew b.txt\n</file>", path);
+        let _ok = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("marker-b".to_string()))
+            .with_status(200)
+            .with_body(
+                serde_json::json!({ "choices": [{ "message": { "content": content } }] })
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let jobs = vec![
+            (
+                dir.path().join("a.txt").to_string_lossy().to_string(),
+                "{\"marker\": \"marker-a\"}".to_string(),
+            ),
+            (path.clone(), "{\"marker\": \"marker-b\"}".to_string()),
+        ];
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+        let parser = crate::format::TagParser;
+
+        let result = run_per_file(
+            jobs.clone(),
+            &endpoint,
+            (&config::Config::default(), &model_config),
+            &parser,
+            1,
+            &Cli::default(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+        let args = Cli {
+            per_file: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = run_per_file(
+            jobs,
+            &endpoint,
+            (&config::Config::default(), &model_config),
+            &parser,
+            1,
+            &args,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new b.txt");
+    }
+
+    #[tokio::test]
+    async fn test_apply_per_file_response_rejects_shrunk_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.rs").to_string_lossy().to_string();
+        let old = "fn a() {}\n".repeat(20);
+        fs::write(&path, &old).unwrap();
+        let response = format!("<file path=\"{}\">\nfn a() {{}}  \n</file>", path);
+        let parser = crate::format::TagParser;
+        let config = config::Config::default();
+
+        let result =
+            apply_per_file_response(&Cli::default(), &config, &parser, &path, &response, None)
+                .await;
+        assert!(result.unwrap_err().contains("--force-shrink"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), old);
+
+        let args = Cli {
+            force_shrink: true,
+            strip_trailing_ws: true,
+            ..Default::default()
+        };
+        apply_per_file_response(&args, &config, &parser, &path, &response, None)
+            .await
+            .unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("fn a() {}"));
+        assert!(!written.contains("}  "));
+    }
+
+    #[tokio::test]
+    async fn test_run_chunked_stops_at_budget() {
+        let mut server = mockito::Server::new_async().await;
+        let model_config = config::ModelConfig {
+            api_url: Some(server.url()),
+            prompt_cost: Some(1.0),
+            completion_cost: Some(1.0),
+            ..Default::default()
+        };
+        let first_chunk = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "choices": [{ "message": { "content": "<explain>\nNothing to do\n</explain>" } }],
+                    "usage": { "prompt_tokens": 1_000_000, "completion_tokens": 5 }
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: Some(0.5),
+            failed: 0.0,
+        };
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+
+        let result = run_chunked(
+            vec![
+                ("{}".to_string(), Vec::new()),
+                ("{}".to_string(), Vec::new()),
+            ],
+            &endpoint,
+            &config::Config::default(),
+            (&budget, Some(10)),
+            &crate::format::TagParser,
+            &Cli::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(LlmpalError::BudgetExceeded(_))));
+        first_chunk.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_chunked_rejects_files_of_other_chunks() {
+        let mut server = mockito::Server::new_async().await;
+        let model_config = config::ModelConfig {
+            api_url: Some(server.url()),
+            ..Default::default()
+        };
+        let _mock = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "choices": [{ "message": { "content": "<file path=\"b.rs\">\nfn b() {}\n</file>" } }]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: None,
+            failed: 0.0,
+        };
+        let args = Cli {
+            strict: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+
+        let result = run_chunked(
+            vec![("{}".to_string(), vec!["a.rs".to_string()])],
+            &endpoint,
+            &config::Config::default(),
+            (&budget, None),
+            &crate::format::TagParser,
+            &args,
+        )
+        .await;
+        assert!(matches!(result, Err(LlmpalError::DisallowedWrite(files)) if files == ["b.rs"]));
+    }
+
+    #[tokio::test]
+    async fn test_run_chunked_rejects_shrunk_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.rs").to_string_lossy().to_string();
+        let old = "fn a() {}\n".repeat(20);
+        fs::write(&path, &old).unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let model_config = config::ModelConfig {
+            api_url: Some(server.url()),
+            ..Default::default()
+        };
+        let content = format!("<file path=\"{}\">\nfn a() {{}}\n</file>", path);
+        let _mock = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(
+                serde_json::json!({ "choices": [{ "message": { "content": content } }] })
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: None,
+            failed: 0.0,
+        };
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+
+        let result = run_chunked(
+            vec![("{}".to_string(), vec![path.clone()])],
+            &endpoint,
+            &config::Config::default(),
+            (&budget, None),
+            &crate::format::TagParser,
+            &Cli::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(LlmpalError::UsageError(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), old);
+    }
+
+    #[tokio::test]
+    async fn test_run_tool_calls() {
+        let mut server = mockito::Server::new_async().await;
+        let finished = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex(r#""role":"tool""#.to_string()),
+                mockito::Matcher::Regex("Recorded src/a.rs.*not in the list".to_string()),
+            ]))
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "choices": [{ "message": { "content": "Done." } }],
+                    "usage": { "prompt_tokens": 30, "completion_tokens": 2 }
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let call = |id: &str, path: &str| {
+            serde_json::json!({
+                "id": id,
+                "type": "function",
+                "function": {
+                    "name": "write_file",
+                    "arguments": serde_json::json!({ "path": path, "content": "new" }).to_string()
+                }
+            })
+        };
+        let first = serde_json::json!({
+            "choices": [{ "message": {
+                "role": "assistant",
+                "content": "Updating a.rs.",
+                "tool_calls": [call("call_1", "src/a.rs"), call("call_2", "secret.txt")]
+            }}],
+            "usage": { "prompt_tokens": 20, "completion_tokens": 10 }
+        });
+        let body = r#"{"model":"m","messages":[{"role":"user","content":"hi"}]}"#;
+        let is_allowed = |path: &str| path == "src/a.rs";
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+        let model_config = config::ModelConfig::default();
+        let mut budget = Budget {
+            model_config: &model_config,
+            max_cost: None,
+            failed: 0.0,
+        };
+        let mut usage_log = UsageLog::default();
+        usage_log.record("request", Usage::from_response(&first).unwrap());
+        let (res, _) = run_tool_calls(
+            &endpoint,
+            body,
+            (first.clone(), 0),
+            &Cli::default(),
+            &is_allowed,
+            (&budget, Some(100)),
+            &mut usage_log,
+        )
+        .await
+        .unwrap();
+        let parsed = crate::format::ToolParser
+            .parse(res["choices"][0]["message"]["content"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(parsed.explanation, "Updating a.rs.\n\nDone.");
+        assert_eq!(
+            parsed.file_pairs(),
+            vec![("src/a.rs".to_string(), "new".to_string())]
+        );
+        assert_eq!(usage_log.len(), 2);
+        assert_eq!(usage_log.total().unwrap().prompt_tokens, 50);
+
+        // Out of budget: the calls are kept, but no further step is sent.
+        budget.max_cost = Some(0.0);
+        let (res, _) = run_tool_calls(
+            &endpoint,
+            body,
+            (first, 0),
+            &Cli::default(),
+            &is_allowed,
+            (&budget, Some(100)),
+            &mut UsageLog::default(),
+        )
+        .await
+        .unwrap();
+        finished.assert_async().await;
+        let parsed = crate::format::ToolParser
+            .parse(res["choices"][0]["message"]["content"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(parsed.file_pairs().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_follow_up_short_completion() {
+        let mut server = mockito::Server::new_async().await;
+        let full = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("seems incomplete".to_string()))
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "choices": [{ "message": { "content": "the full output" } }],
+                    "usage": { "prompt_tokens": 30, "completion_tokens": 200 }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let short = serde_json::json!({
+            "choices": [{ "message": { "content": "..." } }],
+            "usage": { "prompt_tokens": 20, "completion_tokens": 3 }
+        });
+        let body = r#"{"model":"m","messages":[{"role":"user","content":"hi"}]}"#;
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+        let model_config = config::ModelConfig::default();
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: None,
+            failed: 0.0,
+        };
+        let mut usage_log = UsageLog::default();
+        usage_log.record("request", Usage::from_response(&short).unwrap());
+        let (res, _) = follow_up_short_completion(
+            &endpoint,
+            body,
+            &Cli::default(),
+            (short.clone(), 0),
+            100,
+            (&budget, None),
+            &mut usage_log,
+        )
+        .await
+        .unwrap();
+
+        full.assert_async().await;
+        assert_eq!(res["choices"][0]["message"]["content"], "the full output");
+        let total = usage_log.total().unwrap();
+        assert_eq!(total.prompt_tokens, 50);
+        assert_eq!(total.completion_tokens, 203);
+
+        // Nothing is sent when the follow-up would exceed --max-cost.
+        let priced = config::ModelConfig {
+            completion_cost: Some(1000.0),
+            ..Default::default()
+        };
+        let budget = Budget {
+            model_config: &priced,
+            max_cost: Some(0.5),
+            failed: 0.0,
+        };
+        let (res, _) = follow_up_short_completion(
+            &endpoint,
+            body,
+            &Cli::default(),
+            (short, 0),
+            100,
+            (&budget, Some(1000)),
+            &mut UsageLog::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res["choices"][0]["message"]["content"], "...");
+        full.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_continue_truncated() {
+        let mut server = mockito::Server::new_async().await;
+        let rest = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("was cut off".to_string()),
+                mockito::Matcher::Regex(r#""content":"<file path=\\"a.rs\\">","role":"assistant""#.to_string()),
+            ]))
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "choices": [{ "message": { "content": "\nfn a() {}\n</file>" }, "finish_reason": "stop" }],
+                    "usage": { "prompt_tokens": 40, "completion_tokens": 5 }
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let cut_off = serde_json::json!({
+            "choices": [{ "message": { "content": "<file path=\"a.rs\">" }, "finish_reason": "length" }],
+            "usage": { "prompt_tokens": 20, "completion_tokens": 8 }
+        });
+        let body = r#"{"model":"m","messages":[{"role":"user","content":"hi"}]}"#;
+        let client = reqwest::Client::new();
+        let url = server.url();
+        let endpoint = Endpoint {
+            client: &client,
+            api_key: "key",
+            api_url: &url,
+            provider: &provider::OpenRouterProvider,
+        };
+        let model_config = config::ModelConfig::default();
+        let budget = Budget {
+            model_config: &model_config,
+            max_cost: None,
+            failed: 0.0,
+        };
+        let mut usage_log = UsageLog::default();
+        usage_log.record("request", Usage::from_response(&cut_off).unwrap());
+        let (res, _) = continue_truncated(
+            &endpoint,
+            body,
+            &Cli::default(),
+            (cut_off.clone(), 0),
+            (&budget, None),
+            &mut usage_log,
+        )
+        .await
+        .unwrap();
+
+        rest.assert_async().await;
+        assert_eq!(
+            res["choices"][0]["message"]["content"],
+            "<file path=\"a.rs\">\nfn a() {}\n</file>"
+        );
+        assert_eq!(usage_log.total().unwrap().completion_tokens, 13);
+
+        // Nothing is sent once the budget is used up.
+        let priced = config::ModelConfig {
+            prompt_cost: Some(1000.0),
+            ..Default::default()
+        };
+        let budget = Budget {
+            model_config: &priced,
+            max_cost: Some(0.001),
+            failed: 0.0,
+        };
+        let mut usage_log = UsageLog::default();
+        usage_log.record("request", Usage::from_response(&cut_off).unwrap());
+        let (res, _) = continue_truncated(
+            &endpoint,
+            body,
+            &Cli::default(),
+            (cut_off.clone(), 0),
+            (&budget, None),
+            &mut usage_log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res["choices"][0]["message"]["content"],
+            "<file path=\"a.rs\">"
+        );
+
+        // Nor when the next request alone would exceed it.
+        let priced = config::ModelConfig {
+            completion_cost: Some(1000.0),
+            ..Default::default()
+        };
+        let budget = Budget {
+            model_config: &priced,
+            max_cost: Some(0.5),
+            failed: 0.0,
+        };
+        let (res, _) = continue_truncated(
+            &endpoint,
+            body,
+            &Cli::default(),
+            (cut_off, 0),
+            (&budget, Some(1000)),
+            &mut UsageLog::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res["choices"][0]["message"]["content"],
+            "<file path=\"a.rs\">"
+        );
+        rest.assert_async().await;
+    }
+
+    #[test]
+    fn test_format_bench() {
+        let ms = std::time::Duration::from_millis;
+        let samples: Vec<BenchSample> = (1..=20)
+            .map(|i| BenchSample {
+                first_token: Some(ms(i * 10)),
+                total: ms(i * 100),
+                completion_tokens: 100,
+            })
+            .collect();
+        let report = format_bench(&samples, 1);
+        assert!(report.contains("Runs: 21 (1 failed)"), "{}", report);
+        assert!(report.contains("p50 1.00s | p95 1.90s | min 0.10s | max 2.00s"));
+        assert!(report.contains("p50 0.10s | p95 0.19s"));
+        assert!(report.contains("95.2 completion tokens/s"));
+
+        assert_eq!(percentile(&[ms(5)], 95), ms(5));
+        let without_stream = format_bench(
+            &[BenchSample {
+                first_token: None,
+                ..samples.into_iter().next().unwrap()
+            }],
+            0,
+        );
+        assert!(!without_stream.contains("First token"));
+    }
+
+    #[test]
+    fn test_prepare_files_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["c.rs", "a.rs", "b.rs"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let dir_path = dir.path().to_string_lossy().into_owned();
+        let extra = dir.path().join("0.md").to_string_lossy().into_owned();
+        let args = Cli {
+            files: vec![dir_path.clone(), extra.clone()],
+            ..Default::default()
+        };
+        let (input_files, allowed_files) = prepare_files(&&args).unwrap();
+        let expected = vec![
+            extra,
+            format!("{}/a.rs", dir_path),
+            format!("{}/b.rs", dir_path),
+            format!("{}/c.rs", dir_path),
+        ];
+        assert_eq!(input_files, expected);
+        assert_eq!(allowed_files, expected);
+    }
+
+    #[test]
+    fn test_prepare_files_writable() {
+        let args = Cli {
+            files: vec!["src/a.rs".to_string(), "src/b.rs".to_string()],
+            writable: vec!["src/b.rs".to_string()],
+            output: Some("NOTES.md".to_string()),
+            ..Default::default()
+        };
+        let (input_files, allowed_files) = prepare_files(&&args).unwrap();
+        assert_eq!(input_files, vec!["src/a.rs", "src/b.rs"]);
+        assert_eq!(allowed_files, vec!["NOTES.md", "src/b.rs"]);
+    }
+
+    #[test]
+    fn test_find_invalid_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        fs::write(path("ok.txt"), "fine\n").unwrap();
+        fs::write(path("latin1.txt"), b"caf\xe9\n").unwrap();
+        let inputs = vec![path("ok.txt"), path("latin1.txt"), path("missing.txt")];
+
+        let invalid = find_invalid_inputs(&inputs);
+        assert_eq!(invalid.len(), 2);
+        assert_eq!(
+            invalid[0],
+            (path("latin1.txt"), "not valid UTF-8 at byte 3".to_string())
+        );
+        assert_eq!(invalid[1].0, path("missing.txt"));
+    }
+
+    #[test]
+    fn test_check_input_file_count() {
+        assert!(check_input_file_count(200, None).is_ok());
+        assert!(matches!(
+            check_input_file_count(201, None),
+            Err(LlmpalError::UsageError(_))
+        ));
+        assert!(check_input_file_count(500, Some(1000)).is_ok());
+        assert!(check_input_file_count(3, Some(2)).is_err());
+    }
+
+    #[test]
+    fn test_prepare_files_line_range() {
+        let args = Cli {
+            files: vec!["src/big.rs:100-200".to_string(), "src/a.rs".to_string()],
+            ..Default::default()
+        };
+        let (input_files, allowed_files) = prepare_files(&&args).unwrap();
+        assert_eq!(input_files, vec!["src/a.rs", "src/big.rs:100-200"]);
+        assert_eq!(allowed_files, vec!["src/a.rs"]);
+    }
+
+    #[test]
+    fn test_format_forecast() {
+        let model_config = config::ModelConfig {
+            model: "test-model".to_string(),
+            prompt_cost: Some(2.0),
+            ..Default::default()
+        };
+        let table = format_forecast(
+            &[(750_000, "src/app.rs"), (250_000, "src/lib.rs")],
+            &model_config,
+        );
+        assert_eq!(
+            table,
+            "    Tokens  File\n    750000  src/app.rs\n    250000  src/lib.rs\n\
+             # Model: test-model | Files: 2 | Estimated input tokens: 1000000 | Projected input cost: $2.0000 ($2.0000/1M prompt)\n"
+        );
+    }
+
+    #[test]
+    fn test_unwrap_json_encoded() {
+        let parser = crate::format::TagParser;
+        let plain = "<explain>\nDone\n</explain>\n<file path=\"a.rs\">\nfn a() {}\n</file>";
+        let encoded = serde_json::to_string(plain).unwrap();
+        assert_eq!(unwrap_json_encoded(&parser, encoded), plain);
+        assert_eq!(unwrap_json_encoded(&parser, plain.to_string()), plain);
+        assert_eq!(
+            unwrap_json_encoded(&parser, "\"just a quote".to_string()),
+            "\"just a quote"
+        );
+    }
+
+    #[test]
+    fn test_allowed_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let tests = dir.path().join("tests").to_string_lossy().into_owned();
+        fs::create_dir(&tests).unwrap();
+        let allowed = vec!["src/a.rs".to_string()];
+        let under = vec![tests.clone()];
+
+        assert_eq!(
+            allowed_path(&allowed, &under, "./src/a.rs"),
+            Some("src/a.rs".to_string())
+        );
+        let new_test = format!("{}/new_test.rs", tests);
+        assert_eq!(allowed_path(&allowed, &under, &new_test), Some(new_test));
+        assert_eq!(
+            allowed_path(&allowed, &under, &format!("{}/../b.rs", tests)),
+            None
+        );
+        assert_eq!(allowed_path(&allowed, &[], "src/b.rs"), None);
+    }
+
+    #[test]
+    fn test_extra_files() {
+        let files = vec![
+            ("./src/a.rs".to_string(), "a".to_string()),
+            ("src/b.rs".to_string(), "b".to_string()),
+        ];
+        assert_eq!(extra_files(&files, "src/a.rs"), vec!["src/b.rs"]);
+        assert!(extra_files(&files[..1], "src/a.rs").is_empty());
+    }
+
+    #[test]
+    fn test_reject_disallowed_strict() {
+        let args = Cli {
+            strict: true,
+            ..Default::default()
+        };
+        match reject_disallowed(&args, "response", vec!["/etc/passwd".to_string()]) {
+            Err(LlmpalError::DisallowedWrite(files)) => assert_eq!(files, vec!["/etc/passwd"]),
+            other => panic!("expected DisallowedWrite, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_with_request_id() {
+        let e = LlmpalError::NetworkError("timeout".to_string()).with_request_id("abc");
+        assert_eq!(e.to_string(), "timeout (request id: abc)");
+        let e = LlmpalError::ApiKeyMissing.with_request_id("abc");
+        assert!(!e.to_string().contains("abc"));
+    }
+
+    #[test]
+    fn test_api_error_auth_failure() {
+        let mut model_config = config::ModelConfig {
+            code: "kimi".to_string(),
+            ..Default::default()
+        };
+        let unauthorized =
+            "API request failed with status 401 Unauthorized: {\"error\":\"invalid key\"}";
+        let e = api_error(&model_config, unauthorized.to_string());
+        assert!(matches!(e, LlmpalError::AuthError(_)));
+        assert!(
+            e.to_string()
+                .contains("OPENROUTER_API_KEY environment variable")
+        );
+        assert!(e.to_string().contains("invalid key"));
+
+        model_config.api_key = Some("sk-test".to_string());
+        let forbidden = "API request failed with status 403 Forbidden: denied".to_string();
+        assert!(
+            api_error(&model_config, forbidden)
+                .to_string()
+                .contains("the api_key of model 'kimi' in the config")
+        );
+
+        let server_error = "API request failed with status 500 Internal Server Error: oops";
+        assert!(matches!(
+            api_error(&model_config, server_error.to_string()),
+            LlmpalError::NetworkError(_)
+        ));
+    }
+
+    #[test]
+    fn test_chunk_files() {
+        let file = |path: &str, tokens: usize| (path.to_string(), "abcd".repeat(tokens));
+        let files = vec![
+            file("a", 40),
+            file("b", 50),
+            file("c", 30),
+            file("d", 200),
+            file("e", 10),
+        ];
+        let chunks: Vec<Vec<String>> = chunk_files(&files, 100)
+            .into_iter()
+            .map(|chunk| chunk.into_iter().map(|(path, _)| path).collect())
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![vec!["a", "b"], vec!["c"], vec!["d"], vec!["e"]]
+        );
+    }
+
+    #[test]
+    fn test_served_model_label() {
+        let model_config = config::ModelConfig {
+            model: "openrouter/auto".to_string(),
+            ..Default::default()
+        };
+        let res = serde_json::json!({ "model": "openrouter/auto" });
+        assert_eq!(served_model_label(&model_config, &res), "openrouter/auto");
+
+        let res = serde_json::json!({ "model": "anthropic/claude-sonnet-4", "provider": "Google" });
+        assert_eq!(
+            served_model_label(&model_config, &res),
+            "openrouter/auto [served: anthropic/claude-sonnet-4] [provider: Google]"
+        );
+    }
+
+    #[test]
+    fn test_build_request_reasoning() {
+        let request = |reasoning, is_default_api_url| {
+            let options = RequestOptions {
+                model: "m",
+                reasoning: Some(reasoning),
+                is_default_api_url,
+                ..Default::default()
+            };
+            request_json(&build_request(&options, "sys", "user").unwrap())
+        };
+
+        assert_eq!(request("high", true)["reasoning"]["effort"], "high");
+        assert_eq!(request("high", false)["reasoning_effort"], "high");
+        assert_eq!(request("2048", false)["reasoning"]["max_tokens"], 2048);
+        assert!(request("low", true).get("reasoning_effort").is_none());
+    }
+
+    #[test]
+    fn test_build_request_with_examples() {
+        let examples = vec![config::Example {
+            user: "Add a greeting".to_string(),
+            assistant: "<explain>\nAdded\n</explain>".to_string(),
+        }];
+        let options = RequestOptions {
+            model: "m",
+            examples: &examples,
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        let roles: Vec<&str> = json["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(json["messages"][1]["content"], "Add a greeting");
+        assert_eq!(json["messages"][3]["content"], "user");
+    }
+
+    #[test]
+    fn test_build_request_no_system() {
+        let options = RequestOptions {
+            model: "m",
+            no_system: true,
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        assert_eq!(
+            json["messages"],
+            serde_json::json!([{ "role": "user", "content": "sys\n\nuser" }])
+        );
+    }
+
+    #[test]
+    fn test_build_request_split_system() {
+        let options = RequestOptions {
+            model: "m",
+            split_system: true,
+            ..Default::default()
+        };
+        let system = "Guidelines\n\n# Additional rules\n- r\n\n# Output format\nTags";
+        let json = request_json(&build_request(&options, system, "user").unwrap());
+        assert_eq!(
+            json["messages"],
+            serde_json::json!([
+                { "role": "system", "content": "Guidelines" },
+                { "role": "system", "content": "# Additional rules\n- r" },
+                { "role": "system", "content": "# Output format\nTags" },
+                { "role": "user", "content": "user" }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_build_request_with_stop() {
+        let stop = vec!["END".to_string(), "</file>".to_string()];
+        let options = RequestOptions {
+            model: "m",
+            stop: &stop,
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        assert_eq!(json["stop"], serde_json::json!(["END", "</file>"]));
+
+        let options = RequestOptions {
+            model: "m",
+            ..Default::default()
+        };
+        let json = request_json(&build_request(&options, "sys", "user").unwrap());
+        assert!(json.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_validate_inputs_too_many_stop_sequences() {
+        let args = Cli {
+            instruction: Some("do it".to_string()),
+            stop: ["a", "b", "c", "d", "e"].map(String::from).to_vec(),
+            ..Default::default()
+        };
+        let model_config = config::ModelConfig {
+            api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_inputs(&args, &model_config),
+            Err(LlmpalError::UsageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_schema_output_summary_only_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.json");
+        let args = Cli {
+            summary_only: true,
+            output: Some(output.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let schema = serde_json::json!({"type": "object"});
+        write_schema_output(&args, &schema, r#"{"name": "llmpal"}"#).unwrap();
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_print_proposed_changes_counts_real_changes() {
+        let originals = vec![
+            ("same.rs".to_string(), "fn a() {}\n".to_string()),
+            ("edit.rs".to_string(), "fn b() {}\n".to_string()),
+        ];
+        let files = vec![
+            ("same.rs".to_string(), "fn a() {}".to_string()),
+            ("edit.rs".to_string(), "fn b() { todo!() }".to_string()),
+            ("new.rs".to_string(), "fn c() {}".to_string()),
+        ];
+        assert_eq!(print_proposed_changes(&originals, &files), 2);
+        assert_eq!(print_proposed_changes(&originals, &files[..1]), 0);
+    }
+
+    #[test]
+    fn test_confirm_without_terminal() {
+        let args = Cli {
+            stdin_files: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            confirm(&args, "Proceed? [y/N]: "),
+            Err(LlmpalError::UsageError(message)) if message.contains("Proceed? [y/N]:")
+        ));
+    }
+
+    #[test]
+    fn test_find_shrunk_files() {
+        let originals = vec![
+            ("big.rs".to_string(), "x".repeat(1000)),
+            ("small.rs".to_string(), "x".repeat(100)),
+        ];
+        let files = vec![
+            ("big.rs".to_string(), "x".repeat(100)),
+            ("small.rs".to_string(), "x".repeat(90)),
+            ("new.rs".to_string(), String::new()),
+        ];
+        assert_eq!(
+            find_shrunk_files(&originals, &files, 0.3),
+            vec![("big.rs".to_string(), 1000, 100)]
+        );
+        assert!(find_shrunk_files(&originals, &files, 0.05).is_empty());
+    }
+
+    #[test]
+    fn test_request_temperature() {
+        let code = vec!["src/main.rs".to_string()];
+        let docs = vec!["README.md".to_string()];
+        let config = config::Config::default();
+        let model_config = config::ModelConfig::default();
+        assert_eq!(
+            request_temperature(&Cli::default(), &model_config, &config, &code),
+            Some(config::DEFAULT_CODE_TEMPERATURE)
+        );
+        assert_eq!(
+            request_temperature(&Cli::default(), &model_config, &config, &docs),
+            None
+        );
+
+        let configured = config::Config {
+            code_temperature: Some(Some(0.0)),
+            ..Default::default()
+        };
+        assert_eq!(
+            request_temperature(&Cli::default(), &model_config, &configured, &code),
+            Some(0.0)
+        );
+        let disabled: config::Config =
+            serde_json::from_str(r#"{"code_temperature": null}"#).unwrap();
+        assert_eq!(disabled.code_temperature, Some(None));
+        assert_eq!(
+            request_temperature(&Cli::default(), &model_config, &disabled, &code),
+            None
+        );
+        let model_config = config::ModelConfig {
+            temperature: Some(0.7),
+            ..Default::default()
+        };
+        assert_eq!(
+            request_temperature(&Cli::default(), &model_config, &config, &code),
+            Some(0.7)
+        );
+        let args = Cli {
+            temperature: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            request_temperature(&args, &model_config, &config, &docs),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn test_context_length_retry_tokens() {
+        let openai = r#"API request failed with status 400 Bad Request: {"error":{"message":"This model's maximum context length is 8192 tokens. However, you requested 9000 tokens (5000 in the messages, 4000 in the completion).","code":"context_length_exceeded"}}"#;
+        assert_eq!(context_length_retry_tokens(openai, 4000, None), Some(3192));
+
+        let openrouter = "This endpoint's maximum context length is 131072 tokens. However, you requested about 140000 tokens (120000 of text input, 20000 in the output).";
+        assert_eq!(
+            context_length_retry_tokens(openrouter, 100_000, None),
+            Some(11072)
+        );
+
+        let anthropic = "input length and `max_tokens` exceed context limit: 190000 + 16384 > 200000, decrease input length or `max_tokens` and try again";
+        assert_eq!(
+            context_length_retry_tokens(anthropic, 150_000, None),
+            Some(10000)
+        );
+
+        let unparsed = "context_length_exceeded";
+        assert_eq!(
+            context_length_retry_tokens(unparsed, 1000, Some(8192)),
+            Some(7192)
+        );
+        assert_eq!(context_length_retry_tokens(unparsed, 1000, None), None);
+        assert_eq!(
+            context_length_retry_tokens(unparsed, 9000, Some(8192)),
+            None
+        );
+        assert_eq!(
+            context_length_retry_tokens("Failed to send request: timeout", 1000, Some(8192)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fit_context_window() {
+        assert_eq!(
+            fit_context_window(120_000, Some(16384), 131072),
+            Some(11072)
+        );
+        assert_eq!(fit_context_window(1000, Some(16384), 131072), Some(16384));
+        assert_eq!(fit_context_window(1000, None, 131072), None);
+    }
+
+    #[test]
+    fn test_phase_timings_display() {
+        let timings = PhaseTimings {
+            read: std::time::Duration::from_millis(1500),
+            network: std::time::Duration::from_millis(3250),
+            ..Default::default()
+        };
+        assert_eq!(
+            timings.to_string(),
+            "# Timing: read 1.500s | prompt 0.000s | network 3.250s | parse 0.000s | write 0.000s"
+        );
+    }
+
+    #[test]
+    fn test_extra_section() {
+        let parsed = crate::format::TagParser
+            .parse("Sure, here it is.\n<explain>\nDone\n</explain>\nHope this helps!")
+            .unwrap();
+        let show_extra = Cli {
+            show_extra: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            extra_section(&show_extra, &parsed.extra).as_deref(),
+            Some("::EXTRA::\nSure, here it is.\nHope this helps!")
+        );
+        assert_eq!(extra_section(&Cli::default(), &parsed.extra), None);
+        assert_eq!(extra_section(&show_extra, "\n  \n"), None);
+    }
+
+    #[test]
+    fn test_cost_footer() {
+        let footer = cost_footer(
+            "kimi",
+            1200,
+            "300",
+            0.0015,
+            std::time::Duration::from_millis(3200),
+        );
+        assert_eq!(
+            footer,
+            "\n| Model | Prompt tokens | Completion tokens | Cost | Time |\n\
+             |---|---|---|---|---|\n\
+             | kimi | 1200 | 300 | $0.0015 | 3.20s |\n"
+        );
+    }
+
+    #[test]
+    fn test_write_report() {
+        assert_eq!(
+            write_report("src/main.rs", Some("a\nb\n"), "a\nc\nd\n"),
+            "# Wrote src/main.rs (+2 -1 lines, 6B)"
+        );
+        assert_eq!(
+            write_report("new.rs", None, &"x\n".repeat(1000)),
+            "# Created new.rs (1000 lines, 2.0KB)"
+        );
+    }
+
+    #[test]
+    fn test_summarize_changes() {
+        let input_files = vec!["a.rs".to_string(), "b.rs".to_string()];
+        let files = vec![
+            ("a.rs".to_string(), "new a".to_string()),
+            ("out.md".to_string(), "doc".to_string()),
+        ];
+        let summary = summarize_changes(&input_files, &files);
+        assert_eq!(summary.modified, vec!["a.rs"]);
+        assert_eq!(summary.unchanged, vec!["b.rs"]);
+        assert_eq!(summary.created, vec!["out.md"]);
+        assert_eq!(
+            summary.to_string(),
+            "# Modified: a.rs | Unchanged: b.rs | Created: out.md"
+        );
+    }
+
+    #[test]
+    fn test_summarize_changes_nothing_written() {
+        let input_files = vec!["a.rs".to_string()];
+        let summary = summarize_changes(&input_files, &[]);
+        assert!(summary.modified.is_empty());
+        assert_eq!(summary.unchanged, vec!["a.rs"]);
+        assert_eq!(
+            summary.to_string(),
+            "# Modified: - | Unchanged: a.rs | Created: -"
+        );
+    }
 }
@@ -0,0 +1,32 @@
+#[cfg(not(feature = "clipboard"))]
+const NOT_BUILT: &str =
+    "llmpal was built without clipboard support (enable the 'clipboard' feature)";
+
+/// Whether llmpal was built with the `clipboard` feature.
+pub fn is_available() -> bool {
+    cfg!(feature = "clipboard")
+}
+
+#[cfg(feature = "clipboard")]
+pub fn read() -> Result<String, String> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .map_err(|e| format!("Cannot read the clipboard: {}", e))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn read() -> Result<String, String> {
+    Err(NOT_BUILT.to_string())
+}
+
+#[cfg(feature = "clipboard")]
+pub fn write(content: &str) -> Result<(), String> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(content))
+        .map_err(|e| format!("Cannot write to the clipboard: {}", e))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn write(_content: &str) -> Result<(), String> {
+    Err(NOT_BUILT.to_string())
+}
@@ -1,19 +1,39 @@
-use clap::Parser;
-use serde::Deserialize;
+use crate::format::ResponseFormat;
+use crate::utils;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
 
 pub const OPEN_ROUTER_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
 pub const DEFAULT_MODEL: &str = "moonshotai/kimi-k2";
 pub const DEFAULT_PROMPT_COST: f64 = 0.60;
 pub const DEFAULT_COMPLETION_COST: f64 = 2.50;
 pub const DEFAULT_MAX_TOKENS: usize = 16384;
+pub const DEFAULT_SHRINK_THRESHOLD: f64 = 0.3;
+pub const DEFAULT_CODE_TEMPERATURE: f64 = 0.1;
+pub const MAX_STOP_SEQUENCES: usize = 4;
+pub const STDOUT_OUTPUT: &str = "-";
+pub const CLIPBOARD_OUTPUT: &str = "clipboard:";
+pub const DEFAULT_HTTP_REFERER: &str = "https://github.com/00dev-org/llmpal";
+pub const DEFAULT_X_TITLE: &str = "llmpal";
+pub const USER_AGENT: &str = concat!("llmpal/", env!("CARGO_PKG_VERSION"));
+pub const MODEL_ENV_VAR: &str = "LLMPAL_MODEL";
+pub const PROFILE_ENV_VAR: &str = "LLMPAL_PROFILE";
+pub const CONFIG_FILE: &str = ".llmpal.json";
+const SHARED_CONFIG_CACHE_FILE: &str = "shared_config.json";
+const SHARED_CONFIG_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Default, Clone)]
 #[command(author, version, about, long_about = None, after_help = "\
 Examples:\n\
   llmpal -f src/main.rs 'Generate unit tests'\n\
   llmpal -f src/main.rs -f src/config.rs -o README.md 'Create a README.md file'\n\
   llmpal -f src/main.rs 'Explain what this code is doing'\n\
+  llmpal --since-commit main 'Review my changes'\n\
   llmpal -o src/countries.json 'Create a JSON file with a list of G20 countries. Fields: name, code.'\n\
   ")]
 pub struct Cli {
@@ -21,23 +41,86 @@ pub struct Cli {
         long = "file",
         short = 'f',
         value_name = "FILE",
-        help = "Input files to work with. They will be sent to the LLM, and might be modified."
+        help = "Input files to work with. They will be sent to the LLM, and might be modified. FILE:START-END sends only those lines, read-only."
     )]
     pub files: Vec<String>,
-    #[arg(long, short = 'v', help = "Logs LLM prompt and response to stderr.")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Reads additional input files as a NUL-delimited list from PATH, or stdin for '-' (e.g. `git ls-files -z`)."
+    )]
+    pub files0_from: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "per_file",
+        help = "Restricts writes to this path (repeatable); other input files are sent as read-only context."
+    )]
+    pub writable: Vec<String>,
+    #[arg(
+        long,
+        visible_alias = "max-files",
+        value_name = "N",
+        help = "Fails when more than N input files are collected (default 200). Overrides max_input_files from the config."
+    )]
+    pub max_input_files: Option<usize>,
+    #[arg(
+        long,
+        help = "Proceeds even when more input files than --max-input-files are collected."
+    )]
+    pub force: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        num_args = 0..=1,
+        default_missing_value = "abort",
+        help = "Checks all input files before the run and lists every one that cannot be read or is not valid UTF-8, then aborts (abort, the default) or leaves them out (skip)."
+    )]
+    pub verify_utf8_input: Option<InvalidInputAction>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "per_file",
+        help = "Also allows writing any file inside DIR (repeatable), e.g. new tests under tests/. Paths are resolved first, so '..' and symlinks cannot escape it."
+    )]
+    pub allow_under: Vec<String>,
+    #[arg(
+        long,
+        short = 'v',
+        help = "Logs LLM prompt and response to stderr (debug level)."
+    )]
     pub verbose: bool,
     #[arg(
         long,
         short = 'o',
         value_name = "OUTPUT",
-        help = "Path to output file. The LLM will be allowed to write to it."
+        help = "Path to output file. The LLM will be allowed to write to it. Use '-' to print generated files to stdout, or 'clipboard:' to copy the output to the clipboard. {model} and {timestamp} are expanded, e.g. out/{model}/{timestamp}/result.md."
     )]
     pub output: Option<String>,
     #[arg(
         long,
-        help = "Logs the full JSON sent and received during API calls to stderr."
+        help = "Prints generated file contents to stdout instead of writing them to disk."
+    )]
+    pub output_stdout: bool,
+    #[arg(
+        long,
+        requires = "output",
+        help = "Fails unless the response contains the output file, after one follow-up request asking for it."
+    )]
+    pub require_output: bool,
+    #[arg(long, help = "Create missing parent directories of the output file.")]
+    pub mkdir: bool,
+    #[arg(
+        long,
+        help = "Logs the full JSON sent and received during API calls to stderr (trace level)."
     )]
     pub trace: bool,
+    #[arg(
+        long,
+        help = "Replaces file contents, diffs and --context-cmd output in logged prompts and responses with [REDACTED N bytes], for sharing traces."
+    )]
+    pub trace_redact_files: bool,
     #[arg(
         long,
         short = 'm',
@@ -45,6 +128,18 @@ pub struct Cli {
         help = "Use a different model configured in the .llmpal.json file."
     )]
     pub model: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Applies the named profile from the config's `profiles` over the top-level settings (or set LLMPAL_PROFILE)."
+    )]
+    pub profile: Option<String>,
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "API endpoint to send requests to, overriding the selected model's api_url."
+    )]
+    pub api_url: Option<String>,
     #[arg(value_name = "INSTRUCTIONS", help = "Instructions for the LLM.")]
     pub instruction: Option<String>,
     #[arg(
@@ -54,113 +149,1028 @@ pub struct Cli {
         help = "Path to a text file containing instructions for the LLM"
     )]
     pub instruction_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with = "instruction_file",
+        help = "Uses ~/.llmpal/templates/NAME.txt as the instruction; {{args}} in it is replaced by the INSTRUCTIONS argument."
+    )]
+    pub template: Option<String>,
+    #[arg(long, help = "Lists the templates in ~/.llmpal/templates and exits.")]
+    pub list_templates: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["instruction", "instruction_file"],
+        help = "Reads the instruction from the system clipboard (requires the 'clipboard' feature)."
+    )]
+    pub clipboard: bool,
+    #[arg(
+        long,
+        value_name = "REF",
+        help = "Include the output of `git diff <REF>` in the prompt as review context."
+    )]
+    pub since_commit: Option<String>,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Runs a shell command and includes its stdout in the prompt as read-only context (repeatable, e.g. 'cargo check 2>&1')."
+    )]
+    pub context_cmd: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Number of times to retry a failed API request (default 0, or unlimited within --retry-deadline)."
+    )]
+    pub retries: Option<u32>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Gives up retrying once this many seconds have passed since the first attempt, including backoffs."
+    )]
+    pub retry_deadline: Option<u64>,
+    #[arg(
+        long,
+        value_name = "USD",
+        help = "Maximum total cost of all attempts. Overrides max_cost from the config."
+    )]
+    pub max_cost: Option<f64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How long to wait for another llmpal run writing the same files before giving up. Overrides lock_timeout from the config."
+    )]
+    pub lock_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Asks for the full output again when the completion is shorter than N tokens. Overrides min_completion_tokens from the config."
+    )]
+    pub min_tokens: Option<u64>,
+    #[arg(
+        long,
+        conflicts_with_all = ["per_file", "chunk_tokens", "sweep_temperature", "bench"],
+        help = "When the completion is cut off at max_tokens, asks the model to continue where it left off and joins the pieces, within --max-cost."
+    )]
+    pub keep_going_past_max_tokens: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "keep_going_past_max_tokens",
+        help = "Continuation requests sent at most with --keep-going-past-max-tokens (default 3)."
+    )]
+    pub max_continuations: Option<u32>,
+    #[arg(
+        long,
+        help = "Lists models available from the selected model's API endpoint and exits."
+    )]
+    pub fetch_models: bool,
+    #[arg(
+        long,
+        help = "Prints per-file token estimates and the projected input cost for the selected model, without sending anything."
+    )]
+    pub forecast: bool,
+    #[arg(
+        long,
+        help = "Prints the effective rules (config, rules files and --rule flags, in order) and exits."
+    )]
+    pub list_rules: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHERE",
+        num_args = 0..=1,
+        default_missing_value = "local",
+        help = "Writes an example config to ./.llmpal.json (local, the default) or ~/.llmpal.json (home) and exits."
+    )]
+    pub init: Option<InitTarget>,
+    #[arg(
+        long,
+        help = "Reads additional input files from a tar archive on stdin and writes the results as a tar archive to stdout."
+    )]
+    pub stdin_files: bool,
+    #[arg(
+        long,
+        help = "Omits max_tokens from the request so the provider's default applies."
+    )]
+    pub no_max_tokens: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "no_max_tokens",
+        help = "Overrides the model's configured max_tokens for this run (0 omits it from the request)."
+    )]
+    pub max_tokens: Option<usize>,
+    #[arg(
+        long,
+        value_name = "USD",
+        help = "Cost per 1M prompt tokens for this run, overriding the model's prompt_cost."
+    )]
+    pub prompt_cost: Option<f64>,
+    #[arg(
+        long,
+        value_name = "USD",
+        help = "Cost per 1M completion tokens for this run, overriding the model's completion_cost."
+    )]
+    pub completion_cost: Option<f64>,
+    #[arg(
+        long,
+        help = "Asks for confirmation after printing the cost estimate, before sending the request. Fails when stdin is not a terminal."
+    )]
+    pub confirm: bool,
+    #[arg(
+        long = "rule",
+        value_name = "RULE",
+        help = "Additional rule for this run only, appended after configured rules. Can be repeated."
+    )]
+    pub rules: Vec<String>,
+    #[arg(
+        long,
+        value_name = "TEMPERATURES",
+        value_delimiter = ',',
+        help = "Sends the request concurrently at each temperature (e.g. 0.0,0.5,1.0) and prints the results without writing files."
+    )]
+    pub sweep_temperature: Vec<f64>,
+    #[arg(
+        long,
+        conflicts_with = "sweep_temperature",
+        help = "Sampling temperature for this run, overriding the model's temperature and the code_temperature default."
+    )]
+    pub temperature: Option<f64>,
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["sweep_temperature", "per_file", "chunk_tokens", "stdin_files"],
+        help = "Sends the request N times without writing files and prints latency percentiles and tokens/sec."
+    )]
+    pub bench: Option<usize>,
+    #[arg(
+        long,
+        help = "Omits the HTTP-Referer and X-Title headers that identify llmpal to the API."
+    )]
+    pub no_identify: bool,
+    #[arg(
+        long,
+        help = "Never shows the spinner; a single status line is printed instead. Also set by the CI environment variable."
+    )]
+    pub no_spinner: bool,
+    #[arg(long, help = "Streams the response from the API.")]
+    pub stream: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        requires = "stream",
+        help = "Aborts a stream when no data arrives for this many seconds, keeping the partial response."
+    )]
+    pub timeout_stream_idle: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Format the model is asked to answer in; the response is parsed the same way. Overrides the model's output_format (default: tags)."
+    )]
+    pub response_format: Option<ResponseFormat>,
+    #[arg(
+        long,
+        help = "Writes files even when they shrink below the configured fraction of their original size."
+    )]
+    pub force_shrink: bool,
+    #[arg(
+        long,
+        value_name = "SEQUENCE",
+        help = "Stop sequence for generation, replacing the model's configured `stop`. Can be repeated."
+    )]
+    pub stop: Vec<String>,
+    #[arg(
+        long,
+        help = "Prepends a directory tree of the input files (paths only) to the prompt."
+    )]
+    pub with_tree: bool,
+    #[arg(
+        long,
+        help = "Appends the uncommitted git diff of each input file after its content, so the model sees recent changes. Untracked files and non-git directories are skipped."
+    )]
+    pub with_file_diff: bool,
+    #[arg(
+        long,
+        help = "Omits the `provider` object (routing and data_collection settings) from the request."
+    )]
+    pub no_provider_block: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        conflicts_with = "no_provider_block",
+        help = "Sends provider.data_collection with this value for any API URL. By default 'deny' is sent to OpenRouter only."
+    )]
+    pub data_collection: Option<DataCollection>,
+    #[arg(
+        long,
+        help = "Sends the system prompt as part of the user message, for endpoints that reject the system role."
+    )]
+    pub no_system: bool,
+    #[arg(
+        long,
+        help = "Gzip-compresses request bodies over 64KB (Content-Encoding: gzip), resending uncompressed if that fails."
+    )]
+    pub compress: bool,
+    #[arg(
+        long,
+        conflicts_with = "no_provider_block",
+        help = "Checks the configured provider names against the API's provider list (cached for 24 hours) before sending."
+    )]
+    pub check_provider: bool,
+    #[arg(
+        long,
+        help = "Looks up the model's context length from the API's /models endpoint (cached) instead of the configured context_window."
+    )]
+    pub context_window_auto: bool,
+    #[arg(
+        long,
+        value_name = "SCHEMA_FILE",
+        help = "Constrains the response to the JSON schema in this file and writes the validated JSON to the output."
+    )]
+    pub schema: Option<String>,
+    #[arg(
+        long,
+        help = "Prefixes each line of the input files with its line number in the prompt; numbers echoed back in written files are stripped."
+    )]
+    pub line_numbers: bool,
+    #[arg(
+        long,
+        help = "Converts CRLF line endings to LF and strips trailing whitespace from each line of written files."
+    )]
+    pub strip_trailing_ws: bool,
+    #[arg(
+        long,
+        help = "Keeps a code fence the model wrapped around a whole file instead of removing it."
+    )]
+    pub no_strip_fences: bool,
+    #[arg(
+        long,
+        value_name = "ENCODING",
+        help = "Writes files in this encoding (e.g. windows-1252). By default non-UTF-8 files keep their detected encoding."
+    )]
+    pub output_encoding: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["per_file", "chunk_tokens", "schema"],
+        help = "Never writes files: prints a diff of any proposed change and exits non-zero if the model returns any file (for CI checks)."
+    )]
+    pub no_write: bool,
+    #[arg(
+        long,
+        conflicts_with = "no_write",
+        help = "Prints only the explanation and the files the model would change, without applying anything."
+    )]
+    pub summary_only: bool,
+    #[arg(
+        long,
+        value_name = "PATCH",
+        num_args = 0..=1,
+        default_missing_value = STDOUT_OUTPUT,
+        conflicts_with_all = ["no_write", "summary_only", "per_file", "chunk_tokens", "schema", "stdin_files", "output_stdout"],
+        help = "Writes all proposed changes as one patch for 'git apply' to PATCH ('-' or no value for stdout) instead of editing files."
+    )]
+    pub diff_only_output: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["per_file", "schema", "no_write"],
+        help = "Also writes the model's explanation to this file. The model itself cannot write to it."
+    )]
+    pub explanation_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["per_file", "chunk_tokens", "sweep_temperature", "bench"],
+        help = "Appends the system prompt, user prompt and raw response of each successful run to PATH as a chat fine-tuning record (JSONL)."
+    )]
+    pub export_jsonl: Option<String>,
+    #[arg(
+        long,
+        requires = "export_jsonl",
+        help = "Only exports runs whose changes were written, e.g. not with --no-write or when --confirm was declined."
+    )]
+    pub export_applied_only: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Saves the raw response text to this file on every run (the full response JSON with --trace)."
+    )]
+    pub dump_response: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["stdin_files", "output_stdout"],
+        help = "Also prints the explanation and the token and cost summary to stdout, the summary as a markdown table, for captured transcripts."
+    )]
+    pub show_cost_in_output: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["sweep_temperature", "schema", "stdin_files", "summary_only", "output_stdout", "stream", "retries", "retry_deadline", "compress"],
+        help = "Sends one request per input file, each with only that file in the prompt, and applies the results."
+    )]
+    pub per_file: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        requires = "per_file",
+        help = "Maximum number of concurrent requests in --per-file mode."
+    )]
+    pub concurrency: usize,
+    #[arg(
+        long,
+        requires = "per_file",
+        help = "In --per-file mode, keeps applying the other files' results after one fails, instead of stopping at the first failure."
+    )]
+    pub continue_on_error: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["per_file", "sweep_temperature", "schema", "stdin_files", "no_write", "summary_only", "output_stdout", "stream", "retries", "retry_deadline"],
+        help = "Splits the input files into groups of at most N estimated tokens and sends one request per group, applying all results."
+    )]
+    pub chunk_tokens: Option<usize>,
+    #[arg(
+        long,
+        conflicts_with_all = ["per_file", "chunk_tokens", "sweep_temperature", "bench", "stdin_files"],
+        help = "Sends input files unchanged since the last --skip-unchanged run in this directory as a short reference instead of their content; they are not writable."
+    )]
+    pub skip_unchanged: bool,
+    #[arg(
+        long,
+        help = "Prints text the model wrote outside the expected format to stderr under an ::EXTRA:: header."
+    )]
+    pub show_extra: bool,
+    #[arg(
+        long,
+        help = "Removes the indentation common to all explanation lines and their trailing whitespace before printing the explanation."
+    )]
+    pub dedent_explanation: bool,
+    #[arg(
+        long,
+        help = "Fails immediately, without writing a dump, when the model tries to write a file that is not allowed."
+    )]
+    pub strict: bool,
+    #[arg(
+        long,
+        help = "All-or-nothing writes in --per-file mode: a response that also contains another file is rejected and its own file is not written."
+    )]
+    pub fail_on_extra_files: bool,
+    #[arg(
+        long,
+        value_name = "EFFORT",
+        help = "Reasoning effort (minimal, low, medium, high) or a reasoning token budget, overriding the model's reasoning_effort."
+    )]
+    pub reasoning: Option<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        conflicts_with_all = ["model", "files", "files0_from", "output", "instruction", "instruction_file"],
+        help = "Re-runs the Nth most recent recorded run (default 1) with the same model, files and instruction."
+    )]
+    pub redo: Option<usize>,
+}
+
+impl Cli {
+    pub fn output_to_stdout(&self) -> bool {
+        self.output_stdout || self.output.as_deref() == Some(STDOUT_OUTPUT)
+    }
+
+    pub fn output_to_clipboard(&self) -> bool {
+        self.output.as_deref() == Some(CLIPBOARD_OUTPUT)
+    }
+
+    /// The output file path on disk; `-o -` and `-o clipboard:` name no file.
+    pub fn output_file(&self) -> Option<&String> {
+        self.output
+            .as_ref()
+            .filter(|o| *o != STDOUT_OUTPUT && *o != CLIPBOARD_OUTPUT)
+    }
+}
+
+/// Where `--init` writes the example config.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum InitTarget {
+    /// `./.llmpal.json`
+    Local,
+    /// `~/.llmpal.json`
+    Home,
+}
+
+/// The `provider.data_collection` policy sent with `--data-collection`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DataCollection {
+    Deny,
+    Allow,
 }
 
-#[derive(Deserialize, Clone)]
+impl DataCollection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DataCollection::Deny => "deny",
+            DataCollection::Allow => "allow",
+        }
+    }
+}
+
+/// What `--verify-utf8-input` does with input files that are not readable UTF-8.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum InvalidInputAction {
+    Abort,
+    Skip,
+}
+
+/// Written by `--init`. JSON has no comments, so `_comment` fields, which the
+/// config loader ignores, carry the explanations.
+const EXAMPLE_CONFIG: &str = r#"{
+  "_comment": "llmpal config. A project .llmpal.json overrides ~/.llmpal.json; see the README for all fields.",
+  "rules": [],
+  "models": [
+    {
+      "_comment": "Select with -m kimi. api_key accepts $ENV_VAR or file:/path/to/key.",
+      "code": "kimi",
+      "model": "moonshotai/kimi-k2",
+      "api_url": "https://openrouter.ai/api/v1/chat/completions",
+      "api_key": "$OPENROUTER_API_KEY",
+      "prompt_cost": 0.6,
+      "completion_cost": 2.5,
+      "max_tokens": 16384
+    }
+  ]
+}
+"#;
+
+#[derive(Deserialize, Clone, Default)]
 pub struct ModelConfig {
     pub code: String,
     pub model: String,
-    pub prompt_cost: f64,
-    pub completion_cost: f64,
+    pub request_model: Option<String>,
+    pub prompt_cost: Option<f64>,
+    pub completion_cost: Option<f64>,
+    pub cached_prompt_cost: Option<f64>,
     pub api_url: Option<String>,
     pub api_key: Option<String>,
     pub max_tokens: Option<usize>,
+    pub max_completion_cap: Option<usize>,
+    pub context_window: Option<usize>,
+    pub temperature: Option<f64>,
     pub provider: Option<String>,
+    pub ignore_providers: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub stop: Option<Vec<String>>,
+    pub http_referer: Option<String>,
+    pub x_title: Option<String>,
+    pub reasoning_effort: Option<String>,
+    pub no_system: Option<bool>,
+    pub extra_body: Option<serde_json::Map<String, serde_json::Value>>,
+    pub extra_body_override: Option<bool>,
+    pub no_identify: Option<bool>,
+    pub output_format: Option<ResponseFormat>,
+    pub api_format: Option<ApiFormat>,
 }
 
-#[derive(Deserialize)]
+/// The request/response schema of a model's API, selecting its `provider::Provider`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ApiFormat {
+    /// OpenAI chat completions, as used by OpenRouter
+    #[default]
+    #[serde(rename = "openrouter", alias = "openai")]
+    OpenRouter,
+}
+
+/// A user/assistant exchange sent before the real prompt as a few-shot example.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Example {
+    pub user: String,
+    pub assistant: String,
+}
+
+#[derive(Deserialize, Default)]
 pub struct Config {
     pub models: Option<Vec<ModelConfig>>,
     pub rules: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub rules_file: Option<Vec<String>>,
     pub diagnostic: Option<bool>,
+    pub max_cost: Option<f64>,
+    pub min_completion_tokens: Option<u64>,
+    /// `Some(None)` when set to `null`, which sends no code temperature.
+    #[serde(default, deserialize_with = "nullable")]
+    pub code_temperature: Option<Option<f64>>,
+    pub auto_pricing: Option<bool>,
+    pub default_model: Option<String>,
+    pub shrink_threshold: Option<f64>,
+    pub http_referer: Option<String>,
+    pub x_title: Option<String>,
+    pub no_identify: Option<bool>,
+    pub split_system_prompt: Option<bool>,
+    pub lock_timeout: Option<u64>,
+    pub max_input_files: Option<usize>,
+    pub extends: Option<String>,
+    pub examples: Option<Vec<Example>>,
+    pub profiles: Option<HashMap<String, Config>>,
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<StringOrList>::deserialize(deserializer)?.map(|value| match value {
+            StringOrList::One(path) => vec![path],
+            StringOrList::Many(paths) => paths,
+        }),
+    )
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field (`None`).
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Parses a config file, telling apart invalid JSON, JSON that is not a
+/// config object, and an object with malformed fields.
+fn parse_config(content: &str) -> Result<Config, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(content).map_err(|e| format!("not valid JSON: {}", e))?;
+    let found = match &value {
+        serde_json::Value::Object(_) => {
+            return serde_json::from_value(value).map_err(|e| format!("malformed config: {}", e));
+        }
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Null => "null",
+    };
+    Err(format!(
+        "expected an object with 'models'/'rules', found {}",
+        found
+    ))
 }
 
 fn config_from_path<P: AsRef<std::path::Path>>(path: P) -> Config {
-    fs::read_to_string(path)
+    let mut config: Config = fs::read_to_string(&path)
         .ok()
+        .and_then(|content| {
+            parse_config(&content)
+                .map_err(|e| warn!("Ignoring config '{}': {}", path.as_ref().display(), e))
+                .ok()
+        })
+        .unwrap_or_default();
+
+    let config_dir = path.as_ref().parent().unwrap_or(Path::new(""));
+    if let Some(rules_files) = config.rules_file.as_mut() {
+        for rules_file in rules_files.iter_mut() {
+            let expanded = expand_env_vars(rules_file);
+            *rules_file = config_dir.join(expanded).to_string_lossy().into_owned();
+        }
+    }
+
+    config
+}
+
+fn merge_lists<T>(home: Option<Vec<T>>, local: Option<Vec<T>>) -> Option<Vec<T>> {
+    match (home, local) {
+        (Some(mut home), Some(local)) => {
+            home.extend(local);
+            Some(home)
+        }
+        (home, None) => home,
+        (None, local) => local,
+    }
+}
+
+fn merge_configs(home_config: Config, local_config: Config) -> Config {
+    let diagnostic = local_config.diagnostic.or(home_config.diagnostic);
+    let max_cost = local_config.max_cost.or(home_config.max_cost);
+    let min_completion_tokens = local_config
+        .min_completion_tokens
+        .or(home_config.min_completion_tokens);
+    let code_temperature = local_config
+        .code_temperature
+        .or(home_config.code_temperature);
+    let auto_pricing = local_config.auto_pricing.or(home_config.auto_pricing);
+    let default_model = local_config.default_model.or(home_config.default_model);
+    let http_referer = local_config.http_referer.or(home_config.http_referer);
+    let x_title = local_config.x_title.or(home_config.x_title);
+    let no_identify = local_config.no_identify.or(home_config.no_identify);
+    let split_system_prompt = local_config
+        .split_system_prompt
+        .or(home_config.split_system_prompt);
+    let lock_timeout = local_config.lock_timeout.or(home_config.lock_timeout);
+    let max_input_files = local_config.max_input_files.or(home_config.max_input_files);
+    let extends = local_config.extends.or(home_config.extends);
+    let examples = local_config.examples.or(home_config.examples);
+    let profiles = local_config.profiles.or(home_config.profiles);
+    let shrink_threshold = local_config
+        .shrink_threshold
+        .or(home_config.shrink_threshold);
+
+    let rules = merge_lists(home_config.rules, local_config.rules);
+    let rules_file = merge_lists(home_config.rules_file, local_config.rules_file);
+
+    let models = match (home_config.models, local_config.models) {
+        (Some(home_models), Some(local_models)) => {
+            let mut combined_models = local_models.clone();
+            let mut seen_codes = std::collections::HashSet::new();
+
+            for model in &local_models {
+                seen_codes.insert(&model.code);
+            }
+
+            for model in home_models {
+                if !seen_codes.contains(&model.code) {
+                    combined_models.push(model);
+                }
+            }
+
+            Some(combined_models)
+        }
+        (Some(home_models), None) => Some(home_models),
+        (None, Some(local_models)) => Some(local_models),
+        (None, None) => None,
+    };
+
+    Config {
+        models,
+        rules,
+        rules_file,
+        diagnostic,
+        max_cost,
+        min_completion_tokens,
+        code_temperature,
+        auto_pricing,
+        default_model,
+        shrink_threshold,
+        http_referer,
+        x_title,
+        no_identify,
+        split_system_prompt,
+        lock_timeout,
+        max_input_files,
+        extends,
+        examples,
+        profiles,
+    }
+}
+
+impl Config {
+    /// The config file read for `target`; `None` for home when no home
+    /// directory can be found.
+    pub fn default_config_path(target: InitTarget) -> Option<PathBuf> {
+        match target {
+            InitTarget::Local => Some(PathBuf::from(CONFIG_FILE)),
+            InitTarget::Home => utils::home_dir().map(|home| home.join(CONFIG_FILE)),
+        }
+    }
+}
+
+/// Writes the example config to `path`, refusing to replace an existing file.
+pub fn write_example_config(path: &Path) -> Result<(), String> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, EXAMPLE_CONFIG.as_bytes()))
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                format!("{} already exists; not overwriting it", path.display())
+            }
+            _ => format!("Failed to write {}: {}", path.display(), e),
+        })
+}
+
+pub async fn get_config() -> Config {
+    let home_path = Config::default_config_path(InitTarget::Home);
+    if home_path.is_none() {
+        debug!("No home directory found, skipping home config");
+    }
+    let config = load_merged_config(home_path.as_deref(), Path::new(CONFIG_FILE));
+
+    let Some(url) = config.extends.clone() else {
+        return config;
+    };
+    let fetched = fetch_shared_config(&url).await;
+    let cache_path = utils::llmpal_dir().map(|dir| dir.join(SHARED_CONFIG_CACHE_FILE));
+    match shared_config(&url, fetched, cache_path.as_deref()) {
+        Some(shared) => merge_configs(shared, config),
+        None => config,
+    }
+}
+
+/// Merges the named profile over the base config, the profile taking the
+/// place of a local config. The name comes from `--profile` or `LLMPAL_PROFILE`.
+pub fn apply_profile(args: &Cli, config: Config) -> Result<Config, String> {
+    let name = args.profile.clone().or_else(|| {
+        std::env::var(PROFILE_ENV_VAR)
+            .ok()
+            .filter(|name| !name.is_empty())
+    });
+    match name {
+        Some(name) => select_profile(config, &name),
+        None => Ok(config),
+    }
+}
+
+fn select_profile(mut config: Config, name: &str) -> Result<Config, String> {
+    let mut profiles = config.profiles.take().unwrap_or_default();
+    let Some(profile) = profiles.remove(name) else {
+        let mut known: Vec<&String> = profiles.keys().collect();
+        known.sort();
+        return Err(format!(
+            "Unknown profile '{}' (configured: {})",
+            name,
+            if known.is_empty() {
+                "none".to_string()
+            } else {
+                known
+                    .iter()
+                    .map(|k| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        ));
+    };
+    Ok(merge_configs(config, profile))
+}
+
+async fn fetch_shared_config(url: &str) -> Result<String, String> {
+    if !url.starts_with("https://") {
+        return Err("only https:// URLs are supported".to_string());
+    }
+    let client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(SHARED_CONFIG_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client.get(url).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// Removes the settings of a shared config that could send local secrets or
+/// files elsewhere: a model's `api_url` and `api_key` (whose `$VAR`, `file:`
+/// and `keyring:` references are resolved locally) and `rules_file`. Those
+/// belong in the home or local config.
+fn without_local_access(mut config: Config, url: &str) -> Config {
+    let mut stripped = Vec::new();
+    if config.rules_file.take().is_some() {
+        stripped.push("rules_file".to_string());
+    }
+    let profiles = config.profiles.iter_mut().flat_map(|p| p.values_mut());
+    let models = config
+        .models
+        .iter_mut()
+        .chain(profiles.filter_map(|profile| profile.models.as_mut()))
+        .flatten();
+    for model in models {
+        if model.api_url.take().is_some() {
+            stripped.push(format!("api_url of model '{}'", model.code));
+        }
+        if model.api_key.take().is_some() {
+            stripped.push(format!("api_key of model '{}'", model.code));
+        }
+    }
+    if let Some(profiles) = config.profiles.as_mut() {
+        for (name, profile) in profiles.iter_mut() {
+            if profile.rules_file.take().is_some() {
+                stripped.push(format!("rules_file of profile '{}'", name));
+            }
+        }
+    }
+    if !stripped.is_empty() {
+        warn!(
+            "Ignoring {} from shared config '{}'; set them in the home or local config",
+            stripped.join(", "),
+            url
+        );
+    }
+    config
+}
+
+/// Parses the shared config fetched from `url` and refreshes its cached copy.
+/// When fetching or parsing fails, the cached copy is used instead; without
+/// one, `None` leaves the local config on its own. Settings with local access
+/// are removed either way, see `without_local_access`.
+fn shared_config(
+    url: &str,
+    fetched: Result<String, String>,
+    cache_path: Option<&Path>,
+) -> Option<Config> {
+    load_shared_config(url, fetched, cache_path).map(|config| without_local_access(config, url))
+}
+
+fn load_shared_config(
+    url: &str,
+    fetched: Result<String, String>,
+    cache_path: Option<&Path>,
+) -> Option<Config> {
+    let mut cache: HashMap<String, String> = cache_path
+        .and_then(|path| fs::read_to_string(path).ok())
         .and_then(|content| serde_json::from_str(&content).ok())
-        .unwrap_or(Config {
-            models: None,
-            rules: None,
-            diagnostic: None,
+        .unwrap_or_default();
+
+    let parsed = fetched.and_then(|content| {
+        serde_json::from_str::<Config>(&content)
+            .map(|config| (config, content))
+            .map_err(|e| format!("invalid config: {}", e))
+    });
+    match parsed {
+        Ok((config, content)) => {
+            cache.insert(url.to_string(), content);
+            if let Some(path) = cache_path {
+                let saved = path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| serde_json::to_string_pretty(&cache).map_err(|e| e.to_string()))
+                    .and_then(|content| fs::write(path, content).map_err(|e| e.to_string()));
+                if let Err(e) = saved {
+                    warn!("Failed to cache shared config '{}': {}", url, e);
+                }
+            }
+            Some(config)
+        }
+        Err(e) => match cache.get(url).and_then(|c| serde_json::from_str(c).ok()) {
+            Some(config) => {
+                warn!(
+                    "Cannot load shared config '{}' ({}), using the cached copy",
+                    url, e
+                );
+                Some(config)
+            }
+            None => {
+                warn!(
+                    "Cannot load shared config '{}' ({}), using the local config only",
+                    url, e
+                );
+                None
+            }
+        },
+    }
+}
+
+/// Deep-merges the home and local config files; either may be missing.
+fn load_merged_config(home_path: Option<&Path>, local_path: &Path) -> Config {
+    let home_config = home_path.map(config_from_path).unwrap_or_default();
+    let local_config = config_from_path(local_path);
+
+    merge_configs(home_config, local_config)
+}
+
+fn get_selected_model_code(args: &Cli, config: &Config) -> String {
+    let env_model = std::env::var(MODEL_ENV_VAR)
+        .ok()
+        .filter(|model| !model.is_empty());
+    select_model_code(args.model.clone(), env_model, config)
+}
+
+/// Precedence: `-m` flag, `LLMPAL_MODEL`, `default_model`, first configured model, DEFAULT_MODEL.
+fn select_model_code(flag: Option<String>, env_model: Option<String>, config: &Config) -> String {
+    flag.or(env_model)
+        .or_else(|| config.default_model.clone())
+        .or_else(|| {
+            config
+                .models
+                .as_ref()
+                .and_then(|models| models.first().map(|m| m.code.clone()))
         })
+        .unwrap_or(DEFAULT_MODEL.to_string())
+}
+
+fn expand_env_vars(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        match std::env::var(name) {
+            Ok(val) if !name.is_empty() => result.push_str(&val),
+            _ => {
+                result.push('$');
+                result.push_str(name);
+            }
+        }
+        rest = &after[name_len..];
+    }
+    result.push_str(rest);
+    result
 }
 
-fn merge_configs(home_config: Config, local_config: Config) -> Config {
-    let diagnostic = local_config.diagnostic.or(home_config.diagnostic);
+pub fn get_rules(args: &Cli, config: &Config) -> Vec<String> {
+    let mut rules = config.rules.clone().unwrap_or_default();
 
-    let rules = match (home_config.rules, local_config.rules) {
-        (Some(mut home_rules), Some(local_rules)) => {
-            home_rules.extend(local_rules);
-            Some(home_rules)
+    for rules_file in config.rules_file.iter().flatten() {
+        match fs::read_to_string(rules_file) {
+            Ok(content) => rules.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            ),
+            Err(e) => warn!("Cannot read rules file '{}': {}", rules_file, e),
         }
-        (Some(home_rules), None) => Some(home_rules),
-        (None, Some(local_rules)) => Some(local_rules),
-        (None, None) => None,
-    };
+    }
 
-    let models = match (home_config.models, local_config.models) {
-        (Some(home_models), Some(local_models)) => {
-            let mut combined_models = local_models.clone();
-            let mut seen_codes = std::collections::HashSet::new();
+    rules.extend(args.rules.iter().cloned());
+    rules
+}
 
-            for model in &local_models {
-                seen_codes.insert(&model.code);
-            }
+/// Resolves an `api_key` reference: `$ENV_VAR`, `file:/path/to/key` or
+/// `keyring:service/account`. Anything else is used literally, as is a
+/// reference that cannot be resolved (with a warning).
+fn resolve_env_token(token: &str) -> String {
+    let resolved = if let Some(env_var) = token.strip_prefix('$') {
+        std::env::var(env_var).ok()
+    } else if let Some(path) = token.strip_prefix("file:") {
+        fs::read_to_string(expand_env_vars(path))
+            .map(|key| key.trim().to_string())
+            .map_err(|e| warn!("Cannot read API key file '{}': {}", path, e))
+            .ok()
+    } else if let Some(entry) = token.strip_prefix("keyring:") {
+        keyring_password(entry)
+            .map_err(|e| warn!("Cannot read API key from keyring '{}': {}", entry, e))
+            .ok()
+    } else {
+        None
+    };
+    resolved.unwrap_or_else(|| token.to_string())
+}
 
-            for model in home_models {
-                if !seen_codes.contains(&model.code) {
-                    combined_models.push(model);
-                }
-            }
+fn keyring_password(entry: &str) -> Result<String, String> {
+    let (service, account) = entry
+        .split_once('/')
+        .ok_or_else(|| "expected keyring:service/account".to_string())?;
+    keyring::Entry::new(service, account)
+        .and_then(|entry| entry.get_password())
+        .map_err(|e| e.to_string())
+}
 
-            Some(combined_models)
-        }
-        (Some(home_models), None) => Some(home_models),
-        (None, Some(local_models)) => Some(local_models),
-        (None, None) => None,
-    };
+impl ModelConfig {
+    pub fn http_referer(&self) -> &str {
+        self.http_referer.as_deref().unwrap_or(DEFAULT_HTTP_REFERER)
+    }
 
-    Config {
-        models,
-        rules,
-        diagnostic,
+    pub fn x_title(&self) -> &str {
+        self.x_title.as_deref().unwrap_or(DEFAULT_X_TITLE)
     }
-}
 
-pub fn get_config() -> Config {
-    let home_config = if let Ok(home) = std::env::var("HOME") {
-        let home_path = std::path::PathBuf::from(home).join(".llmpal.json");
-        config_from_path(home_path)
-    } else {
-        Config {
-            models: None,
-            rules: None,
-            diagnostic: None,
+    /// Model id sent in the request body; `model` stays the label shown in logs.
+    pub fn request_model(&self) -> &str {
+        self.request_model.as_deref().unwrap_or(&self.model)
+    }
+
+    /// Stop sequences from `--stop`, falling back to the model's configured ones.
+    pub fn stop_sequences<'a>(&'a self, args: &'a Cli) -> &'a [String] {
+        if !args.stop.is_empty() {
+            &args.stop
+        } else {
+            self.stop.as_deref().unwrap_or_default()
         }
-    };
+    }
 
-    let local_config = config_from_path(".llmpal.json");
+    pub fn prompt_cost(&self) -> f64 {
+        self.prompt_cost.unwrap_or(DEFAULT_PROMPT_COST)
+    }
 
-    merge_configs(home_config, local_config)
-}
+    pub fn completion_cost(&self) -> f64 {
+        self.completion_cost.unwrap_or(DEFAULT_COMPLETION_COST)
+    }
 
-fn get_selected_model_code(args: &Cli, config: &Config) -> String {
-    args.model
-        .clone()
-        .or(config
-            .models
-            .as_ref()
-            .and_then(|models| models.first().map(|m| m.code.clone())))
-        .unwrap_or(DEFAULT_MODEL.to_string())
-}
+    /// Cost per 1M cached prompt tokens, the full prompt rate when not configured.
+    pub fn cached_prompt_cost(&self) -> f64 {
+        self.cached_prompt_cost
+            .unwrap_or_else(|| self.prompt_cost())
+    }
 
-fn resolve_env_token(token: &str) -> String {
-    if token.starts_with('$') {
-        let env_var = &token[1..];
-        std::env::var(env_var).unwrap_or_else(|_| token.to_string())
-    } else {
-        token.to_string()
+    pub fn max_tokens(&self) -> Option<usize> {
+        match self.max_tokens {
+            Some(0) => None,
+            Some(max_tokens) => Some(max_tokens),
+            None => Some(DEFAULT_MAX_TOKENS),
+        }
     }
 }
 
@@ -177,21 +1187,51 @@ pub fn get_model_config(args: &Cli, config: &Config) -> ModelConfig {
         model: model_config
             .map(|m| m.model.clone())
             .unwrap_or(DEFAULT_MODEL.to_string()),
-        prompt_cost: model_config
-            .map(|m| m.prompt_cost)
-            .unwrap_or(DEFAULT_PROMPT_COST),
-        completion_cost: model_config
-            .map(|m| m.completion_cost)
-            .unwrap_or(DEFAULT_COMPLETION_COST),
-        api_url: model_config
-            .as_ref()
-            .and_then(|m| m.api_url.clone())
-            .clone(),
+        request_model: model_config.and_then(|m| m.request_model.clone()),
+        prompt_cost: args
+            .prompt_cost
+            .or_else(|| model_config.and_then(|m| m.prompt_cost)),
+        completion_cost: args
+            .completion_cost
+            .or_else(|| model_config.and_then(|m| m.completion_cost)),
+        cached_prompt_cost: model_config.and_then(|m| m.cached_prompt_cost),
+        api_url: args
+            .api_url
+            .clone()
+            .or_else(|| model_config.and_then(|m| m.api_url.clone())),
         api_key: model_config
             .as_ref()
             .and_then(|m| m.api_key.as_ref().map(|token| resolve_env_token(token))),
-        max_tokens: model_config.as_ref().and_then(|m| m.max_tokens),
+        max_tokens: args
+            .max_tokens
+            .or_else(|| model_config.as_ref().and_then(|m| m.max_tokens)),
+        max_completion_cap: model_config.as_ref().and_then(|m| m.max_completion_cap),
+        context_window: model_config.as_ref().and_then(|m| m.context_window),
+        temperature: model_config.as_ref().and_then(|m| m.temperature),
         provider: model_config.as_ref().and_then(|m| m.provider.clone()),
+        ignore_providers: model_config
+            .as_ref()
+            .and_then(|m| m.ignore_providers.clone()),
+        stop: model_config.as_ref().and_then(|m| m.stop.clone()),
+        http_referer: model_config
+            .and_then(|m| m.http_referer.clone())
+            .or_else(|| config.http_referer.clone()),
+        x_title: model_config
+            .and_then(|m| m.x_title.clone())
+            .or_else(|| config.x_title.clone()),
+        reasoning_effort: model_config.and_then(|m| m.reasoning_effort.clone()),
+        no_system: model_config.and_then(|m| m.no_system),
+        extra_body: model_config.and_then(|m| m.extra_body.clone()),
+        extra_body_override: model_config.and_then(|m| m.extra_body_override),
+        no_identify: args
+            .no_identify
+            .then_some(true)
+            .or_else(|| model_config.and_then(|m| m.no_identify))
+            .or(config.no_identify),
+        output_format: args
+            .response_format
+            .or_else(|| model_config.and_then(|m| m.output_format)),
+        api_format: model_config.and_then(|m| m.api_format),
     }
 }
 
@@ -229,6 +1269,81 @@ mod tests {
             assert_eq!(cli.model, Some("test-model".to_string()));
         }
 
+        #[test]
+        fn test_cli_output_to_stdout() {
+            let cli = Cli::parse_from(["llmpal", "-o", "-", "Write a haiku"]);
+            assert!(cli.output_to_stdout());
+            assert_eq!(cli.output_file(), None);
+
+            let cli = Cli::parse_from(["llmpal", "--output-stdout", "-o", "out.txt", "x"]);
+            assert!(cli.output_to_stdout());
+            assert_eq!(cli.output_file(), Some(&"out.txt".to_string()));
+        }
+
+        #[test]
+        fn test_cli_output_to_clipboard() {
+            let cli = Cli::parse_from(["llmpal", "-o", "clipboard:", "Write a haiku"]);
+            assert!(cli.output_to_clipboard());
+            assert!(!cli.output_to_stdout());
+            assert_eq!(cli.output_file(), None);
+
+            assert!(Cli::try_parse_from(["llmpal", "--clipboard", "x"]).is_err());
+            assert!(Cli::try_parse_from(["llmpal", "--clipboard", "--template", "t"]).is_ok());
+        }
+
+        #[test]
+        fn test_cli_summary_only_conflicts_with_no_write() {
+            assert!(Cli::try_parse_from(["llmpal", "--summary-only", "x"]).is_ok());
+            assert!(Cli::try_parse_from(["llmpal", "--summary-only", "--no-write", "x"]).is_err());
+        }
+
+        #[test]
+        fn test_cli_per_file_conflicts_with_unsupported_flags() {
+            assert!(Cli::try_parse_from(["llmpal", "--per-file", "x"]).is_ok());
+            for flag in [
+                &["--summary-only"][..],
+                &["--output-stdout"],
+                &["--stream"],
+                &["--retries", "2"],
+                &["--retry-deadline", "60"],
+                &["--compress"],
+            ] {
+                let mut argv = vec!["llmpal", "--per-file"];
+                argv.extend_from_slice(flag);
+                argv.push("x");
+                assert!(Cli::try_parse_from(argv).is_err(), "{:?}", flag);
+            }
+        }
+
+        #[test]
+        fn test_cli_chunk_tokens_conflicts_with_unsupported_flags() {
+            for flag in [
+                &["--output-stdout"][..],
+                &["--stream"],
+                &["--retries", "2"],
+                &["--retry-deadline", "60"],
+            ] {
+                let mut argv = vec!["llmpal", "--chunk-tokens", "1000"];
+                argv.extend_from_slice(flag);
+                argv.push("x");
+                assert!(Cli::try_parse_from(argv).is_err(), "{:?}", flag);
+            }
+        }
+
+        #[test]
+        fn test_cli_no_write_conflicts_with_unchecked_write_paths() {
+            for flag in [
+                &["--per-file"][..],
+                &["--chunk-tokens", "1000"],
+                &["--schema", "s.json"],
+            ] {
+                let mut argv = vec!["llmpal", "--no-write"];
+                argv.extend_from_slice(flag);
+                argv.push("x");
+                assert!(Cli::try_parse_from(argv).is_err(), "{:?}", flag);
+            }
+        }
+
         #[test]
         fn test_cli_parsing_with_instruction_file() {
             let cli = Cli::parse_from(["llmpal", "-f", "test.txt", "-i", "instructions.txt"]);
@@ -236,6 +1351,12 @@ mod tests {
             assert_eq!(cli.instruction, None);
             assert_eq!(cli.instruction_file, Some("instructions.txt".to_string()));
         }
+
+        #[test]
+        fn test_cli_parsing_repeated_rules() {
+            let cli = Cli::parse_from(["llmpal", "--rule", "a", "--rule", "b", "instruction"]);
+            assert_eq!(cli.rules, vec!["a", "b"]);
+        }
     }
 
     #[cfg(test)]
@@ -245,16 +1366,158 @@ mod tests {
         #[test]
         fn test_default_model_config() {
             let args = Cli::parse_from(["llmpal", "instruction"]);
-            let config = Config {
-                models: None,
-                rules: None,
-                diagnostic: None,
-            };
+            let config = Config::default();
             let model_config = get_model_config(&args, &config);
             assert_eq!(model_config.model, DEFAULT_MODEL);
-            assert_eq!(model_config.prompt_cost, DEFAULT_PROMPT_COST);
-            assert_eq!(model_config.completion_cost, DEFAULT_COMPLETION_COST);
+            assert_eq!(model_config.prompt_cost(), DEFAULT_PROMPT_COST);
+            assert_eq!(model_config.completion_cost(), DEFAULT_COMPLETION_COST);
             assert_eq!(model_config.code, DEFAULT_MODEL);
+            assert_eq!(model_config.max_tokens(), Some(DEFAULT_MAX_TOKENS));
+        }
+
+        #[test]
+        fn test_select_model_code_precedence() {
+            let config = Config {
+                models: Some(vec![
+                    ModelConfig {
+                        code: "first".to_string(),
+                        ..Default::default()
+                    },
+                    ModelConfig {
+                        code: "second".to_string(),
+                        ..Default::default()
+                    },
+                ]),
+                default_model: Some("second".to_string()),
+                ..Default::default()
+            };
+            let flag = Some("flag".to_string());
+            let env = Some("env".to_string());
+
+            assert_eq!(select_model_code(flag, env.clone(), &config), "flag");
+            assert_eq!(select_model_code(None, env, &config), "env");
+            assert_eq!(select_model_code(None, None, &config), "second");
+
+            let config = Config {
+                default_model: None,
+                ..config
+            };
+            assert_eq!(select_model_code(None, None, &config), "first");
+            assert_eq!(
+                select_model_code(None, None, &Config::default()),
+                DEFAULT_MODEL
+            );
+        }
+
+        #[test]
+        fn test_request_model_falls_back_to_model() {
+            let model_config = ModelConfig {
+                model: "Kimi K2".to_string(),
+                ..Default::default()
+            };
+            assert_eq!(model_config.request_model(), "Kimi K2");
+
+            let model_config = ModelConfig {
+                request_model: Some("gateway/kimi-k2".to_string()),
+                ..model_config
+            };
+            assert_eq!(model_config.request_model(), "gateway/kimi-k2");
+            assert_eq!(model_config.model, "Kimi K2");
+        }
+
+        #[test]
+        fn test_identifying_headers_resolution() {
+            let config = Config {
+                models: Some(vec![
+                    ModelConfig {
+                        code: "own".to_string(),
+                        x_title: Some("model-title".to_string()),
+                        ..Default::default()
+                    },
+                    ModelConfig {
+                        code: "plain".to_string(),
+                        ..Default::default()
+                    },
+                ]),
+                http_referer: Some("https://example.org".to_string()),
+                x_title: Some("org-title".to_string()),
+                ..Default::default()
+            };
+
+            let args = Cli::parse_from(["llmpal", "-m", "own", "x"]);
+            let model_config = get_model_config(&args, &config);
+            assert_eq!(model_config.x_title(), "model-title");
+            assert_eq!(model_config.http_referer(), "https://example.org");
+
+            let args = Cli::parse_from(["llmpal", "-m", "plain", "x"]);
+            let model_config = get_model_config(&args, &config);
+            assert_eq!(model_config.x_title(), "org-title");
+
+            let model_config = get_model_config(&args, &Config::default());
+            assert_eq!(model_config.http_referer(), DEFAULT_HTTP_REFERER);
+            assert_eq!(model_config.x_title(), DEFAULT_X_TITLE);
+            assert_eq!(model_config.no_identify, None);
+
+            let private = Config {
+                no_identify: Some(true),
+                ..Default::default()
+            };
+            assert_eq!(get_model_config(&args, &private).no_identify, Some(true));
+            let args = Cli::parse_from(["llmpal", "--no-identify", "x"]);
+            assert_eq!(
+                get_model_config(&args, &Config::default()).no_identify,
+                Some(true)
+            );
+        }
+
+        #[test]
+        fn test_max_tokens_flag_overrides_config() {
+            let config = Config {
+                models: Some(vec![ModelConfig {
+                    code: "m".to_string(),
+                    max_tokens: Some(4096),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            };
+
+            let args = Cli::parse_from(["llmpal", "x"]);
+            assert_eq!(get_model_config(&args, &config).max_tokens(), Some(4096));
+
+            let args = Cli::parse_from(["llmpal", "--max-tokens", "512", "x"]);
+            assert_eq!(get_model_config(&args, &config).max_tokens(), Some(512));
+
+            let args = Cli::parse_from(["llmpal", "--max-tokens", "0", "x"]);
+            assert_eq!(get_model_config(&args, &config).max_tokens(), None);
+        }
+
+        #[test]
+        fn test_output_format_per_model() {
+            let config: Config = serde_json::from_str(
+                r#"{"models": [{"code": "m", "model": "x", "output_format": "markers"}]}"#,
+            )
+            .unwrap();
+
+            let args = Cli::parse_from(["llmpal", "x"]);
+            assert_eq!(
+                get_model_config(&args, &config).output_format,
+                Some(ResponseFormat::Markers)
+            );
+
+            let args = Cli::parse_from(["llmpal", "--response-format", "json", "x"]);
+            assert_eq!(
+                get_model_config(&args, &config).output_format,
+                Some(ResponseFormat::Json)
+            );
+        }
+
+        #[test]
+        fn test_zero_max_tokens_means_provider_default() {
+            let model_config = ModelConfig {
+                max_tokens: Some(0),
+                ..Default::default()
+            };
+            assert_eq!(model_config.max_tokens(), None);
         }
 
         #[test]
@@ -263,27 +1526,54 @@ mod tests {
                 models: Some(vec![ModelConfig {
                     code: "kimi".to_string(),
                     model: "test-model".to_string(),
-                    prompt_cost: 1.1,
-                    completion_cost: 2.2,
+                    prompt_cost: Some(1.1),
+                    completion_cost: Some(2.2),
                     api_url: None,
                     api_key: Some("$TOKEN".to_string()),
                     max_tokens: Some(4096),
                     provider: Some("fireworks".to_string()),
+                    ..Default::default()
                 }]),
-                rules: None,
-                diagnostic: None,
+                ..Default::default()
             };
 
             let args = Cli::parse_from(["llmpal", "instruction", "--model", "kimi"]);
             let model_config = get_model_config(&args, &config);
 
             assert_eq!(model_config.model, "test-model");
-            assert_eq!(model_config.prompt_cost, 1.1);
-            assert_eq!(model_config.completion_cost, 2.2);
+            assert_eq!(model_config.prompt_cost(), 1.1);
+            assert_eq!(model_config.completion_cost(), 2.2);
             assert_eq!(model_config.max_tokens, Some(4096));
             assert_eq!(model_config.code, "kimi");
             assert_eq!(model_config.api_key.as_deref(), Some("$TOKEN"));
             assert_eq!(model_config.provider, Some("fireworks".to_string()));
+            assert_eq!(model_config.api_url, None);
+
+            let args = Cli::parse_from([
+                "llmpal",
+                "instruction",
+                "--model",
+                "kimi",
+                "--prompt-cost",
+                "0.25",
+            ]);
+            let model_config = get_model_config(&args, &config);
+            assert_eq!(model_config.prompt_cost(), 0.25);
+            assert_eq!(model_config.completion_cost(), 2.2);
+
+            let args = Cli::parse_from([
+                "llmpal",
+                "instruction",
+                "--model",
+                "kimi",
+                "--api-url",
+                "http://localhost:8080/v1/chat/completions",
+            ]);
+            let model_config = get_model_config(&args, &config);
+            assert_eq!(
+                model_config.api_url.as_deref(),
+                Some("http://localhost:8080/v1/chat/completions")
+            );
         }
 
         #[test]
@@ -292,39 +1582,48 @@ mod tests {
                 models: Some(vec![ModelConfig {
                     code: "other".to_string(),
                     model: "other-model".to_string(),
-                    prompt_cost: 0.5,
-                    completion_cost: 1.0,
-                    api_url: None,
-                    api_key: None,
-                    max_tokens: None,
-                    provider: None,
+                    prompt_cost: Some(0.5),
+                    completion_cost: Some(1.0),
+                    ..Default::default()
                 }]),
-                rules: None,
-                diagnostic: None,
+                ..Default::default()
             };
 
             let args = Cli::parse_from(["llmpal", "instruction"]);
             let model_config = get_model_config(&args, &config);
             assert_eq!(model_config.model, "other-model");
-            assert_eq!(model_config.prompt_cost, 0.5);
-            assert_eq!(model_config.completion_cost, 1.0);
+            assert_eq!(model_config.prompt_cost(), 0.5);
+            assert_eq!(model_config.completion_cost(), 1.0);
             assert_eq!(model_config.max_tokens, None);
             assert_eq!(model_config.provider, None);
         }
 
+        #[test]
+        fn test_model_without_pricing_uses_defaults() {
+            let config: Config = serde_json::from_str(
+                r#"{"models": [{"code": "free", "model": "vendor/free-model"}]}"#,
+            )
+            .unwrap();
+            let args = Cli::parse_from(["llmpal", "instruction"]);
+            let model_config = get_model_config(&args, &config);
+            assert_eq!(model_config.model, "vendor/free-model");
+            assert_eq!(model_config.prompt_cost, None);
+            assert_eq!(model_config.prompt_cost(), DEFAULT_PROMPT_COST);
+            assert_eq!(model_config.completion_cost(), DEFAULT_COMPLETION_COST);
+        }
+
         #[test]
         fn test_specified_model_not_in_config() {
             let config = Config {
                 models: Some(vec![]),
-                rules: None,
-                diagnostic: None,
+                ..Default::default()
             };
             let args = Cli::parse_from(["llmpal", "--model", "missing", "instruction"]);
             let model_config = get_model_config(&args, &config);
             assert_eq!(model_config.code, "missing");
             assert_eq!(model_config.model, DEFAULT_MODEL);
-            assert_eq!(model_config.prompt_cost, DEFAULT_PROMPT_COST);
-            assert_eq!(model_config.completion_cost, DEFAULT_COMPLETION_COST);
+            assert_eq!(model_config.prompt_cost(), DEFAULT_PROMPT_COST);
+            assert_eq!(model_config.completion_cost(), DEFAULT_COMPLETION_COST);
             assert_eq!(model_config.provider, None);
         }
     }
@@ -349,12 +1648,107 @@ mod tests {
             assert_eq!(resolve_env_token("$MISSING_VAR"), "$MISSING_VAR");
         }
 
+        #[test]
+        fn test_resolve_file_token() {
+            let dir = tempdir().unwrap();
+            let key_path = dir.path().join("key");
+            fs::write(&key_path, "sk-from-file\n").unwrap();
+            assert_eq!(
+                resolve_env_token(&format!("file:{}", key_path.display())),
+                "sk-from-file"
+            );
+
+            let missing = format!("file:{}", dir.path().join("missing").display());
+            assert_eq!(resolve_env_token(&missing), missing);
+        }
+
+        #[test]
+        fn test_resolve_keyring_token_malformed() {
+            assert_eq!(
+                keyring_password("no-account"),
+                Err("expected keyring:service/account".to_string())
+            );
+            assert_eq!(
+                resolve_env_token("keyring:no-account"),
+                "keyring:no-account"
+            );
+        }
+
         #[test]
         fn test_resolve_env_token_plain_string() {
             assert_eq!(resolve_env_token("plain_val"), "plain_val");
         }
     }
 
+    #[cfg(test)]
+    mod rules_files {
+        use super::*;
+
+        #[test]
+        fn test_expand_env_vars() {
+            unsafe {
+                env::set_var("LLMPAL_TEST_RULES_DIR", "/shared");
+            }
+            assert_eq!(
+                expand_env_vars("$LLMPAL_TEST_RULES_DIR/rules.txt"),
+                "/shared/rules.txt"
+            );
+            assert_eq!(
+                expand_env_vars("$LLMPAL_MISSING_VAR/x"),
+                "$LLMPAL_MISSING_VAR/x"
+            );
+            assert_eq!(expand_env_vars("plain/path"), "plain/path");
+            unsafe {
+                env::remove_var("LLMPAL_TEST_RULES_DIR");
+            }
+        }
+
+        #[test]
+        fn test_rules_file_merged_with_inline_rules() {
+            let dir = tempdir().unwrap();
+            fs::write(
+                dir.path().join("standards.txt"),
+                "# Coding standards\nNever use unwrap.\n\n  Prefer iterators.  \n",
+            )
+            .unwrap();
+            let config_path = dir.path().join(".llmpal.json");
+            fs::write(
+                &config_path,
+                r#"{"rules": ["inline rule"], "rules_file": "standards.txt"}"#,
+            )
+            .unwrap();
+
+            let config = config_from_path(&config_path);
+            let args = Cli::parse_from(["llmpal", "--rule", "Keep it short.", "instruction"]);
+            assert_eq!(
+                get_rules(&args, &config),
+                vec![
+                    "inline rule",
+                    "Never use unwrap.",
+                    "Prefer iterators.",
+                    "Keep it short."
+                ]
+            );
+        }
+
+        #[test]
+        fn test_rules_file_list() {
+            let dir = tempdir().unwrap();
+            fs::write(dir.path().join("a.txt"), "rule a").unwrap();
+            fs::write(dir.path().join("b.txt"), "rule b").unwrap();
+            let config_path = dir.path().join(".llmpal.json");
+            fs::write(
+                &config_path,
+                r#"{"rules_file": ["a.txt", "b.txt", "missing.txt"]}"#,
+            )
+            .unwrap();
+
+            let config = config_from_path(&config_path);
+            let args = Cli::parse_from(["llmpal", "instruction"]);
+            assert_eq!(get_rules(&args, &config), vec!["rule a", "rule b"]);
+        }
+    }
+
     #[cfg(test)]
     mod config_loading {
         use super::*;
@@ -371,6 +1765,19 @@ mod tests {
             assert!(config.diagnostic.is_none());
         }
 
+        #[test]
+        fn test_parse_config_errors() {
+            let err = |content| parse_config(content).err().unwrap();
+            assert_eq!(
+                err(r#"[{"code": "kimi"}]"#),
+                "expected an object with 'models'/'rules', found array"
+            );
+            assert!(err(r#""rules""#).ends_with("found string"));
+            assert!(err(r#"{"rules": "not a list"}"#).starts_with("malformed config"));
+            assert!(err("{ invalid").starts_with("not valid JSON"));
+            assert!(parse_config(r#"{"rules": ["r"]}"#).is_ok());
+        }
+
         #[test]
         fn test_config_from_missing_file() {
             let config = config_from_path("nonexistent.json");
@@ -378,6 +1785,22 @@ mod tests {
             assert!(config.rules.is_none());
             assert!(config.diagnostic.is_none());
         }
+
+        #[test]
+        fn test_write_example_config() {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join(CONFIG_FILE);
+            write_example_config(&path).unwrap();
+
+            let config = config_from_path(&path);
+            let models = config.models.unwrap();
+            assert_eq!(models.len(), 1);
+            assert_eq!(models[0].api_key.as_deref(), Some("$OPENROUTER_API_KEY"));
+            assert_eq!(config.rules, Some(vec![]));
+
+            let err = write_example_config(&path).unwrap_err();
+            assert!(err.contains("already exists"), "{}", err);
+        }
     }
 
     #[cfg(test)]
@@ -394,11 +1817,11 @@ mod tests {
             let config = config_from_path(&local_config_path);
 
             let vec = config.models.unwrap();
-            let model_config = vec.get(0).unwrap();
+            let model_config = vec.first().unwrap();
             assert_eq!(model_config.code, "local_code");
             assert_eq!(model_config.model, "local_model");
-            assert_eq!(model_config.prompt_cost, 1.5);
-            assert_eq!(model_config.completion_cost, 2.5);
+            assert_eq!(model_config.prompt_cost, Some(1.5));
+            assert_eq!(model_config.completion_cost, Some(2.5));
             assert_eq!(config.rules.as_ref().unwrap()[0], "rule1");
             assert!(config.diagnostic.unwrap_or(false));
         }
@@ -408,6 +1831,94 @@ mod tests {
     mod config_merging {
         use super::*;
 
+        #[test]
+        fn test_select_profile() {
+            let config: Config = serde_json::from_str(
+                r#"{
+                    "default_model": "dev",
+                    "max_cost": 0.1,
+                    "models": [{ "code": "dev", "model": "small" }],
+                    "profiles": {
+                        "prod": {
+                            "default_model": "prod",
+                            "models": [{ "code": "prod", "model": "large", "api_key": "$PROD_KEY" }]
+                        }
+                    }
+                }"#,
+            )
+            .unwrap();
+
+            let prod = select_profile(config, "prod").unwrap();
+            assert_eq!(prod.default_model.as_deref(), Some("prod"));
+            assert_eq!(prod.max_cost, Some(0.1));
+            let codes: Vec<&str> = prod
+                .models
+                .iter()
+                .flatten()
+                .map(|m| m.code.as_str())
+                .collect();
+            assert_eq!(codes, vec!["prod", "dev"]);
+
+            match select_profile(Config::default(), "staging") {
+                Err(e) => assert_eq!(e, "Unknown profile 'staging' (configured: none)"),
+                Ok(_) => panic!("expected an unknown profile error"),
+            }
+        }
+
+        #[test]
+        fn test_shared_config_falls_back_to_cache() {
+            let temp_dir = tempdir().unwrap();
+            let cache_path = temp_dir
+                .path()
+                .join("nested")
+                .join(SHARED_CONFIG_CACHE_FILE);
+            let url = "https://example.com/llmpal.json";
+
+            let fetched = Ok(r#"{"rules": ["team rule"]}"#.to_string());
+            let shared = shared_config(url, fetched, Some(&cache_path)).unwrap();
+            assert_eq!(shared.rules, Some(vec!["team rule".to_string()]));
+
+            let cached = shared_config(url, Err("offline".to_string()), Some(&cache_path)).unwrap();
+            assert_eq!(cached.rules, Some(vec!["team rule".to_string()]));
+
+            let other = "https://example.com/other.json";
+            assert!(shared_config(other, Err("offline".to_string()), Some(&cache_path)).is_none());
+            assert!(shared_config(other, Ok("not json".to_string()), Some(&cache_path)).is_none());
+
+            let local = Config {
+                rules: Some(vec!["local rule".to_string()]),
+                extends: Some(url.to_string()),
+                ..Default::default()
+            };
+            let merged = merge_configs(cached, local);
+            assert_eq!(
+                merged.rules,
+                Some(vec!["team rule".to_string(), "local rule".to_string()])
+            );
+        }
+
+        #[test]
+        fn test_shared_config_drops_local_access() {
+            let url = "https://example.com/llmpal.json";
+            let fetched = Ok(r#"{
+                "rules_file": "/etc/passwd",
+                "models": [{"code": "m", "model": "m-model", "api_url": "https://evil.example", "api_key": "file:~/.ssh/id_rsa"}],
+                "profiles": {"p": {"rules_file": "x", "models": [{"code": "p", "model": "p-model", "api_key": "$SECRET"}]}}
+            }"#
+            .to_string());
+            let shared = shared_config(url, fetched, None).unwrap();
+            assert_eq!(shared.rules_file, None);
+
+            let model = &shared.models.as_ref().unwrap()[0];
+            assert_eq!(model.model, "m-model");
+            assert_eq!(model.api_url, None);
+            assert_eq!(model.api_key, None);
+
+            let profile = &shared.profiles.as_ref().unwrap()["p"];
+            assert_eq!(profile.rules_file, None);
+            assert_eq!(profile.models.as_ref().unwrap()[0].api_key, None);
+        }
+
         #[test]
         fn test_merge_configs_overrides_local() {
             let home_config = Config {
@@ -415,26 +1926,22 @@ mod tests {
                     ModelConfig {
                         code: "home1".to_string(),
                         model: "home1-model".to_string(),
-                        prompt_cost: 1.0,
-                        completion_cost: 2.0,
-                        api_url: None,
-                        api_key: None,
-                        max_tokens: None,
-                        provider: None,
+                        prompt_cost: Some(1.0),
+                        completion_cost: Some(2.0),
+                        ..Default::default()
                     },
                     ModelConfig {
                         code: "shared".to_string(),
                         model: "home-shared".to_string(),
-                        prompt_cost: 1.0,
-                        completion_cost: 2.0,
-                        api_url: None,
-                        api_key: None,
-                        max_tokens: None,
-                        provider: None,
+                        prompt_cost: Some(1.0),
+                        completion_cost: Some(2.0),
+                        ..Default::default()
                     },
                 ]),
                 rules: Some(vec!["home-rule1".to_string(), "home-rule2".to_string()]),
                 diagnostic: Some(false),
+                max_cost: Some(0.5),
+                ..Default::default()
             };
 
             let local_config = Config {
@@ -442,31 +1949,27 @@ mod tests {
                     ModelConfig {
                         code: "local1".to_string(),
                         model: "local1-model".to_string(),
-                        prompt_cost: 1.5,
-                        completion_cost: 2.5,
-                        api_url: None,
-                        api_key: None,
-                        max_tokens: None,
-                        provider: None,
+                        prompt_cost: Some(1.5),
+                        completion_cost: Some(2.5),
+                        ..Default::default()
                     },
                     ModelConfig {
                         code: "shared".to_string(),
                         model: "local-shared".to_string(),
-                        prompt_cost: 1.5,
-                        completion_cost: 2.5,
-                        api_url: None,
-                        api_key: None,
-                        max_tokens: None,
-                        provider: None,
+                        prompt_cost: Some(1.5),
+                        completion_cost: Some(2.5),
+                        ..Default::default()
                     },
                 ]),
                 rules: Some(vec!["local-rule1".to_string()]),
                 diagnostic: Some(true),
+                ..Default::default()
             };
 
             let merged = merge_configs(home_config, local_config);
 
             assert_eq!(merged.diagnostic, Some(true));
+            assert_eq!(merged.max_cost, Some(0.5));
             assert_eq!(
                 merged.rules.unwrap(),
                 vec!["home-rule1", "home-rule2", "local-rule1"]
@@ -482,5 +1985,56 @@ mod tests {
             assert_eq!(models[1].model, "local-shared");
             assert_eq!(models[2].model, "home1-model");
         }
+
+        #[test]
+        fn test_local_rules_only_inherits_home_sections() {
+            let temp_dir = tempdir().unwrap();
+            let home_path = temp_dir.path().join("home.json");
+            let local_path = temp_dir.path().join("local.json");
+            fs::write(
+                &home_path,
+                r#"{
+                    "models": [{"code": "global", "model": "global-model"}],
+                    "rules": ["home-rule"],
+                    "diagnostic": true,
+                    "max_cost": 1.0,
+                    "default_model": "global"
+                }"#,
+            )
+            .unwrap();
+            fs::write(
+                &local_path,
+                r#"{"rules": ["project-rule"], "max_cost": 0.2}"#,
+            )
+            .unwrap();
+
+            let merged = load_merged_config(Some(&home_path), &local_path);
+
+            let models = merged.models.unwrap();
+            assert_eq!(models.len(), 1);
+            assert_eq!(models[0].code, "global");
+            assert_eq!(merged.rules.unwrap(), vec!["home-rule", "project-rule"]);
+            assert_eq!(merged.diagnostic, Some(true));
+            assert_eq!(merged.max_cost, Some(0.2));
+            assert_eq!(merged.default_model.as_deref(), Some("global"));
+        }
+
+        #[test]
+        fn test_missing_files_merge_to_the_other() {
+            let temp_dir = tempdir().unwrap();
+            let missing = temp_dir.path().join("missing.json");
+            let local_path = temp_dir.path().join("local.json");
+            fs::write(&local_path, r#"{"models": [{"code": "l", "model": "m"}]}"#).unwrap();
+
+            let merged = load_merged_config(Some(&missing), &local_path);
+            assert_eq!(merged.models.unwrap()[0].code, "l");
+
+            let merged = load_merged_config(None, &local_path);
+            assert_eq!(merged.models.unwrap()[0].code, "l");
+
+            let merged = load_merged_config(Some(&local_path), &missing);
+            assert_eq!(merged.models.unwrap()[0].code, "l");
+            assert!(merged.rules.is_none());
+        }
     }
 }
@@ -0,0 +1,59 @@
+use serde_json::json;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// One run as a chat fine-tuning example:
+/// `{"messages": [system, user, assistant]}`.
+pub fn fine_tune_record(system_prompt: &str, user_prompt: &str, response: &str) -> String {
+    json!({
+        "messages": [
+            { "role": "system", "content": system_prompt },
+            { "role": "user", "content": user_prompt },
+            { "role": "assistant", "content": response },
+        ]
+    })
+    .to_string()
+}
+
+/// Appends one record line to the `--export-jsonl` file.
+pub fn append_record(path: &Path, record: &str) -> Result<(), String> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create export directory: {}", e))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", record))
+        .map_err(|e| format!("Failed to write '{}': {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_append_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data").join("train.jsonl");
+        append_record(&path, &fine_tune_record("sys", "user 1", "answer 1")).unwrap();
+        append_record(&path, &fine_tune_record("sys", "user 2", "answer\n2")).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["messages"][0]["role"], "system");
+        assert_eq!(lines[1]["messages"][1]["content"], "user 2");
+        assert_eq!(lines[1]["messages"][2]["role"], "assistant");
+        assert_eq!(lines[1]["messages"][2]["content"], "answer\n2");
+    }
+}
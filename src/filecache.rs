@@ -0,0 +1,98 @@
+use crate::utils;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FILECACHE_DIR: &str = "filecache";
+
+/// 64-bit FNV-1a, stable across Rust versions unlike `DefaultHasher`.
+pub fn content_hash(content: &str) -> String {
+    let hash = content.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The cache of the current directory: `~/.llmpal/filecache/<hash of cwd>.json`.
+pub fn cache_path() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    utils::llmpal_dir().map(|dir| {
+        dir.join(FILECACHE_DIR)
+            .join(format!("{}.json", content_hash(&cwd.to_string_lossy())))
+    })
+}
+
+/// Path to content hash, as recorded by the last run; empty when missing or unreadable.
+pub fn load(path: &Path) -> HashMap<String, String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Records the hashes of `contents`, keeping the entries of other files.
+pub fn save(path: &Path, contents: &[(String, String)]) -> Result<(), String> {
+    let mut hashes = load(path);
+    for (file, content) in contents {
+        hashes.insert(file.clone(), content_hash(content));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create file cache directory: {}", e))?;
+    }
+    let json = serde_json::to_string(&hashes)
+        .map_err(|e| format!("Failed to serialize file cache: {}", e))?;
+    fs::write(path, json).map_err(|e| format!("Failed to write file cache: {}", e))
+}
+
+/// Files of `contents` whose hash matches the one recorded last time.
+pub fn unchanged_files(
+    previous: &HashMap<String, String>,
+    contents: &[(String, String)],
+) -> Vec<String> {
+    contents
+        .iter()
+        .filter(|(file, content)| previous.get(file) == Some(&content_hash(content)))
+        .map(|(file, _)| file.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, content: &str) -> (String, String) {
+        (path.to_string(), content.to_string())
+    }
+
+    #[test]
+    fn test_unchanged_files_after_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILECACHE_DIR).join("project.json");
+        assert!(load(&path).is_empty());
+
+        save(
+            &path,
+            &[file("a.rs", "fn a() {}"), file("b.rs", "fn b() {}")],
+        )
+        .unwrap();
+        save(&path, &[file("c.rs", "fn c() {}")]).unwrap();
+
+        let current = [
+            file("a.rs", "fn a() {}"),
+            file("b.rs", "fn b() { todo!() }"),
+            file("c.rs", "fn c() {}"),
+            file("d.rs", "fn d() {}"),
+        ];
+        assert_eq!(
+            unchanged_files(&load(&path), &current),
+            vec!["a.rs", "c.rs"]
+        );
+    }
+
+    #[test]
+    fn test_content_hash_is_stable() {
+        assert_eq!(content_hash(""), "cbf29ce484222325");
+        assert_eq!(content_hash("a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,560 @@
+use crate::llm::{self, ParsedResponse};
+use crate::{schema, tools};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// A response format understood by llmpal: the instructions telling the model
+/// how to lay out its answer, and the parser reading that layout back.
+pub trait ResponseParser {
+    fn format_instructions(&self) -> String;
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String>;
+
+    /// `resp_text` with the content of every file replaced by
+    /// `[REDACTED N bytes]`, for `--trace-redact-files`.
+    fn redact(&self, resp_text: &str) -> String {
+        llm::redact_file_contents(resp_text)
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// `<explain>` and `<file path="...">` tags
+    #[default]
+    #[value(alias = "xml_tags")]
+    #[serde(alias = "xml_tags")]
+    Tags,
+    /// Markdown headings followed by fenced code blocks
+    Markdown,
+    /// Legacy `=== ... START ===` / `=== ... END ===` markers
+    Markers,
+    /// A JSON object with `explanation` and `files`
+    Json,
+    /// Calls of a `write_file` tool, for models with function calling
+    Tools,
+}
+
+impl ResponseFormat {
+    pub fn parser(self) -> Box<dyn ResponseParser + Send + Sync> {
+        match self {
+            ResponseFormat::Tags => Box::new(TagParser),
+            ResponseFormat::Markdown => Box::new(MarkdownParser),
+            ResponseFormat::Markers => Box::new(MarkerParser),
+            ResponseFormat::Json => Box::new(JsonParser),
+            ResponseFormat::Tools => Box::new(ToolParser),
+        }
+    }
+}
+
+pub struct TagParser;
+
+impl ResponseParser for TagParser {
+    fn format_instructions(&self) -> String {
+        format!(
+            "# Output format\n\
+             You must follow this output format exactly. Deviations will be rejected.\n\
+             The response must start with:\n\
+             <explain>\n\
+             Brief explanations and answers to questions\n\
+             </explain>\n\
+             Then, for each file you are modifying or creating:\n\
+             <file path=\"path_to_file\">\n\
+             full file content\n\
+             </file>\n\n\
+             {}\n\n\
+             Example:\n\
+             <explain>\n\
+             I'm updating the build_system_prompt to reinforce format compliance.\n\
+             </explain>\n\
+             <file path=\"src/llm.rs\">\n\
+             updated content of the file\n\
+             </file>\n\n",
+            llm::FILE_ESCAPE_RULE
+        )
+    }
+
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String> {
+        llm::parse_llm_response(resp_text)
+    }
+}
+
+const MARKDOWN_FILE_HEADING: &str = "#### File:";
+
+pub struct MarkdownParser;
+
+impl ResponseParser for MarkdownParser {
+    fn format_instructions(&self) -> String {
+        "# Output format\n\
+         You must follow this output format exactly. Deviations will be rejected.\n\
+         The response must start with brief explanations and answers to questions as plain text.\n\
+         Then, for each file you are modifying or creating, a heading with the file path followed by a fenced code block:\n\
+         #### File: path_to_file\n\
+         ```\n\
+         full file content\n\
+         ```\n\
+         If the file content itself contains ``` fences, use a longer fence (e.g. ````) around it.\n\n\
+         Example:\n\
+         I'm updating the build_system_prompt to reinforce format compliance.\n\n\
+         #### File: src/llm.rs\n\
+         ```rust\n\
+         updated content of the file\n\
+         ```\n\n"
+            .to_string()
+    }
+
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String> {
+        let mut explanations = Vec::new();
+        let mut files_to_write = Vec::new();
+        let mut pending_path: Option<String> = None;
+        let mut fence: Option<String> = None;
+        let mut current_file = Vec::new();
+
+        for line in resp_text.lines() {
+            if let Some(open) = &fence {
+                if line.trim_end() == open {
+                    let path = pending_path.take().unwrap_or_default();
+                    files_to_write.push((path, current_file.join("\n")));
+                    current_file.clear();
+                    fence = None;
+                } else {
+                    current_file.push(llm::unescape_file_line(line).to_string());
+                }
+                continue;
+            }
+
+            let trimmed = line.trim();
+            if let Some(path) = trimmed.strip_prefix(MARKDOWN_FILE_HEADING) {
+                pending_path = Some(path.trim().trim_matches('`').to_string());
+                continue;
+            }
+            if pending_path.is_some() && trimmed.starts_with("```") {
+                let ticks = trimmed.chars().take_while(|c| *c == '`').count();
+                fence = Some("`".repeat(ticks));
+                continue;
+            }
+            if pending_path.is_some() && trimmed.is_empty() {
+                continue;
+            }
+            if let Some(path) = pending_path.take() {
+                return Err(format!(
+                    "Error: missing code block after the heading for '{}'",
+                    path
+                ));
+            }
+            explanations.push(line.to_string());
+        }
+
+        if fence.is_some() || pending_path.is_some() {
+            return Err(
+                "Error: unexpected end of response while parsing a file section".to_string(),
+            );
+        }
+
+        Ok(ParsedResponse {
+            explanation: explanations.join("\n").trim().to_string(),
+            files: llm::file_edits(files_to_write),
+            thinking: None,
+            extra: String::new(),
+        })
+    }
+
+    fn redact(&self, resp_text: &str) -> String {
+        let mut redacted = String::new();
+        let mut after_heading = false;
+        let mut block: Option<(String, usize)> = None;
+        for line in resp_text.split_inclusive('\n') {
+            if let Some((fence, bytes)) = block.as_mut() {
+                if line.trim_end() == fence {
+                    redacted.push_str(&format!("{}\n", llm::redacted(*bytes)));
+                    redacted.push_str(line);
+                    block = None;
+                } else {
+                    *bytes += line.len();
+                }
+                continue;
+            }
+            redacted.push_str(line);
+            let trimmed = line.trim();
+            if trimmed.starts_with(MARKDOWN_FILE_HEADING) {
+                after_heading = true;
+            } else if after_heading && trimmed.starts_with("```") {
+                let ticks = trimmed.chars().take_while(|c| *c == '`').count();
+                block = Some(("`".repeat(ticks), 0));
+                after_heading = false;
+            } else if !trimmed.is_empty() {
+                after_heading = false;
+            }
+        }
+        if let Some((_, bytes)) = block {
+            redacted.push_str(&llm::redacted(bytes));
+        }
+        redacted
+    }
+}
+
+pub struct MarkerParser;
+
+impl ResponseParser for MarkerParser {
+    fn format_instructions(&self) -> String {
+        "# Output format\n\
+         You must follow this output format exactly. Deviations will be rejected.\n\
+         The response must start with:\n\
+         === EXPLAIN START ===\n\
+         Brief explanations and answers to questions\n\
+         === EXPLAIN END ===\n\
+         Then, for each file you are modifying or creating:\n\
+         === path_to_file === START ===\n\
+         full file content\n\
+         === path_to_file === END ===\n\n\
+         Example:\n\
+         === EXPLAIN START ===\n\
+         I'm updating the build_system_prompt to reinforce format compliance.\n\
+         === EXPLAIN END ===\n\
+         === src/llm.rs === START ===\n\
+         updated content of the file\n\
+         === src/llm.rs === END ===\n\n"
+            .to_string()
+    }
+
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String> {
+        let mut in_explain = false;
+        let mut current_path: Option<String> = None;
+        let mut current_file = Vec::new();
+        let mut files_to_write = Vec::new();
+        let mut explanations = Vec::new();
+        let mut remaining = Vec::new();
+
+        for line in resp_text.lines() {
+            let trimmed = line.trim();
+
+            if let Some(path) = &current_path {
+                if trimmed == format!("=== {} === END ===", path) {
+                    files_to_write.push((path.clone(), current_file.join("\n")));
+                    current_file.clear();
+                    current_path = None;
+                } else {
+                    current_file.push(llm::unescape_file_line(line).to_string());
+                }
+                continue;
+            }
+
+            if trimmed == "=== EXPLAIN START ===" {
+                in_explain = true;
+                continue;
+            }
+            if trimmed == "=== EXPLAIN END ===" {
+                in_explain = false;
+                continue;
+            }
+            if in_explain {
+                explanations.push(line.to_string());
+                continue;
+            }
+
+            if let Some(path) = trimmed
+                .strip_prefix("=== ")
+                .and_then(|rest| rest.strip_suffix(" === START ==="))
+            {
+                current_path = Some(path.trim().to_string());
+                continue;
+            }
+            remaining.push(line.to_string());
+        }
+
+        if current_path.is_some() {
+            return Err(
+                "Error: unexpected end of response while parsing a file section".to_string(),
+            );
+        }
+
+        Ok(ParsedResponse {
+            explanation: explanations.join("\n"),
+            files: llm::file_edits(files_to_write),
+            thinking: None,
+            extra: remaining.join("\n"),
+        })
+    }
+
+    fn redact(&self, resp_text: &str) -> String {
+        let mut redacted = String::new();
+        let mut block: Option<(String, usize)> = None;
+        for line in resp_text.split_inclusive('\n') {
+            match block.as_mut() {
+                Some((end, bytes)) if line.trim() == end => {
+                    redacted.push_str(&format!("{}\n", llm::redacted(*bytes)));
+                    redacted.push_str(line);
+                    block = None;
+                }
+                Some((_, bytes)) => *bytes += line.len(),
+                None => {
+                    redacted.push_str(line);
+                    block = line
+                        .trim()
+                        .strip_prefix("=== ")
+                        .and_then(|rest| rest.strip_suffix(" === START ==="))
+                        .map(|path| (format!("=== {} === END ===", path.trim()), 0));
+                }
+            }
+        }
+        if let Some((_, bytes)) = block {
+            redacted.push_str(&llm::redacted(bytes));
+        }
+        redacted
+    }
+}
+
+pub struct JsonParser;
+
+#[derive(Deserialize)]
+struct JsonResponse {
+    #[serde(default)]
+    explanation: String,
+    #[serde(default)]
+    files: Vec<JsonFile>,
+}
+
+#[derive(Deserialize)]
+struct JsonFile {
+    path: String,
+    content: String,
+}
+
+impl ResponseParser for JsonParser {
+    fn format_instructions(&self) -> String {
+        "# Output format\n\
+         You must follow this output format exactly. Deviations will be rejected.\n\
+         The response must be a single JSON object, without code fences or any text around it, with:\n\
+         - \"explanation\": brief explanations and answers to questions\n\
+         - \"files\": an array with one {\"path\", \"content\"} object per file you are modifying or creating, \
+         holding the path and the full file content\n\n\
+         Example:\n\
+         {\"explanation\": \"I'm updating the build_system_prompt to reinforce format compliance.\", \
+         \"files\": [{\"path\": \"src/llm.rs\", \"content\": \"updated content of the file\"}]}\n\n"
+            .to_string()
+    }
+
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String> {
+        let response: JsonResponse = serde_json::from_str(schema::strip_code_fence(resp_text))
+            .map_err(|e| format!("Error: response is not a valid JSON object: {}", e))?;
+        Ok(ParsedResponse {
+            explanation: response.explanation.trim().to_string(),
+            files: llm::file_edits(
+                response
+                    .files
+                    .into_iter()
+                    .map(|file| (file.path, llm::unescape_file_content(&file.content)))
+                    .collect(),
+            ),
+            thinking: None,
+            extra: String::new(),
+        })
+    }
+
+    fn redact(&self, resp_text: &str) -> String {
+        match serde_json::from_str(schema::strip_code_fence(resp_text)) {
+            Ok(mut value) => {
+                redact_content_fields(&mut value);
+                value.to_string()
+            }
+            Err(_) => resp_text.to_string(),
+        }
+    }
+}
+
+/// Replaces every `content` string in a JSON response, or in the arguments of
+/// a `write_file` call, with `[REDACTED N bytes]`.
+fn redact_content_fields(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                match field {
+                    serde_json::Value::String(text) if key == "content" => {
+                        *text = llm::redacted(text.len());
+                    }
+                    _ => redact_content_fields(field),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_content_fields),
+        _ => {}
+    }
+}
+
+/// Asks for `write_file` tool calls. The tool conversation is turned into a
+/// `json` format response before parsing (see `tools::response_text`).
+pub struct ToolParser;
+
+impl ResponseParser for ToolParser {
+    fn format_instructions(&self) -> String {
+        format!(
+            "# Output format\n\
+             Call the {} tool once for each file you are modifying or creating, with its path and full content. \
+             Answer questions and briefly explain your changes in the message text.\n\n",
+            tools::WRITE_FILE
+        )
+    }
+
+    fn parse(&self, resp_text: &str) -> Result<ParsedResponse, String> {
+        JsonParser.parse(resp_text)
+    }
+
+    /// Covers both the `json` content built from the calls and the raw
+    /// arguments of a `write_file` call.
+    fn redact(&self, resp_text: &str) -> String {
+        JsonParser.redact(resp_text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FileEdit;
+
+    #[test]
+    fn test_instructions_match_parser() {
+        for format in [
+            ResponseFormat::Tags,
+            ResponseFormat::Markdown,
+            ResponseFormat::Markers,
+            ResponseFormat::Json,
+        ] {
+            let parser = format.parser();
+            let instructions = parser.format_instructions();
+            let example = instructions.split("Example:\n").nth(1).unwrap();
+            let parsed = parser.parse(example).unwrap();
+            assert_eq!(
+                parsed.explanation,
+                "I'm updating the build_system_prompt to reinforce format compliance.",
+                "{:?}",
+                format
+            );
+            assert_eq!(
+                parsed.files,
+                vec![FileEdit {
+                    path: "src/llm.rs".to_string(),
+                    content: "updated content of the file".to_string()
+                }],
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_markdown_parser_longer_fence() {
+        let resp_text = "Updated the docs.\n\n\
+            #### File: README.md\n\
+            ````markdown\n\
+            # Title\n\
+            ```sh\n\
+            llmpal --help\n\
+            ```\n\
+            ````\n";
+        let parsed = MarkdownParser.parse(resp_text).unwrap();
+        assert_eq!(parsed.explanation, "Updated the docs.");
+        assert_eq!(parsed.files.len(), 1);
+        assert_eq!(parsed.files[0].path, "README.md");
+        assert_eq!(
+            parsed.files[0].content,
+            "# Title\n```sh\nllmpal --help\n```"
+        );
+    }
+
+    #[test]
+    fn test_markdown_parser_unterminated_block() {
+        let resp_text = "#### File: src/main.rs\n```rust\nfn main() {}\n";
+        assert!(MarkdownParser.parse(resp_text).is_err());
+    }
+
+    #[test]
+    fn test_marker_parser_unterminated_file() {
+        let resp_text = "=== src/main.rs === START ===\nfn main() {}\n";
+        assert!(MarkerParser.parse(resp_text).is_err());
+    }
+
+    #[test]
+    fn test_parsers_unescape_file_tags() {
+        let markdown = "Done.\n#### File: a.html\n```\n\\</file>\n```\n";
+        let markers = "=== a.html === START ===\n\\</file>\n=== a.html === END ===\n";
+        for files in [
+            MarkdownParser.parse(markdown).unwrap().file_pairs(),
+            MarkerParser.parse(markers).unwrap().file_pairs(),
+        ] {
+            assert_eq!(files, vec![("a.html".to_string(), "</file>".to_string())]);
+        }
+
+        let original = "<p>\n</file>\n\\<file x>\n";
+        let echoed = serde_json::json!({
+            "explanation": "Unchanged.",
+            "files": [{ "path": "a.html", "content": llm::escape_file_content(original) }]
+        });
+        for parser in [&JsonParser as &dyn ResponseParser, &ToolParser] {
+            assert_eq!(
+                parser.parse(&echoed.to_string()).unwrap().file_pairs(),
+                vec![("a.html".to_string(), original.to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_parser() {
+        let resp_text = "```json\n{\"explanation\": \"Done.\", \"files\": [\
+            {\"path\": \"a.rs\", \"content\": \"old\"}, {\"path\": \"a.rs\", \"content\": \"fn a() {}\\n\"}]}\n```";
+        let parsed = JsonParser.parse(resp_text).unwrap();
+        assert_eq!(parsed.explanation, "Done.");
+        assert_eq!(
+            parsed.file_pairs(),
+            vec![("a.rs".to_string(), "fn a() {}\n".to_string())]
+        );
+        assert!(
+            JsonParser
+                .parse(r#"{"explanation": "No changes needed."}"#)
+                .unwrap()
+                .files
+                .is_empty()
+        );
+        assert!(JsonParser.parse("Here you go: {}").is_err());
+    }
+
+    #[test]
+    fn test_parsers_redact_file_content() {
+        let markdown = "Done.\n#### File: a.rs\n```rust\nfn a() {}\n```\n";
+        assert_eq!(
+            MarkdownParser.redact(markdown),
+            "Done.\n#### File: a.rs\n```rust\n[REDACTED 10 bytes]\n```\n"
+        );
+        let markers = "=== a.rs === START ===\nfn a() {}\n=== a.rs === END ===\n";
+        assert_eq!(
+            MarkerParser.redact(markers),
+            "=== a.rs === START ===\n[REDACTED 10 bytes]\n=== a.rs === END ===\n"
+        );
+        let json = r#"{"explanation":"Done.","files":[{"path":"a.rs","content":"fn a() {}"}]}"#;
+        assert_eq!(
+            JsonParser.redact(json),
+            r#"{"explanation":"Done.","files":[{"content":"[REDACTED 9 bytes]","path":"a.rs"}]}"#
+        );
+        let arguments = r#"{"path":"a.rs","content":"fn a() {}"}"#;
+        assert!(!ToolParser.redact(arguments).contains("fn a()"));
+        assert_eq!(JsonParser.redact("not json"), "not json");
+    }
+
+    #[test]
+    fn test_response_format_names() {
+        let format: ResponseFormat = serde_json::from_str("\"xml_tags\"").unwrap();
+        assert_eq!(format, ResponseFormat::Tags);
+        let format: ResponseFormat = serde_json::from_str("\"json\"").unwrap();
+        assert_eq!(format, ResponseFormat::Json);
+        assert_eq!(
+            ResponseFormat::from_str("xml_tags", false),
+            Ok(ResponseFormat::Tags)
+        );
+    }
+
+    #[test]
+    fn test_tag_parser_ignores_markers() {
+        let resp_text = "=== EXPLAIN START ===\nhi\n=== EXPLAIN END ===\n";
+        let parsed = TagParser.parse(resp_text).unwrap();
+        assert!(parsed.explanation.is_empty());
+        assert!(parsed.files.is_empty());
+    }
+}
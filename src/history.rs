@@ -0,0 +1,189 @@
+use crate::app::LlmpalError;
+use crate::config::Cli;
+use crate::{lock, utils};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// The inputs of one invocation, as recorded in `~/.llmpal/history.jsonl`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub cwd: String,
+    pub model: Option<String>,
+    pub files: Vec<String>,
+    pub files0_from: Option<String>,
+    pub output: Option<String>,
+    pub instruction: Option<String>,
+    pub instruction_file: Option<String>,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn from_args(args: &Cli, request_id: &str) -> Self {
+        HistoryEntry {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            cwd: std::env::current_dir()
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            model: args.model.clone(),
+            files: args.files.clone(),
+            files0_from: args.files0_from.clone(),
+            output: args.output.clone(),
+            instruction: args.instruction.clone(),
+            instruction_file: args.instruction_file.clone(),
+            template: args.template.clone(),
+            request_id: Some(request_id.to_string()),
+        }
+    }
+
+    /// `args` with the recorded model, files and instruction; all other
+    /// flags of the current invocation are kept.
+    pub fn to_args(&self, args: &Cli) -> Cli {
+        Cli {
+            model: self.model.clone(),
+            files: self.files.clone(),
+            files0_from: self.files0_from.clone(),
+            output: self.output.clone(),
+            instruction: self.instruction.clone(),
+            instruction_file: self.instruction_file.clone(),
+            template: self.template.clone(),
+            redo: None,
+            ..args.clone()
+        }
+    }
+}
+
+pub fn history_path() -> Option<PathBuf> {
+    utils::llmpal_dir().map(|dir| dir.join(HISTORY_FILE))
+}
+
+pub fn append_entry(path: &Path, entry: &HistoryEntry) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create history directory: {}", e))?;
+    }
+    let line = serde_json::to_string(entry)
+        .map_err(|e| format!("Failed to serialize history entry: {}", e))?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| format!("Failed to write history: {}", e))
+}
+
+/// The `n`th most recent entry (1 = last run).
+pub fn nth_recent_entry(path: &Path, n: usize) -> Result<HistoryEntry, LlmpalError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        LlmpalError::FileError(format!("Cannot read history '{}': {}", path.display(), e))
+    })?;
+    let entries: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+    let line = n
+        .checked_sub(1)
+        .and_then(|back| entries.len().checked_sub(back + 1))
+        .map(|index| entries[index])
+        .ok_or_else(|| {
+            LlmpalError::UsageError(format!(
+                "--redo {}: history has {} recorded run(s)",
+                n,
+                entries.len()
+            ))
+        })?;
+    serde_json::from_str(line)
+        .map_err(|e| LlmpalError::ParseError(format!("Corrupt history entry: {}", e)))
+}
+
+/// Appends the invocation to the history; failures are only reported.
+pub async fn record(args: &Cli, request_id: &str) {
+    if let Some(path) = history_path()
+        && let Err(e) =
+            lock::with_global(|| append_entry(&path, &HistoryEntry::from_args(args, request_id)))
+                .await
+    {
+        eprintln!("> Warning: {}", e);
+    }
+}
+
+/// Rebuilds the arguments of the `n`th most recent run for `--redo`.
+pub fn replay_args(args: &Cli, n: usize) -> Result<Cli, LlmpalError> {
+    let path = history_path().ok_or_else(|| {
+        LlmpalError::FileError("No home directory found for the run history".to_string())
+    })?;
+    let entry = nth_recent_entry(&path, n)?;
+    let cwd = std::env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if entry.cwd != cwd {
+        return Err(LlmpalError::UsageError(format!(
+            "--redo {}: that run was made in '{}'; re-run from there",
+            n, entry.cwd
+        )));
+    }
+    eprintln!(
+        "# Replaying: {}",
+        entry
+            .instruction
+            .as_deref()
+            .or(entry.instruction_file.as_deref())
+            .unwrap_or_default()
+    );
+    Ok(entry.to_args(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(instruction: &str) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: 1,
+            cwd: "/project".to_string(),
+            model: Some("kimi".to_string()),
+            files: vec!["src/main.rs".to_string()],
+            files0_from: None,
+            output: None,
+            instruction: Some(instruction.to_string()),
+            instruction_file: None,
+            template: None,
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn test_nth_recent_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(HISTORY_FILE);
+        append_entry(&path, &entry("first")).unwrap();
+        append_entry(&path, &entry("second")).unwrap();
+
+        assert_eq!(nth_recent_entry(&path, 1).unwrap(), entry("second"));
+        assert_eq!(nth_recent_entry(&path, 2).unwrap(), entry("first"));
+        assert!(nth_recent_entry(&path, 3).is_err());
+        assert!(nth_recent_entry(&path, 0).is_err());
+    }
+
+    #[test]
+    fn test_to_args_keeps_other_flags() {
+        let current = Cli {
+            redo: Some(1),
+            confirm: true,
+            ..Default::default()
+        };
+        let args = entry("Add tests").to_args(&current);
+        assert_eq!(args.instruction.as_deref(), Some("Add tests"));
+        assert_eq!(args.model.as_deref(), Some("kimi"));
+        assert_eq!(args.files, vec!["src/main.rs"]);
+        assert_eq!(args.redo, None);
+        assert!(args.confirm);
+    }
+}
@@ -1,5 +1,18 @@
 pub mod app;
+pub mod clipboard;
 pub mod config;
+pub mod export;
+pub mod filecache;
+pub mod format;
+pub mod history;
 pub mod llm;
+pub mod lock;
+pub mod models;
+pub mod provider;
+pub mod schema;
 pub mod spinner;
+pub mod stream;
+pub mod templates;
+pub mod tools;
+pub mod usage;
 pub mod utils;
@@ -1,4 +1,11 @@
-pub fn build_system_prompt(allowed_files: &[String], rules: &[String]) -> String {
+use crate::format::ResponseParser;
+use tracing::warn;
+
+pub fn build_system_prompt(
+    allowed_files: &[String],
+    rules: &[String],
+    parser: &dyn ResponseParser,
+) -> String {
     let mut prompt = String::new();
 
     prompt.push_str(
@@ -18,7 +25,7 @@ pub fn build_system_prompt(allowed_files: &[String], rules: &[String]) -> String
 
     prompt.push_str(
         "\n\
-        - NEVER, EVER, UNDER ANY CIRCUMSTANCES output <file> tags for files other than listed in the list provided above.\n\
+        - NEVER, EVER, UNDER ANY CIRCUMSTANCES output content for files other than listed in the list provided above.\n\
         - Never create or modify any files when the user is only asking questions.\n\
         - When asked to modify a file, provide **full** contents of the file after modification.\n\
         - Always provide a brief explanation for your actions.\n\
@@ -37,172 +44,722 @@ pub fn build_system_prompt(allowed_files: &[String], rules: &[String]) -> String
         for rule in rules {
             prompt.push_str(&format!("- {}\n", rule));
         }
-        prompt.push_str("\n");
+        prompt.push('\n');
+    }
+
+    prompt.push_str(&parser.format_instructions());
+
+    prompt
+}
+
+/// Headings that start a new system message when the prompt is split.
+const SYSTEM_PROMPT_SECTIONS: [&str; 2] = ["# Additional rules", "# Output format"];
+
+/// Splits a system prompt into its base guidelines, additional rules and
+/// output format sections, in that order, dropping empty ones.
+pub fn split_system_prompt(prompt: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in prompt.split_inclusive('\n') {
+        if offset > start && SYSTEM_PROMPT_SECTIONS.contains(&line.trim_end()) {
+            parts.push(prompt[start..offset].trim_end());
+            start = offset;
+        }
+        offset += line.len();
     }
+    parts.push(prompt[start..].trim_end());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+pub fn build_schema_system_prompt(rules: &[String]) -> String {
+    let mut prompt = String::new();
 
     prompt.push_str(
-        "# Output format\n\
-         You must follow this output format exactly. Deviations will be rejected.\n\
-         The response must start with:\n\
-         <explain>\n\
-         Brief explanations and answers to questions\n\
-         </explain>\n\
-         Then, for each file you are modifying or creating:\n\
-         <file path=\"path_to_file\">\n\
-         full file content\n\
-         </file>\n\n\
-         Example:\n\
-         <explain>\n\
-         I'm updating the build_system_prompt to reinforce format compliance.\n\
-         </explain>\n\
-         <file path=\"src/llm.rs\">\n\
-         updated content of the file\n\
-         </file>\n\n",
+        "You are a non-interactive agent specialized in extracting and generating structured data.\n\
+        # Guidelines\n\
+        - Respond ONLY with a single JSON value that conforms to the JSON schema provided with the request.\n\
+        - Never output explanations, comments or any text outside of the JSON value.\n\
+        - Never wrap the JSON value in code fences.\n\n",
     );
 
+    if !rules.is_empty() {
+        prompt.push_str("# Additional rules\n");
+        for rule in rules {
+            prompt.push_str(&format!("- {}\n", rule));
+        }
+        prompt.push('\n');
+    }
+
     prompt
 }
 
+/// Sent after a completion shorter than `min_completion_tokens`.
+pub const LENGTH_FLOOR_FOLLOW_UP: &str =
+    "That seems incomplete. Please provide the full output again, following the output format.";
+
+/// Sent with `--keep-going-past-max-tokens` after a completion cut off at max_tokens.
+pub const CONTINUE_FOLLOW_UP: &str = "Your response was cut off. Continue exactly where you left off, \
+without repeating anything or adding any introduction.";
+
+/// Sent when `--require-output` is set and the response lacks the output file.
+pub fn missing_output_follow_up(output: &str) -> String {
+    format!(
+        "Your response did not include the file {0}. Please provide the complete response again, including the full content of {0}, following the output format.",
+        output
+    )
+}
+
+pub const LINE_NUMBERS_RULE: &str = "Lines of the user input files are prefixed with their line number followed by '| '. \
+The prefixes are not part of the files: use them to reference positions, but never include them in file contents you output.";
+
+pub const LINE_RANGE_RULE: &str = "Input files whose path ends in `:START-END` show only \
+     those lines of the file. They are read-only context: never output them.";
+
+/// Prefixes each line with its right-aligned 1-based number, e.g. ` 7| `.
+pub fn number_lines(content: &str) -> String {
+    let width = content.lines().count().max(1).to_string().len();
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| format!("{:>width$}| {}", index + 1, line, width = width))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+const MARKDOWN_EXTENSIONS: [&str; 3] = ["md", "markdown", "mdx"];
+
+/// Removes a code fence the model wrapped around a whole file, e.g. a file
+/// starting with ```` ```rust ```` and ending with ```` ``` ````. Content is only
+/// changed when it is exactly one fenced block; markdown files are left alone
+/// since they legitimately contain fences.
+pub fn strip_wrapping_fence(path: &str, content: &str) -> String {
+    let is_markdown = std::path::Path::new(path).extension().is_some_and(|ext| {
+        MARKDOWN_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+    });
+    if is_markdown {
+        return content.to_string();
+    }
+
+    let lines: Vec<&str> = content.trim().lines().collect();
+    let (Some(first), Some(last)) = (lines.first(), lines.last()) else {
+        return content.to_string();
+    };
+    let first = first.trim();
+    let Some(fence_char) = first.chars().next().filter(|c| *c == '`' || *c == '~') else {
+        return content.to_string();
+    };
+    let fence_len = first.chars().take_while(|c| *c == fence_char).count();
+    let is_closing_fence = |line: &str| {
+        let line = line.trim();
+        line.len() >= fence_len && line.chars().all(|c| c == fence_char)
+    };
+    if lines.len() < 2 || fence_len < 3 || !is_closing_fence(last) {
+        return content.to_string();
+    }
+    let inner = &lines[1..lines.len() - 1];
+    if inner.iter().any(|line| is_closing_fence(line)) {
+        return content.to_string();
+    }
+
+    let mut stripped = inner.join("\n");
+    if content.ends_with('\n') && !stripped.is_empty() {
+        stripped.push('\n');
+    }
+    stripped
+}
+
+/// Removes line-number prefixes echoed back by the model. Content is only changed
+/// when every line carries the prefix with consecutive numbers starting at 1.
+pub fn strip_line_numbers(content: &str) -> String {
+    let mut stripped = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let Some((number, rest)) = line.split_once('|') else {
+            return content.to_string();
+        };
+        if number.trim_start().parse::<usize>() != Ok(index + 1) {
+            return content.to_string();
+        }
+        stripped.push(rest.strip_prefix(' ').unwrap_or(rest));
+    }
+    if stripped.is_empty() {
+        return content.to_string();
+    }
+    stripped.join("\n")
+}
+
 pub fn build_user_prompt(
     instruction: &str,
-    files: &[String],
+    files: &[(String, String)],
     output_file: &Option<String>,
+    diff: Option<(&str, &str)>,
+    tree: Option<&str>,
+    command_outputs: &[(String, String)],
+    file_diffs: &[(String, String)],
 ) -> String {
     let mut prompt = String::new();
     prompt.push_str("# User instructions\n");
     prompt.push_str(instruction);
 
     prompt.push_str("\n\n");
+
+    if let Some((reference, patch)) = diff {
+        prompt.push_str(&format!(
+            "# Changes since {} (read-only context):\n\
+             <diff>\n\
+             {}\n\
+             </diff>\n\n",
+            reference,
+            patch.trim_end()
+        ));
+    }
+
+    for (command, output) in command_outputs {
+        prompt.push_str(&format!(
+            "# Output of `{}` (read-only context):\n\
+             <command_output>\n\
+             {}\n\
+             </command_output>\n\n",
+            command,
+            output.trim_end()
+        ));
+    }
+
+    if let Some(tree) = tree {
+        prompt.push_str(&format!(
+            "# Project tree (paths only):\n\
+             <tree>\n\
+             {}\n\
+             </tree>\n\n",
+            tree.trim_end()
+        ));
+    }
+
     prompt.push_str("# User input files:\n");
 
-    for f in files {
-        if let Some(output) = output_file {
-            if f == output {
-                continue;
-            }
+    for (path, content) in files {
+        if let Some(output) = output_file
+            && path == output
+        {
+            continue;
         }
 
-        let content = if cfg!(test) {
-            String::new()
-        } else {
-            std::fs::read_to_string(f).unwrap_or_else(|_| {
-                eprintln!("Error: cannot read file '{}': No such file or directory", f);
-                std::process::exit(1);
-            })
-        };
         prompt.push_str(&format!(
             "<file path=\"{}\">\n\
              {}\n\
              </file>\n",
-            f, content
+            path,
+            escape_file_content(content)
         ));
+
+        if let Some((_, patch)) = file_diffs.iter().find(|(file, _)| file == path) {
+            prompt.push_str(&format!(
+                "<diff path=\"{}\">\n\
+                 {}\n\
+                 </diff>\n",
+                path,
+                patch.trim_end()
+            ));
+        }
     }
 
     prompt
 }
 
-pub fn parse_llm_response(
-    resp_text: &str,
-) -> Result<(String, Vec<(String, String)>, String), String> {
-    let mut in_think = false;
-    let mut in_explain = false;
-    let mut in_file = false;
-    let mut current_path = String::new();
-    let mut current_file = Vec::new();
-    let mut files_to_write = Vec::new();
-    let mut explanations = Vec::new();
-    let mut remaining = Vec::new();
-
-    for line in resp_text.lines() {
+/// Sent in place of the content of a file skipped by `--skip-unchanged`.
+pub const UNCHANGED_FILE_PLACEHOLDER: &str = "[content omitted; unchanged since the last run]";
+
+pub const UNCHANGED_FILES_RULE: &str = "Input files whose content is shown as \
+     `[content omitted; unchanged since the last run]` exist in the project, but their content \
+     is not available to you. Do not output them, and do not assume anything about their content \
+     beyond their path.";
+
+/// Lists the directories of `--allow-under`, where the model may create files.
+pub fn allow_under_rule(dirs: &[String]) -> String {
+    format!(
+        "You may also create or modify files inside these directories: {}.",
+        dirs.join(", ")
+    )
+}
+
+/// Tells the model how to escape tag-like lines inside `<file>` content.
+pub const FILE_ESCAPE_RULE: &str = "Inside file content, a line starting with `<file` or `</file` \
+     must be prefixed with a backslash (e.g. `\\</file>`), and a line starting with backslashes \
+     followed by `<file` or `</file` gets one more backslash. Input files are escaped the same way.";
+
+/// Whether the line, after any leading backslashes, starts like a file tag.
+fn is_file_tag_line(line: &str) -> bool {
+    let rest = line.trim_start_matches('\\');
+    rest.starts_with("<file") || rest.starts_with("</file")
+}
+
+/// Blocks of the user prompt holding file content or other project output,
+/// by the start of their opening line and their closing line.
+const REDACTED_BLOCKS: [(&str, &str); 3] = [
+    ("<file", "</file>"),
+    ("<diff", "</diff>"),
+    ("<command_output>", "</command_output>"),
+];
+
+/// The placeholder for `bytes` of redacted content.
+pub fn redacted(bytes: usize) -> String {
+    format!("[REDACTED {} bytes]", bytes)
+}
+
+/// Replaces the content of every `<file>`, `<diff>` and `<command_output>`
+/// block in `text` with `[REDACTED N bytes]`, keeping the tags, for sharing
+/// traces of prompts and responses without the source code.
+pub fn redact_file_contents(text: &str) -> String {
+    let mut redacted_text = String::new();
+    let mut block: Option<(&str, usize)> = None;
+    for line in text.split_inclusive('\n') {
+        let tag = line.trim_end();
+        match block.as_mut() {
+            Some((close, bytes)) if tag == *close => {
+                redacted_text.push_str(&format!("{}\n", redacted(*bytes)));
+                redacted_text.push_str(line);
+                block = None;
+            }
+            Some((_, bytes)) => *bytes += line.len(),
+            None => {
+                redacted_text.push_str(line);
+                block = REDACTED_BLOCKS
+                    .iter()
+                    .find(|(open, _)| tag.starts_with(open))
+                    .map(|(_, close)| (*close, 0));
+            }
+        }
+    }
+    if let Some((_, bytes)) = block {
+        redacted_text.push_str(&redacted(bytes));
+    }
+    redacted_text
+}
+
+/// Escapes lines of file content that the tag parser would take for a
+/// `<file>`/`</file>` boundary by prefixing one backslash.
+pub fn escape_file_content(content: &str) -> String {
+    if !content.lines().any(is_file_tag_line) {
+        return content.to_string();
+    }
+    content
+        .split('\n')
+        .map(|line| {
+            if is_file_tag_line(line) {
+                format!("\\{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reverses `escape_file_content` for one line. Input files are always sent
+/// escaped, so every parser applies this to file content.
+pub fn unescape_file_line(line: &str) -> &str {
+    match line.strip_prefix('\\') {
+        Some(rest) if is_file_tag_line(rest) => rest,
+        _ => line,
+    }
+}
+
+/// Reverses `escape_file_content` for a whole file, for formats that return
+/// file content as one string rather than line by line.
+pub fn unescape_file_content(content: &str) -> String {
+    content
+        .split('\n')
+        .map(unescape_file_line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A file the model wrote in its response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEdit {
+    pub path: String,
+    pub content: String,
+}
+
+impl From<(String, String)> for FileEdit {
+    fn from((path, content): (String, String)) -> Self {
+        FileEdit { path, content }
+    }
+}
+
+impl From<FileEdit> for (String, String) {
+    fn from(edit: FileEdit) -> Self {
+        (edit.path, edit.content)
+    }
+}
+
+/// A model response split by a `ResponseParser`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedResponse {
+    pub explanation: String,
+    /// Deduplicated, keeping the last block for each path.
+    pub files: Vec<FileEdit>,
+    /// Content of `<think>` blocks, for models that reason inline.
+    pub thinking: Option<String>,
+    /// Text outside the response format, shown with `--show-extra`.
+    pub extra: String,
+}
+
+impl ParsedResponse {
+    /// The files as `(path, content)` pairs, the shape shared with input contents.
+    pub fn file_pairs(&self) -> Vec<(String, String)> {
+        self.files.iter().cloned().map(Into::into).collect()
+    }
+}
+
+/// `files` deduplicated with `dedup_files`, as `FileEdit`s.
+pub fn file_edits(files: Vec<(String, String)>) -> Vec<FileEdit> {
+    dedup_files(files).into_iter().map(FileEdit::from).collect()
+}
+
+pub fn parse_llm_response(resp_text: &str) -> Result<ParsedResponse, String> {
+    let mut parser = StreamingParser::default();
+    parser.feed(resp_text);
+    parser.finish()
+}
+
+/// The `<explain>`/`<file>` parser as a state machine fed with stream
+/// deltas: explanation lines are returned as soon as they are complete,
+/// file content is buffered until `finish`.
+#[derive(Default)]
+pub struct StreamingParser {
+    in_think: bool,
+    thinking: Option<Vec<String>>,
+    in_explain: bool,
+    in_file: bool,
+    current_path: String,
+    current_file: Vec<String>,
+    files_to_write: Vec<(String, String)>,
+    explanations: Vec<String>,
+    remaining: Vec<String>,
+    pending: String,
+}
+
+impl StreamingParser {
+    /// Feeds a chunk of the response and returns the explanation lines it completed.
+    pub fn feed(&mut self, delta: &str) -> Vec<String> {
+        self.pending.push_str(delta);
+        let mut explained = Vec::new();
+        while let Some(pos) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=pos).collect();
+            let line = line.trim_end_matches('\n');
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if let Some(explanation) = self.feed_line(line) {
+                explained.push(explanation);
+            }
+        }
+        explained
+    }
+
+    fn feed_line(&mut self, line: &str) -> Option<String> {
         let trimmed = line.trim();
 
         if trimmed.starts_with("<think>") {
-            in_think = true;
-            continue;
+            self.in_think = true;
+            self.thinking.get_or_insert_with(Vec::new);
+            return None;
         }
         if trimmed.starts_with("</think>") {
-            in_think = false;
-            continue;
+            self.in_think = false;
+            return None;
         }
-        if in_think {
-            continue;
+        if self.in_think {
+            if let Some(thinking) = self.thinking.as_mut() {
+                thinking.push(line.to_string());
+            }
+            return None;
         }
 
-        if trimmed.starts_with("<explain>") {
-            in_explain = true;
-            continue;
+        if let Some(rest) = trimmed.strip_prefix("<explain>") {
+            // Text after the tag, possibly closed on the same line, is
+            // part of the explanation.
+            let (text, closed) = match rest.find("</explain>") {
+                Some(end) => (&rest[..end], true),
+                None => (rest, false),
+            };
+            self.in_explain = !closed;
+            let text = text.trim();
+            return (!text.is_empty()).then(|| self.push_explanation(text));
         }
         if trimmed.starts_with("</explain>") {
-            in_explain = false;
-            continue;
+            self.in_explain = false;
+            return None;
         }
-        if in_explain {
-            explanations.push(line.to_string());
-            continue;
+        if self.in_explain {
+            if let Some(text) = line.trim_end().strip_suffix("</explain>") {
+                self.in_explain = false;
+                return Some(self.push_explanation(text));
+            }
+            return Some(self.push_explanation(line));
         }
 
         if line.starts_with("<file") && line.ends_with(">") {
-            in_file = true;
-            if let Some(pos) = trimmed.find("path=\"") {
-                let path_start = pos + 6;
-                if path_start < trimmed.len() {
-                    let slice = &trimmed[path_start..];
-                    if let Some(end) = slice.find('"') {
-                        let path_end = path_start + end;
-                        current_path = (&trimmed[path_start..path_end]).parse().unwrap();
-                        current_file.clear();
-                        continue;
-                    }
-                }
-            }
-            current_path = String::new();
-            current_file.clear();
-            continue;
+            self.in_file = true;
+            self.current_file.clear();
+            self.current_path = trimmed
+                .find("path=\"")
+                .map(|pos| &trimmed[pos + 6..])
+                .and_then(|slice| slice.find('"').map(|end| slice[..end].to_string()))
+                .unwrap_or_default();
+            return None;
         }
         if line.starts_with("</file>") {
-            in_file = false;
-            if !current_path.is_empty() {
-                files_to_write.push((current_path.clone(), current_file.join("\n")));
+            self.in_file = false;
+            if !self.current_path.is_empty() {
+                self.files_to_write
+                    .push((self.current_path.clone(), self.current_file.join("\n")));
             }
-            continue;
+            return None;
         }
-        if in_file {
-            current_file.push(line.to_string());
+        if self.in_file {
+            self.current_file.push(unescape_file_line(line).to_string());
         } else {
-            remaining.push(line.to_string());
+            self.remaining.push(line.to_string());
         }
+        None
     }
 
-    if in_file {
-        return Err("Error: unexpected end of response while parsing a file section".to_string());
+    fn push_explanation(&mut self, line: &str) -> String {
+        self.explanations.push(line.to_string());
+        line.to_string()
     }
 
-    Ok((
-        explanations.join("\n"),
-        files_to_write,
-        remaining.join("\n"),
-    ))
+    /// Parses the unterminated last line and returns the whole response.
+    pub fn finish(mut self) -> Result<ParsedResponse, String> {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.feed_line(&line);
+        }
+        if self.in_file {
+            return Err(
+                "Error: unexpected end of response while parsing a file section".to_string(),
+            );
+        }
+
+        Ok(ParsedResponse {
+            explanation: self.explanations.join("\n"),
+            files: file_edits(self.files_to_write),
+            thinking: self.thinking.map(|lines| lines.join("\n")),
+            extra: self.remaining.join("\n"),
+        })
+    }
+}
+
+/// Removes the indentation common to all non-blank lines, trailing
+/// whitespace and leading/trailing blank lines of an explanation.
+pub fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.get(indent..).unwrap_or(line.trim_start()).trim_end())
+        .collect();
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+/// Keeps only the last block for each path, in the order of those last
+/// occurrences, and warns about paths the model emitted more than once.
+pub fn dedup_files(files: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut deduped: Vec<(String, String)> = Vec::new();
+    let mut duplicates: Vec<String> = Vec::new();
+    for (path, content) in files.into_iter().rev() {
+        if deduped.iter().any(|(seen, _)| *seen == path) {
+            if !duplicates.contains(&path) {
+                duplicates.push(path);
+            }
+            continue;
+        }
+        deduped.push((path, content));
+    }
+    deduped.reverse();
+    if !duplicates.is_empty() {
+        duplicates.reverse();
+        warn!(
+            "The response contains more than one block for: {} - using the last one",
+            duplicates.join(", ")
+        );
+    }
+    deduped
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_llm_response_keeps_last_duplicate() {
+        let resp_text = "<explain>\nplan\n</explain>\n\
+            <file path=\"a.rs\">\ndraft\n</file>\n\
+            <file path=\"b.rs\">\nb\n</file>\n\
+            <file path=\"a.rs\">\nfinal\n</file>";
+        let files = parse_llm_response(resp_text).unwrap().file_pairs();
+        assert_eq!(
+            files,
+            vec![
+                ("b.rs".to_string(), "b".to_string()),
+                ("a.rs".to_string(), "final".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_system_prompt() {
+        let rules = vec!["Use tabs".to_string()];
+        let prompt = build_system_prompt(&["a.rs".to_string()], &rules, &crate::format::TagParser);
+        let parts = split_system_prompt(&prompt);
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].starts_with("You are a non-interactive agent"));
+        assert!(parts[0].ends_with("consistent with existing code."));
+        assert_eq!(parts[1], "# Additional rules\n- Use tabs");
+        assert!(parts[2].starts_with("# Output format\n"));
+
+        let without_rules = build_system_prompt(&[], &[], &crate::format::TagParser);
+        assert_eq!(split_system_prompt(&without_rules).len(), 2);
+        assert_eq!(
+            split_system_prompt(&build_schema_system_prompt(&[])).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_strip_wrapping_fence() {
+        assert_eq!(
+            strip_wrapping_fence("src/main.rs", "```rust\nfn main() {}\n```"),
+            "fn main() {}"
+        );
+        assert_eq!(
+            strip_wrapping_fence("run.sh", "~~~~\necho ok\n~~~~\n"),
+            "echo ok\n"
+        );
+        // Fenced markdown is legitimate content.
+        let readme = "```sh\ncargo build\n```";
+        assert_eq!(strip_wrapping_fence("README.md", readme), readme);
+        // Two blocks, or a fence that isn't the whole file, stay as they are.
+        let two_blocks = "```\na\n```\n```\nb\n```";
+        assert_eq!(strip_wrapping_fence("a.txt", two_blocks), two_blocks);
+        let partial = "fn a() {}\n```\nb\n```";
+        assert_eq!(strip_wrapping_fence("a.rs", partial), partial);
+        let unclosed = "```rust\nfn main() {}";
+        assert_eq!(strip_wrapping_fence("a.rs", unclosed), unclosed);
+    }
+
     #[test]
     fn test_build_user_prompt_empty_files() {
         let instruction = "test";
         let files = vec![];
-        let prompt = build_user_prompt(instruction, &files, &None);
+        let prompt = build_user_prompt(instruction, &files, &None, None, None, &[], &[]);
         assert!(prompt.contains("# User instructions"));
         assert!(prompt.contains("test"));
         assert!(prompt.contains("# User input files:"));
+        assert!(!prompt.contains("<diff>"));
+    }
+
+    #[test]
+    fn test_build_user_prompt_with_diff() {
+        let patch = "diff --git a/a.rs b/a.rs\n+added line\n";
+        let prompt = build_user_prompt("review", &[], &None, Some(("main", patch)), None, &[], &[]);
+        assert!(prompt.contains("# Changes since main (read-only context):"));
+        assert!(prompt.contains("<diff>\ndiff --git a/a.rs b/a.rs\n+added line\n</diff>"));
+    }
+
+    #[test]
+    fn test_build_user_prompt_with_command_output() {
+        let outputs = vec![("cargo check 2>&1".to_string(), "error[E0308]\n".to_string())];
+        let prompt = build_user_prompt("fix", &[], &None, None, None, &outputs, &[]);
+        assert!(prompt.contains(
+            "# Output of `cargo check 2>&1` (read-only context):\n<command_output>\nerror[E0308]\n</command_output>"
+        ));
+    }
+
+    #[test]
+    fn test_build_user_prompt_with_tree() {
+        let files = vec![("src/a.rs".to_string(), "fn a() {}".to_string())];
+        let prompt = build_user_prompt(
+            "test",
+            &files,
+            &None,
+            None,
+            Some("src/\n  a.rs\n"),
+            &[],
+            &[],
+        );
+        let tree_pos = prompt
+            .find("# Project tree (paths only):\n<tree>\nsrc/\n  a.rs\n</tree>")
+            .unwrap();
+        assert!(tree_pos < prompt.find("<file path=").unwrap());
+    }
+
+    #[test]
+    fn test_build_user_prompt_with_file_diff() {
+        let files = vec![
+            ("src/a.rs".to_string(), "fn a() {}".to_string()),
+            ("src/b.rs".to_string(), "fn b() {}".to_string()),
+        ];
+        let diffs = vec![(
+            "src/a.rs".to_string(),
+            "-fn old() {}\n+fn a() {}\n".to_string(),
+        )];
+        let prompt = build_user_prompt("continue", &files, &None, None, None, &[], &diffs);
+        assert!(prompt.contains(
+            "<file path=\"src/a.rs\">\nfn a() {}\n</file>\n<diff path=\"src/a.rs\">\n-fn old() {}\n+fn a() {}\n</diff>\n<file path=\"src/b.rs\">"
+        ));
+        assert!(!prompt.contains("<diff path=\"src/b.rs\">"));
+    }
+
+    #[test]
+    fn test_build_user_prompt_skips_output_file() {
+        let files = vec![
+            ("src/a.rs".to_string(), "fn a() {}".to_string()),
+            ("out.md".to_string(), "old".to_string()),
+        ];
+        let prompt = build_user_prompt(
+            "test",
+            &files,
+            &Some("out.md".to_string()),
+            None,
+            None,
+            &[],
+            &[],
+        );
+        assert!(prompt.contains("<file path=\"src/a.rs\">\nfn a() {}\n</file>"));
+        assert!(!prompt.contains("out.md"));
+    }
+
+    #[test]
+    fn test_number_lines_roundtrip() {
+        let content = (1..=10)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let numbered = number_lines(&content);
+        assert!(numbered.starts_with(" 1| line 1\n 2| line 2"));
+        assert!(numbered.ends_with("10| line 10"));
+        assert_eq!(strip_line_numbers(&numbered), content);
+    }
+
+    #[test]
+    fn test_strip_line_numbers_keeps_unnumbered_content() {
+        let content = "1| looks numbered\nbut this is not";
+        assert_eq!(strip_line_numbers(content), content);
+        let content = "match x {\n    1| 2 => {}\n}";
+        assert_eq!(strip_line_numbers(content), content);
     }
 
     #[test]
     fn test_build_system_prompt_with_files() {
         let allowed_files = vec!["file1.rs".to_string()];
         let rules = vec![];
-        let prompt = build_system_prompt(&allowed_files, &rules);
+        let prompt = build_system_prompt(&allowed_files, &rules, &crate::format::TagParser);
         assert!(prompt.contains("file1.rs"));
         assert!(prompt.contains("You are a non-interactive agent"));
     }
@@ -226,15 +783,36 @@ mod tests {
             .join("\n");
         resp_text = string.as_str();
 
-        let (explanation, files, remaining) = parse_llm_response(resp_text).unwrap();
-        assert_eq!(explanation, "This is an explanation.");
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].0, "src/main.rs");
+        let parsed = parse_llm_response(resp_text).unwrap();
+        assert_eq!(parsed.explanation, "This is an explanation.");
+        assert_eq!(parsed.files.len(), 1);
+        assert_eq!(parsed.files[0].path, "src/main.rs");
         assert_eq!(
-            files[0].1,
+            parsed.files[0].content,
             "fn main() {\n    println!(\"Hello, world!\");\n}"
         );
-        assert_eq!(remaining, "This is remaining text.");
+        assert_eq!(parsed.extra, "This is remaining text.");
+    }
+
+    #[test]
+    fn test_parse_inline_explain_tags() {
+        let parsed = parse_llm_response(
+            "<explain>Renamed the helper.</explain>\n<file path=\"a.rs\">\nfn b() {}\n</file>",
+        )
+        .unwrap();
+        assert_eq!(parsed.explanation, "Renamed the helper.");
+        assert_eq!(parsed.files.len(), 1);
+
+        let parsed =
+            parse_llm_response("  <explain> First line.\n    Second line.</explain>\nrest")
+                .unwrap();
+        assert_eq!(parsed.explanation, "First line.\n    Second line.");
+        assert_eq!(parsed.extra, "rest");
+
+        assert_eq!(
+            dedent("\n    Changes:\n      - a.rs  \n\n    Done.\n"),
+            "Changes:\n  - a.rs\n\nDone."
+        );
     }
 
     #[test]
@@ -263,14 +841,70 @@ mod tests {
             .join("\n");
         resp_text = string.as_str();
 
-        let (explanation, files, remaining) = parse_llm_response(resp_text).unwrap();
-        assert_eq!(explanation, "This is an explanation.");
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].0, "src/main.rs");
+        let parsed = parse_llm_response(resp_text).unwrap();
+        assert_eq!(parsed.explanation, "This is an explanation.");
+        assert_eq!(parsed.files.len(), 1);
+        assert_eq!(parsed.files[0].path, "src/main.rs");
         assert_eq!(
-            files[0].1,
+            parsed.files[0].content,
             "fn main() {\n    println!(\"Hello, world!\");\n    println!(\"\\\n\n    <file name=\"test.txt\">\\\n\n    \");>println!(\"Hello, world!\");\n    println!(\"\\\\\n\n    </file>\\\n\n    !\");\n}"
         );
-        assert_eq!(remaining, "This is remaining text.");
+        assert_eq!(parsed.extra, "This is remaining text.");
+    }
+
+    #[test]
+    fn test_streaming_parser_emits_explanation_lines() {
+        let resp_text = "<think>\nplanning\n</think>\n<explain>\nFirst line.\r\nSecond line.\n</explain>\n<file path=\"a.txt\">\nhello\n</file>\ntrailing";
+        let mut parser = StreamingParser::default();
+        let mut emitted = Vec::new();
+        for chunk in resp_text.as_bytes().chunks(5) {
+            emitted.extend(parser.feed(std::str::from_utf8(chunk).unwrap()));
+        }
+        assert_eq!(emitted, vec!["First line.", "Second line."]);
+
+        let parsed = parser.finish().unwrap();
+        assert_eq!(parsed, parse_llm_response(resp_text).unwrap());
+        assert_eq!(
+            parsed.file_pairs(),
+            vec![("a.txt".to_string(), "hello".to_string())]
+        );
+        assert_eq!(parsed.thinking.as_deref(), Some("planning"));
+        assert_eq!(parsed.extra, "trailing");
+    }
+
+    #[test]
+    fn test_escaped_file_tags_roundtrip() {
+        let content = "let open = \"\n<file path=\\\"x\\\">\n</file>\n\\</file>\n\";\n";
+        let escaped = escape_file_content(content);
+        assert!(escaped.contains("\n\\<file path="));
+        assert!(escaped.contains("\n\\</file>\n\\\\</file>\n"));
+
+        let resp_text = format!(
+            "<explain>\nok\n</explain>\n<file path=\"src/llm.rs\">\n{}\n</file>\n",
+            escaped
+        );
+        let files = parse_llm_response(&resp_text).unwrap().file_pairs();
+        assert_eq!(files, vec![("src/llm.rs".to_string(), content.to_string())]);
+        assert_eq!(escape_file_content("plain\ntext"), "plain\ntext");
+    }
+
+    #[test]
+    fn test_redact_file_contents() {
+        let prompt =
+            "# User input files:\n<file path=\"a.rs\">\nfn main() {}\n\\</file>\n</file>\nafter\n";
+        assert_eq!(
+            redact_file_contents(prompt),
+            "# User input files:\n<file path=\"a.rs\">\n[REDACTED 22 bytes]\n</file>\nafter\n"
+        );
+        assert_eq!(
+            redact_file_contents("<file path=\"b.rs\">\nunterminated"),
+            "<file path=\"b.rs\">\n[REDACTED 12 bytes]"
+        );
+        assert_eq!(
+            redact_file_contents(
+                "<diff path=\"a.rs\">\n-old\n+new\n</diff>\n<command_output>\nsecret\n</command_output>\n"
+            ),
+            "<diff path=\"a.rs\">\n[REDACTED 10 bytes]\n</diff>\n<command_output>\n[REDACTED 7 bytes]\n</command_output>\n"
+        );
     }
 }
@@ -0,0 +1,185 @@
+use crate::{filecache, utils};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCKS_DIR: &str = "locks";
+const GLOBAL_LOCK_FILE: &str = ".lock";
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock on a lock file, released when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Drop for FileLock {
+    /// Removes the lock file while it is still locked, so lock files don't
+    /// pile up. A run waiting on the removed file notices and locks a new one.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Locks `path`, creating it if needed, and retries until `timeout` while
+/// another process holds it.
+pub async fn acquire(path: &Path, timeout: Duration) -> Result<FileLock, String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create lock directory: {}", e))?;
+    }
+
+    let start = Instant::now();
+    loop {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open lock file '{}': {}", path.display(), e))?;
+        match file.try_lock() {
+            Ok(()) if is_current(&file, path) => {
+                return Ok(FileLock {
+                    path: path.to_path_buf(),
+                    _file: file,
+                });
+            }
+            // The previous holder removed the file on release.
+            Ok(()) => continue,
+            Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                tokio::time::sleep(RETRY_INTERVAL).await
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(format!(
+                    "Another llmpal run still holds '{}' after {:.0}s",
+                    path.display(),
+                    timeout.as_secs_f64()
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("Failed to lock '{}': {}", path.display(), e));
+            }
+        }
+    }
+}
+
+/// Whether `file` is still the lock file at `path`, and not one removed since
+/// it was opened.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(locked), Ok(current)) => locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// The lock file for target `path`: `~/.llmpal/locks/<hash of absolute path>.lock`,
+/// so no lock files are left in the working tree.
+fn target_lock_path(locks_dir: &Path, path: &str) -> PathBuf {
+    let absolute = std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| PathBuf::from(path));
+    let key = utils::normalize_path(&absolute.to_string_lossy(), false);
+    locks_dir.join(format!("{}.lock", filecache::content_hash(&key)))
+}
+
+/// Locks every target file of the write phase, in a fixed order so two runs
+/// cannot deadlock. Nothing is locked when there is no home directory.
+pub async fn lock_targets(paths: &[&str], timeout: Duration) -> Result<Vec<FileLock>, String> {
+    let Some(locks_dir) = utils::llmpal_dir().map(|dir| dir.join(LOCKS_DIR)) else {
+        return Ok(Vec::new());
+    };
+    lock_targets_in(&locks_dir, paths, timeout).await
+}
+
+async fn lock_targets_in(
+    locks_dir: &Path,
+    paths: &[&str],
+    timeout: Duration,
+) -> Result<Vec<FileLock>, String> {
+    let mut lock_paths: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| target_lock_path(locks_dir, path))
+        .collect();
+    lock_paths.sort();
+    lock_paths.dedup();
+    let mut locks = Vec::new();
+    for lock_path in &lock_paths {
+        locks.push(acquire(lock_path, timeout).await?);
+    }
+    Ok(locks)
+}
+
+/// Locks `~/.llmpal/.lock`, held while session and cache files are written.
+/// `None` when there is no home directory.
+pub async fn lock_global() -> Result<Option<FileLock>, String> {
+    match utils::llmpal_dir() {
+        Some(dir) => acquire(&dir.join(GLOBAL_LOCK_FILE), DEFAULT_LOCK_TIMEOUT)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Runs `f` under the global lock, so concurrent runs don't interleave writes
+/// to the shared files in `~/.llmpal`.
+pub async fn with_global<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let _lock = lock_global().await?;
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_contention_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = lock_targets_in(dir.path(), &["src/a.rs", "./src/a.rs"], Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(held.len(), 1);
+
+        let err = lock_targets_in(dir.path(), &["src/b.rs", "src/a.rs"], Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(err.contains("Another llmpal run still holds"));
+        // The lock on b.rs taken before the failure was released again.
+        assert!(
+            lock_targets_in(dir.path(), &["src/b.rs"], Duration::ZERO)
+                .await
+                .is_ok()
+        );
+
+        drop(held);
+        assert!(
+            lock_targets_in(dir.path(), &["src/a.rs"], Duration::ZERO)
+                .await
+                .is_ok()
+        );
+        // Released locks leave no lock files behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_lock_file_removed_while_waiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.lock");
+        let held = acquire(&path, Duration::ZERO).await.unwrap();
+        // A waiter that opened the file before it was removed locks a new one.
+        let stale = File::open(&path).unwrap();
+        drop(held);
+        assert!(!is_current(&stale, &path));
+        let _lock = acquire(&path, Duration::ZERO).await.unwrap();
+        assert!(path.exists());
+    }
+}
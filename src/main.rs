@@ -1,13 +1,61 @@
 use clap::Parser;
 use std::process;
+use tracing_subscriber::EnvFilter;
 
-use llmpal::{app, config};
+use llmpal::{app, config, history, utils};
+
+fn init_logging(args: &config::Cli) {
+    let level = if args.trace {
+        "trace"
+    } else if args.verbose {
+        "debug"
+    } else {
+        "warn"
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("llmpal={}", level)));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .without_time()
+        .init();
+}
 
 #[tokio::main]
 async fn main() {
     let args = config::Cli::parse();
+    init_logging(&args);
+    utils::cleanup_old_dumps();
+
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            utils::finalize_dump();
+            // The spinner hides the cursor while it runs.
+            eprint!("\x1b[?25h");
+            process::exit(130);
+        }
+    });
+
+    let args = match args.redo {
+        Some(n) => history::replay_args(&args, n).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        None => args,
+    };
+    let request_id = app::new_request_id();
+    if !args.fetch_models
+        && !args.forecast
+        && !args.list_rules
+        && !args.list_templates
+        && args.init.is_none()
+    {
+        history::record(&args, &request_id).await;
+    }
 
-    if let Err(e) = app::run(&args).await {
+    if let Err(e) = app::run_with_request_id(&args, &request_id).await {
         eprintln!("{}", e);
         process::exit(1);
     }
@@ -0,0 +1,422 @@
+use crate::{config, lock, utils};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const PRICING_CACHE_FILE: &str = "pricing.json";
+const PRICING_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+const PROVIDERS_CACHE_FILE: &str = "providers.json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    pub prompt_cost: Option<f64>,
+    pub completion_cost: Option<f64>,
+    pub context_length: Option<usize>,
+}
+
+fn api_base(api_url: &str) -> &str {
+    let base = api_url.trim_end_matches('/');
+    base.strip_suffix("/chat/completions").unwrap_or(base)
+}
+
+pub fn models_url(api_url: &str) -> String {
+    format!("{}/models", api_base(api_url))
+}
+
+pub fn providers_url(api_url: &str) -> String {
+    format!("{}/providers", api_base(api_url))
+}
+
+fn per_million(value: &Value) -> Option<f64> {
+    let per_token = match value {
+        Value::String(s) => s.parse::<f64>().ok()?,
+        Value::Number(n) => n.as_f64()?,
+        _ => return None,
+    };
+    Some(per_token * 1_000_000.0)
+}
+
+pub fn parse_models(res: &Value) -> Vec<ModelInfo> {
+    let mut models: Vec<ModelInfo> = res["data"]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    let id = entry["id"].as_str()?.to_string();
+                    Some(ModelInfo {
+                        id,
+                        prompt_cost: per_million(&entry["pricing"]["prompt"]),
+                        completion_cost: per_million(&entry["pricing"]["completion"]),
+                        context_length: entry["context_length"].as_u64().map(|c| c as usize),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+    models
+}
+
+pub async fn fetch_models(api_url: &str, api_key: Option<&str>) -> Result<Vec<ModelInfo>, String> {
+    let res = fetch_json(&models_url(api_url), api_key, "models").await?;
+    Ok(parse_models(&res))
+}
+
+async fn fetch_json(url: &str, api_key: Option<&str>, what: &str) -> Result<Value, String> {
+    let client = reqwest::Client::builder()
+        .user_agent(config::USER_AGENT)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+    let mut request = client.get(url);
+    if let Some(key) = api_key {
+        request = request.header("Authorization", format!("Bearer {}", key));
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch {}: {}", what, e))?;
+
+    let status_code = response.status();
+    if !status_code.is_success() {
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read error response: {}", e))?;
+        return Err(format!(
+            "Request for {} failed with status {}: {}",
+            what, status_code, error_text
+        ));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse {} response: {}", what, e))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ModelPricing {
+    pub prompt_cost: f64,
+    pub completion_cost: f64,
+    #[serde(default)]
+    pub context_length: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PricingCacheEntry {
+    pub fetched_at: u64,
+    pub models: HashMap<String, ModelPricing>,
+}
+
+pub type PricingCache = HashMap<String, PricingCacheEntry>;
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn load_cache<T: DeserializeOwned + Default>(path: &Path) -> T {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache<T: Serialize>(path: &Path, cache: &T, what: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {} cache directory: {}", what, e))?;
+    }
+    let content = serde_json::to_string_pretty(cache)
+        .map_err(|e| format!("Failed to serialize {} cache: {}", what, e))?;
+    fs::write(path, content).map_err(|e| format!("Failed to write {} cache: {}", what, e))
+}
+
+pub fn load_pricing_cache(path: &Path) -> PricingCache {
+    load_cache(path)
+}
+
+pub fn save_pricing_cache(path: &Path, cache: &PricingCache) -> Result<(), String> {
+    save_cache(path, cache, "pricing")
+}
+
+pub fn pricing_entry(models: &[ModelInfo]) -> PricingCacheEntry {
+    PricingCacheEntry {
+        fetched_at: now_secs(),
+        models: models
+            .iter()
+            .filter_map(|m| {
+                Some((
+                    m.id.clone(),
+                    ModelPricing {
+                        prompt_cost: m.prompt_cost?,
+                        completion_cost: m.completion_cost?,
+                        context_length: m.context_length,
+                    },
+                ))
+            })
+            .collect(),
+    }
+}
+
+pub async fn lookup_pricing(
+    api_url: &str,
+    model: &str,
+    api_key: Option<&str>,
+) -> Option<ModelPricing> {
+    let url = models_url(api_url);
+    let cache_path = utils::llmpal_dir().map(|dir| dir.join(PRICING_CACHE_FILE));
+    let mut cache = cache_path
+        .as_deref()
+        .map(load_pricing_cache)
+        .unwrap_or_default();
+
+    if let Some(entry) = cache.get(&url)
+        && now_secs().saturating_sub(entry.fetched_at) < PRICING_CACHE_TTL_SECS
+    {
+        return entry.models.get(model).copied();
+    }
+
+    match fetch_models(api_url, api_key).await {
+        Ok(models) => {
+            let entry = pricing_entry(&models);
+            let pricing = entry.models.get(model).copied();
+            cache.insert(url, entry);
+            if let Some(path) = &cache_path
+                && let Err(e) = lock::with_global(|| save_pricing_cache(path, &cache)).await
+            {
+                eprintln!("{}", e);
+            }
+            pricing
+        }
+        Err(e) => {
+            eprintln!("> Warning: {}", e);
+            cache
+                .get(&url)
+                .and_then(|entry| entry.models.get(model).copied())
+        }
+    }
+}
+
+pub fn parse_providers(res: &Value) -> Vec<String> {
+    let mut providers: Vec<String> = res["data"]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .flat_map(|entry| [entry["slug"].as_str(), entry["name"].as_str()])
+                .flatten()
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    providers.sort();
+    providers.dedup();
+    providers
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ProvidersCacheEntry {
+    pub fetched_at: u64,
+    pub providers: Vec<String>,
+}
+
+pub type ProvidersCache = HashMap<String, ProvidersCacheEntry>;
+
+/// Returns the provider names and slugs known to the API, cached in
+/// `~/.llmpal/providers.json` for as long as the pricing cache.
+pub async fn lookup_providers(api_url: &str, api_key: Option<&str>) -> Result<Vec<String>, String> {
+    let url = providers_url(api_url);
+    let cache_path = utils::llmpal_dir().map(|dir| dir.join(PROVIDERS_CACHE_FILE));
+    let mut cache: ProvidersCache = cache_path.as_deref().map(load_cache).unwrap_or_default();
+
+    if let Some(entry) = cache.get(&url)
+        && now_secs().saturating_sub(entry.fetched_at) < PRICING_CACHE_TTL_SECS
+    {
+        return Ok(entry.providers.clone());
+    }
+
+    match fetch_json(&url, api_key, "providers").await {
+        Ok(res) => {
+            let providers = parse_providers(&res);
+            cache.insert(
+                url,
+                ProvidersCacheEntry {
+                    fetched_at: now_secs(),
+                    providers: providers.clone(),
+                },
+            );
+            if let Some(path) = &cache_path
+                && let Err(e) = lock::with_global(|| save_cache(path, &cache, "providers")).await
+            {
+                eprintln!("{}", e);
+            }
+            Ok(providers)
+        }
+        Err(e) => match cache.remove(&url) {
+            Some(entry) => {
+                eprintln!("> Warning: {}", e);
+                Ok(entry.providers)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Finds `name` among `known` (case-insensitively). On a miss, returns the
+/// closest known name as a suggestion, if any is reasonably close.
+pub fn match_provider<'a>(name: &str, known: &'a [String]) -> Result<(), Option<&'a str>> {
+    let name = name.to_lowercase();
+    if known.iter().any(|k| k.to_lowercase() == name) {
+        return Ok(());
+    }
+    Err(known
+        .iter()
+        .map(|k| (edit_distance(&name, &k.to_lowercase()), k))
+        .filter(|(distance, k)| *distance <= (k.len() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, k)| k.as_str()))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            curr.push(substitution.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+pub fn format_model(model: &ModelInfo) -> String {
+    let cost = |c: Option<f64>| c.map_or("-".to_string(), |c| format!("${:.4}", c));
+    format!(
+        "{} | Cost: {}/1M prompt, {}/1M completion",
+        model.id,
+        cost(model.prompt_cost),
+        cost(model.completion_cost)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_models_url() {
+        assert_eq!(
+            models_url("https://openrouter.ai/api/v1/chat/completions"),
+            "https://openrouter.ai/api/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:8080/v1/"),
+            "http://localhost:8080/v1/models"
+        );
+    }
+
+    #[test]
+    fn test_parse_models() {
+        let res = serde_json::json!({
+            "data": [
+                {
+                    "id": "z-ai/model",
+                    "pricing": { "prompt": "0.0000006", "completion": "0.0000025" },
+                    "context_length": 131072
+                },
+                { "id": "local-model" },
+                { "name": "missing id" }
+            ]
+        });
+        let models = parse_models(&res);
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].id, "local-model");
+        assert_eq!(models[0].prompt_cost, None);
+        assert_eq!(models[1].id, "z-ai/model");
+        assert!((models[1].prompt_cost.unwrap() - 0.6).abs() < 1e-9);
+        assert!((models[1].completion_cost.unwrap() - 2.5).abs() < 1e-9);
+        assert_eq!(models[1].context_length, Some(131072));
+        assert_eq!(
+            format_model(&models[0]),
+            "local-model | Cost: -/1M prompt, -/1M completion"
+        );
+    }
+
+    #[test]
+    fn test_pricing_cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(PRICING_CACHE_FILE);
+        let models = vec![
+            ModelInfo {
+                id: "priced".to_string(),
+                prompt_cost: Some(0.6),
+                completion_cost: Some(2.5),
+                context_length: Some(131072),
+            },
+            ModelInfo {
+                id: "unpriced".to_string(),
+                prompt_cost: None,
+                completion_cost: None,
+                context_length: None,
+            },
+        ];
+
+        let mut cache = PricingCache::new();
+        cache.insert(
+            "https://example.com/v1/models".to_string(),
+            pricing_entry(&models),
+        );
+        save_pricing_cache(&path, &cache).unwrap();
+
+        let loaded = load_pricing_cache(&path);
+        let entry = loaded.get("https://example.com/v1/models").unwrap();
+        assert_eq!(entry.models.len(), 1);
+        assert_eq!(
+            entry.models.get("priced"),
+            Some(&ModelPricing {
+                prompt_cost: 0.6,
+                completion_cost: 2.5,
+                context_length: Some(131072)
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_providers() {
+        let res = serde_json::json!({
+            "data": [
+                { "name": "Fireworks", "slug": "fireworks" },
+                { "name": "DeepInfra", "slug": "deepinfra" },
+                { "privacy_policy_url": null }
+            ]
+        });
+        assert_eq!(
+            parse_providers(&res),
+            vec!["DeepInfra", "Fireworks", "deepinfra", "fireworks"]
+        );
+        assert_eq!(
+            providers_url("https://openrouter.ai/api/v1/chat/completions"),
+            "https://openrouter.ai/api/v1/providers"
+        );
+    }
+
+    #[test]
+    fn test_match_provider() {
+        let known = vec!["Fireworks".to_string(), "deepinfra".to_string()];
+        assert_eq!(match_provider("fireworks", &known), Ok(()));
+        assert_eq!(match_provider("fireworx", &known), Err(Some("Fireworks")));
+        assert_eq!(match_provider("deep-infra", &known), Err(Some("deepinfra")));
+        assert_eq!(match_provider("together", &known), Err(None));
+    }
+}
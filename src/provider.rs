@@ -0,0 +1,213 @@
+use crate::app::{self, LlmpalError, RequestOptions};
+use crate::config::ApiFormat;
+use crate::tools::{self, ToolCall};
+use crate::usage::Usage;
+use serde_json::{Value, json};
+
+/// The wire format of a backend: how a request body is built and how the
+/// generated text and token usage are read back from its response.
+pub trait Provider {
+    fn build_body(
+        &self,
+        options: &RequestOptions,
+        system_prompt: &str,
+        user_prompt: &str,
+    ) -> Result<String, LlmpalError>;
+
+    /// The generated text and the token usage (zero when not reported).
+    fn extract(&self, res: &Value) -> Result<(String, Usage), LlmpalError>;
+
+    /// Whether the completion was cut off at max_tokens.
+    fn is_truncated(&self, res: &Value) -> bool;
+
+    /// Replaces the generated text of `res`, e.g. with joined continuations.
+    fn set_text(&self, res: &mut Value, text: &str);
+
+    /// `body` with the model's `answer` and a follow-up user message appended.
+    fn with_follow_up(
+        &self,
+        body: &str,
+        answer: &str,
+        follow_up: &str,
+    ) -> Result<String, LlmpalError>;
+
+    /// The tool calls the model made in `res`.
+    fn tool_calls(&self, res: &Value) -> Vec<ToolCall>;
+
+    /// `body` with the model's turn from `res` appended, followed by one
+    /// `(tool call id, result)` answer per call.
+    fn with_tool_results(
+        &self,
+        body: &str,
+        res: &Value,
+        results: &[(String, String)],
+    ) -> Result<String, LlmpalError>;
+}
+
+/// The OpenAI chat completions schema, as spoken by OpenRouter and most
+/// OpenAI-compatible servers.
+pub struct OpenRouterProvider;
+
+impl Provider for OpenRouterProvider {
+    fn build_body(
+        &self,
+        options: &RequestOptions,
+        system_prompt: &str,
+        user_prompt: &str,
+    ) -> Result<String, LlmpalError> {
+        app::build_request(options, system_prompt, user_prompt)
+            .map_err(|e| LlmpalError::SerializeError(e.to_string()))
+    }
+
+    fn extract(&self, res: &Value) -> Result<(String, Usage), LlmpalError> {
+        let message = &res["choices"][0]["message"];
+        // A message that only calls tools has no content.
+        let text = match &message["content"] {
+            Value::String(text) => text.as_str(),
+            Value::Null if message["tool_calls"].is_array() => "",
+            _ => {
+                return Err(LlmpalError::ParseError(
+                    "Invalid response format from API".to_string(),
+                ));
+            }
+        };
+        Ok((
+            text.to_string(),
+            Usage::from_response(res).unwrap_or_default(),
+        ))
+    }
+
+    fn is_truncated(&self, res: &Value) -> bool {
+        res["choices"][0]["finish_reason"].as_str() == Some("length")
+    }
+
+    fn set_text(&self, res: &mut Value, text: &str) {
+        res["choices"][0]["message"]["content"] = json!(text);
+    }
+
+    fn with_follow_up(
+        &self,
+        body: &str,
+        answer: &str,
+        follow_up: &str,
+    ) -> Result<String, LlmpalError> {
+        let mut request = parse_body(body)?;
+        if let Some(messages) = request["messages"].as_array_mut() {
+            messages.push(json!({ "role": "assistant", "content": answer }));
+            messages.push(json!({ "role": "user", "content": follow_up }));
+        }
+        Ok(request.to_string())
+    }
+
+    fn tool_calls(&self, res: &Value) -> Vec<ToolCall> {
+        tools::tool_calls(&res["choices"][0]["message"])
+    }
+
+    fn with_tool_results(
+        &self,
+        body: &str,
+        res: &Value,
+        results: &[(String, String)],
+    ) -> Result<String, LlmpalError> {
+        let mut request = parse_body(body)?;
+        if let Some(messages) = request["messages"].as_array_mut() {
+            messages.push(res["choices"][0]["message"].clone());
+            messages.extend(results.iter().map(
+                |(id, result)| json!({ "role": "tool", "tool_call_id": id, "content": result }),
+            ));
+        }
+        Ok(request.to_string())
+    }
+}
+
+fn parse_body(body: &str) -> Result<Value, LlmpalError> {
+    serde_json::from_str(body).map_err(|e| LlmpalError::SerializeError(e.to_string()))
+}
+
+/// The provider for a model's `api_format`.
+pub fn for_format(format: ApiFormat) -> Box<dyn Provider> {
+    match format {
+        ApiFormat::OpenRouter => Box::new(OpenRouterProvider),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_openrouter_provider() {
+        let provider = for_format(ApiFormat::default());
+        let options = RequestOptions {
+            model: "m",
+            ..Default::default()
+        };
+        let body: Value =
+            serde_json::from_str(&provider.build_body(&options, "sys", "user").unwrap()).unwrap();
+        assert_eq!(body["model"], "m");
+        assert_eq!(body["messages"][1]["content"], "user");
+
+        let res = json!({
+            "choices": [{ "message": { "content": "<explain>\nok\n</explain>" } }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 3 }
+        });
+        let (text, usage) = provider.extract(&res).unwrap();
+        assert_eq!(text, "<explain>\nok\n</explain>");
+        assert_eq!(usage.total_tokens(), 15);
+
+        assert!(matches!(
+            provider.extract(&json!({ "error": "overloaded" })),
+            Err(LlmpalError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_openrouter_conversation() {
+        let provider = OpenRouterProvider;
+        let body =
+            json!({ "model": "m", "messages": [{ "role": "user", "content": "go" }] }).to_string();
+
+        let follow_up: Value =
+            serde_json::from_str(&provider.with_follow_up(&body, "half", "more").unwrap()).unwrap();
+        assert_eq!(
+            follow_up["messages"],
+            json!([
+                { "role": "user", "content": "go" },
+                { "role": "assistant", "content": "half" },
+                { "role": "user", "content": "more" }
+            ])
+        );
+
+        let mut res = json!({ "choices": [{
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "write_file", "arguments": "{}" }
+                }]
+            },
+            "finish_reason": "length"
+        }]});
+        assert!(provider.is_truncated(&res));
+        assert_eq!(provider.extract(&res).unwrap().0, "");
+        assert_eq!(provider.tool_calls(&res)[0].id, "call_1");
+
+        let next: Value = serde_json::from_str(
+            &provider
+                .with_tool_results(&body, &res, &[("call_1".to_string(), "ok".to_string())])
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(next["messages"][1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(
+            next["messages"][2],
+            json!({ "role": "tool", "tool_call_id": "call_1", "content": "ok" })
+        );
+
+        provider.set_text(&mut res, "joined");
+        assert_eq!(provider.extract(&res).unwrap().0, "joined");
+    }
+}
@@ -0,0 +1,100 @@
+use crate::app::LlmpalError;
+use serde_json::Value;
+use std::fs;
+
+/// Reads a JSON schema file and checks that it is a valid schema.
+pub fn load_schema(path: &str) -> Result<Value, LlmpalError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| LlmpalError::FileError(format!("Cannot read schema '{}': {}", path, e)))?;
+    let schema: Value = serde_json::from_str(&content)
+        .map_err(|e| LlmpalError::UsageError(format!("Schema '{}' is not JSON: {}", path, e)))?;
+    jsonschema::validator_for(&schema)
+        .map_err(|e| LlmpalError::UsageError(format!("Invalid schema '{}': {}", path, e)))?;
+    Ok(schema)
+}
+
+/// The `response_format` request parameter constraining output to `schema`.
+pub fn response_format(schema: &Value) -> Value {
+    serde_json::json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "output",
+            "strict": true,
+            "schema": schema
+        }
+    })
+}
+
+/// Parses the model output as JSON and validates it against `schema`.
+pub fn parse_schema_response(schema: &Value, resp_text: &str) -> Result<Value, String> {
+    let value: Value = serde_json::from_str(strip_code_fence(resp_text))
+        .map_err(|e| format!("response is not valid JSON: {}", e))?;
+    let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+    let errors: Vec<String> = validator
+        .iter_errors(&value)
+        .map(|e| e.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(format!(
+            "response does not match the schema: {}",
+            errors.join("; ")
+        ));
+    }
+    Ok(value)
+}
+
+/// `text` without a code fence wrapped around all of it.
+pub fn strip_code_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    match trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    {
+        Some(inner) => inner.split_once('\n').map_or(inner, |(_, body)| body),
+        None => trimmed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn country_schema() -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "code": { "type": "string" }
+            },
+            "required": ["name", "code"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn test_parse_schema_response_valid() {
+        let value = parse_schema_response(&country_schema(), r#"{"name": "Poland", "code": "PL"}"#)
+            .unwrap();
+        assert_eq!(value["code"], "PL");
+    }
+
+    #[test]
+    fn test_parse_schema_response_fenced() {
+        let resp_text = "```json\n{\"name\": \"Poland\", \"code\": \"PL\"}\n```";
+        assert!(parse_schema_response(&country_schema(), resp_text).is_ok());
+    }
+
+    #[test]
+    fn test_parse_schema_response_invalid() {
+        let err = parse_schema_response(&country_schema(), r#"{"name": "Poland"}"#).unwrap_err();
+        assert!(err.contains("does not match the schema"));
+        assert!(parse_schema_response(&country_schema(), "not json").is_err());
+    }
+
+    #[test]
+    fn test_response_format() {
+        let format = response_format(&country_schema());
+        assert_eq!(format["type"], "json_schema");
+        assert_eq!(format["json_schema"]["schema"], country_schema());
+    }
+}
@@ -1,30 +1,147 @@
 use std::{
+    io::{IsTerminal, Write},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-pub fn setup_spinner(
-    loading: Arc<AtomicBool>,
-    message: Option<&'static str>,
-) -> thread::JoinHandle<()> {
-    let loading_thread = loading.clone();
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns the spinner off for the rest of the process (`--no-spinner`).
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// CI logs keep every spinner frame, so a `CI` variable other than
+/// `false`/`0` disables the spinner even when a pseudo-TTY is attached.
+fn is_ci(value: Option<&str>) -> bool {
+    value.is_some_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+fn enabled() -> bool {
+    !DISABLED.load(Ordering::Relaxed)
+        && !is_ci(std::env::var("CI").ok().as_deref())
+        && std::io::stderr().is_terminal()
+}
+
+/// The running spinner, or nothing when it is disabled.
+pub struct SpinnerHandle(Option<thread::JoinHandle<()>>);
+
+impl SpinnerHandle {
+    pub fn join(self) {
+        if let Some(handle) = self.0 {
+            handle.join().unwrap();
+        }
+    }
+}
+
+/// Animates `message` on stderr until `loading` turns false. When disabled,
+/// only prints the message once to stderr and spawns no thread.
+pub fn setup_spinner(loading: Arc<AtomicBool>, message: Option<&'static str>) -> SpinnerHandle {
     let message = message.unwrap_or("");
-    thread::spawn(move || {
+    if !enabled() {
+        if !message.is_empty() {
+            eprintln!("# {}...", message);
+        }
+        return SpinnerHandle(None);
+    }
+    let loading_thread = loading.clone();
+    SpinnerHandle(Some(thread::spawn(move || {
         const FRAMES: [char; 8] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧'];
         let mut idx = 0;
-        print!("\x1b[?25l");
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        eprint!("\x1b[?25l");
         while loading_thread.load(Ordering::Relaxed) {
-            print!("\r[{}] {}\r\x1b[0m", FRAMES[idx], message);
-            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+            eprint!("\r[{}] {}\r\x1b[0m", FRAMES[idx], message);
             idx = (idx + 1) % FRAMES.len();
             thread::sleep(Duration::from_millis(100));
         }
-        print!("\r \r\x1b[?25h");
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
-    })
+        eprint!("\r \r\x1b[?25h");
+    })))
+}
+
+/// A "Collecting files..." line on stderr, redrawn at most every 100ms while
+/// input files are read. Nothing is printed for quick collections or when
+/// the spinner is disabled (`--no-spinner`, `CI`, or stderr not a terminal).
+pub struct FileProgress {
+    files: usize,
+    bytes: usize,
+    last_update: Instant,
+    enabled: bool,
+    shown: bool,
+}
+
+impl FileProgress {
+    pub fn new() -> Self {
+        FileProgress {
+            files: 0,
+            bytes: 0,
+            last_update: Instant::now(),
+            enabled: enabled(),
+            shown: false,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.files += 1;
+        self.bytes += bytes;
+        if self.enabled && self.last_update.elapsed() >= PROGRESS_INTERVAL {
+            self.last_update = Instant::now();
+            self.shown = true;
+            eprint!("\r{}", progress_line(self.files, self.bytes));
+            std::io::stderr().flush().ok();
+        }
+    }
+
+    pub fn finish(self) {
+        if self.shown {
+            eprintln!("\r{}", progress_line(self.files, self.bytes));
+        }
+    }
+}
+
+impl Default for FileProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn progress_line(files: usize, bytes: usize) -> String {
+    const KB: usize = 1024;
+    const MB: usize = 1024 * KB;
+    let size = if bytes >= MB {
+        format!("{}MB", bytes / MB)
+    } else if bytes >= KB {
+        format!("{}KB", bytes / KB)
+    } else {
+        format!("{}B", bytes)
+    };
+    format!("Collecting files... {} found, {}", files, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ci() {
+        assert!(is_ci(Some("true")));
+        assert!(is_ci(Some("1")));
+        assert!(!is_ci(Some("false")));
+        assert!(!is_ci(Some("")));
+        assert!(!is_ci(None));
+    }
+
+    #[test]
+    fn test_progress_line() {
+        assert_eq!(progress_line(3, 512), "Collecting files... 3 found, 512B");
+        assert_eq!(
+            progress_line(1243, 38 * 1024 * 1024 + 10),
+            "Collecting files... 1243 found, 38MB"
+        );
+    }
 }
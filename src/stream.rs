@@ -0,0 +1,237 @@
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct StreamAccumulator {
+    pub content: String,
+    pub usage: Option<Value>,
+    pub model: Option<String>,
+    pub provider: Option<String>,
+    pub finish_reason: Option<String>,
+    pub done: bool,
+    /// When the first content delta arrived.
+    pub first_token: Option<Instant>,
+    pending: Vec<u8>,
+}
+
+impl StreamAccumulator {
+    /// Buffers raw bytes and decodes only complete lines, so a multi-byte
+    /// character split across network chunks is not mangled.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut deltas = Vec::new();
+
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.pending.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&raw[..pos]);
+            if let Some(delta) = self.feed_line(line.trim_end_matches('\r')) {
+                deltas.push(delta);
+            }
+        }
+        deltas
+    }
+
+    fn feed_line(&mut self, line: &str) -> Option<String> {
+        let data = line.strip_prefix("data:")?.trim();
+        if data == "[DONE]" {
+            self.done = true;
+            return None;
+        }
+
+        let event: Value = serde_json::from_str(data).ok()?;
+        if let Some(model) = event["model"].as_str() {
+            self.model = Some(model.to_string());
+        }
+        if let Some(provider) = event["provider"].as_str() {
+            self.provider = Some(provider.to_string());
+        }
+        if event["usage"].is_object() {
+            self.usage = Some(event["usage"].clone());
+        }
+        if let Some(reason) = event["choices"][0]["finish_reason"].as_str() {
+            self.finish_reason = Some(reason.to_string());
+        }
+
+        let delta = event["choices"][0]["delta"]["content"].as_str()?;
+        if self.first_token.is_none() && !delta.is_empty() {
+            self.first_token = Some(Instant::now());
+        }
+        self.content.push_str(delta);
+        Some(delta.to_string())
+    }
+
+    pub fn into_response(self) -> Value {
+        let mut res = serde_json::json!({
+            "choices": [{
+                "message": { "role": "assistant", "content": self.content },
+                "finish_reason": self.finish_reason,
+            }],
+        });
+        if let Some(usage) = self.usage {
+            res["usage"] = usage;
+        }
+        if let Some(model) = self.model {
+            res["model"] = Value::String(model);
+        }
+        if let Some(provider) = self.provider {
+            res["provider"] = Value::String(provider);
+        }
+        res
+    }
+}
+
+pub async fn send_streaming_request(
+    client: &reqwest::Client,
+    api_key: &str,
+    api_url: &str,
+    body: &str,
+    idle_timeout: Option<Duration>,
+) -> Result<(Value, usize), String> {
+    let (accumulator, received_bytes) =
+        read_stream(client, api_key, api_url, body, idle_timeout, &mut |_| {}).await?;
+    Ok((accumulator.into_response(), received_bytes))
+}
+
+/// Sends a streaming request, passing each content delta to `on_delta`, and
+/// returns the accumulated stream, which also records when the first token arrived.
+pub async fn read_stream(
+    client: &reqwest::Client,
+    api_key: &str,
+    api_url: &str,
+    body: &str,
+    idle_timeout: Option<Duration>,
+    on_delta: &mut (dyn FnMut(&str) + Send),
+) -> Result<(StreamAccumulator, usize), String> {
+    let mut response = client
+        .post(api_url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to send request: {}", e))?;
+
+    let status_code = response.status();
+    if !status_code.is_success() {
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read error response: {}", e))?;
+        return Err(format!(
+            "API request failed with status {}: {}",
+            status_code, error_text
+        ));
+    }
+
+    let mut accumulator = StreamAccumulator::default();
+    let mut received_bytes = 0;
+
+    while !accumulator.done {
+        let next_chunk = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, response.chunk()).await {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    if accumulator.content.is_empty() {
+                        return Err(format!(
+                            "Stream stalled: no data received for {}s",
+                            idle.as_secs()
+                        ));
+                    }
+                    eprintln!(
+                        "> Warning: Stream stalled for {}s. Using the partial response.",
+                        idle.as_secs()
+                    );
+                    break;
+                }
+            },
+            None => response.chunk().await,
+        };
+
+        match next_chunk.map_err(|e| format!("Failed to read stream: {}", e))? {
+            Some(chunk) => {
+                received_bytes += chunk.len();
+                for delta in accumulator.feed(&chunk) {
+                    on_delta(&delta);
+                }
+            }
+            None => break,
+        }
+    }
+
+    Ok((accumulator, received_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_accumulator() {
+        let mut acc = StreamAccumulator::default();
+        acc.feed(b": OPENROUTER PROCESSING\n\n");
+        let deltas = acc.feed(
+            b"data: {\"model\":\"m\",\"choices\":[{\"delta\":{\"content\":\"<explain>\\n\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}",
+        );
+        assert_eq!(deltas, vec!["<explain>\n"]);
+        assert!(acc.first_token.is_some());
+        acc.feed(b"}]}\n\n");
+        acc.feed(b"data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2}}\n\n");
+        acc.feed(b"data: [DONE]\n\n");
+        assert!(acc.done);
+
+        let res = acc.into_response();
+        assert_eq!(res["choices"][0]["message"]["content"], "<explain>\nHi");
+        assert_eq!(res["choices"][0]["finish_reason"], "stop");
+        assert_eq!(res["usage"]["prompt_tokens"], 3);
+        assert_eq!(res["model"], "m");
+    }
+
+    #[test]
+    fn test_stream_accumulator_split_multibyte_char() {
+        let line = "data: {\"choices\":[{\"delta\":{\"content\":\"caf\u{e9} \u{1f980}\"}}]}\n\n";
+        let split = line.find('\u{1f980}').unwrap() + 2;
+
+        let mut acc = StreamAccumulator::default();
+        assert!(acc.feed(&line.as_bytes()[..split]).is_empty());
+        let deltas = acc.feed(&line.as_bytes()[split..]);
+        assert_eq!(deltas, vec!["caf\u{e9} \u{1f980}"]);
+        assert_eq!(acc.content, "caf\u{e9} \u{1f980}");
+    }
+
+    #[tokio::test]
+    async fn test_send_streaming_request() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(
+                "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+                 data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+                 data: [DONE]\n\n",
+            )
+            .create_async()
+            .await;
+
+        let (res, bytes) =
+            send_streaming_request(&reqwest::Client::new(), "key", &server.url(), "{}", None)
+                .await
+                .unwrap();
+        assert_eq!(res["choices"][0]["message"]["content"], "Hello world");
+        assert!(bytes > 0);
+
+        let mut deltas = Vec::new();
+        let (accumulator, _) = read_stream(
+            &reqwest::Client::new(),
+            "key",
+            &server.url(),
+            "{}",
+            None,
+            &mut |delta| deltas.push(delta.to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(deltas, vec!["Hello", " world"]);
+        assert!(accumulator.first_token.is_some());
+    }
+}
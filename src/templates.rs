@@ -0,0 +1,99 @@
+use crate::utils;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TEMPLATES_DIR: &str = "templates";
+const TEMPLATE_EXTENSION: &str = "txt";
+pub const ARGS_PLACEHOLDER: &str = "{{args}}";
+
+/// `~/.llmpal/templates`, where `--template` instructions are stored.
+pub fn templates_dir() -> Option<PathBuf> {
+    utils::llmpal_dir().map(|dir| dir.join(TEMPLATES_DIR))
+}
+
+/// Names of the `*.txt` templates in `dir`, sorted; empty when it doesn't exist.
+pub fn list(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == TEMPLATE_EXTENSION)
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// The instruction of template `name`, with `{{args}}` replaced by `args`.
+/// Without the placeholder, `args` is appended as a separate paragraph.
+pub fn render(dir: &Path, name: &str, args: Option<&str>) -> Result<String, String> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("Invalid template name '{}'", name));
+    }
+    let path = dir.join(format!("{}.{}", name, TEMPLATE_EXTENSION));
+    let template = fs::read_to_string(&path).map_err(|e| {
+        let available = list(dir);
+        format!(
+            "Cannot read template '{}' ({}): {}. Available templates: {}",
+            name,
+            path.display(),
+            e,
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        )
+    })?;
+    let template = template.trim_end();
+
+    Ok(match args {
+        Some(args) if template.contains(ARGS_PLACEHOLDER) => {
+            template.replace(ARGS_PLACEHOLDER, args)
+        }
+        Some(args) => format!("{}\n\n{}", template, args),
+        None => template.replace(ARGS_PLACEHOLDER, ""),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_and_render() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("rustdoc.txt"),
+            "Add rustdoc to every public item of {{args}}.\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("tests.txt"), "Write table-driven tests.").unwrap();
+        fs::write(dir.path().join("notes.md"), "not a template").unwrap();
+
+        assert_eq!(list(dir.path()), vec!["rustdoc", "tests"]);
+        assert_eq!(
+            render(dir.path(), "rustdoc", Some("src/app.rs")).unwrap(),
+            "Add rustdoc to every public item of src/app.rs."
+        );
+        assert_eq!(
+            render(dir.path(), "tests", Some("Cover the error paths.")).unwrap(),
+            "Write table-driven tests.\n\nCover the error paths."
+        );
+        assert_eq!(
+            render(dir.path(), "tests", None).unwrap(),
+            "Write table-driven tests."
+        );
+
+        let missing = render(dir.path(), "docs", None).unwrap_err();
+        assert!(missing.contains("Available templates: rustdoc, tests"));
+        assert!(render(dir.path(), "../secrets", None).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+pub const WRITE_FILE: &str = "write_file";
+/// Requests in one tool-calling conversation, including the first.
+pub const MAX_TOOL_STEPS: usize = 8;
+
+/// The `tools` request parameter declaring `write_file(path, content)`.
+pub fn definitions() -> Value {
+    json!([{
+        "type": "function",
+        "function": {
+            "name": WRITE_FILE,
+            "description": "Writes the full content of a file you are allowed to modify or create.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Path of the file, exactly as listed in the prompt"
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "The full new content of the file"
+                    }
+                },
+                "required": ["path", "content"]
+            }
+        }
+    }])
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON-encoded arguments, as sent by the model.
+    pub arguments: String,
+}
+
+/// The function calls in `choices[0].message.tool_calls` of a response message.
+pub fn tool_calls(message: &Value) -> Vec<ToolCall> {
+    message["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|call| ToolCall {
+            id: call["id"].as_str().unwrap_or_default().to_string(),
+            name: call["function"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            arguments: call["function"]["arguments"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct WriteFileArgs {
+    path: String,
+    content: String,
+}
+
+/// The `(path, content)` of a `write_file` call; `Err` is sent back to the
+/// model as the tool result.
+pub fn write_file_args(call: &ToolCall) -> Result<(String, String), String> {
+    if call.name != WRITE_FILE {
+        return Err(format!(
+            "Error: unknown tool '{}'; only {} is available",
+            call.name, WRITE_FILE
+        ));
+    }
+    serde_json::from_str::<WriteFileArgs>(&call.arguments)
+        .map(|args| (args.path, args.content))
+        .map_err(|e| format!("Error: invalid {} arguments: {}", WRITE_FILE, e))
+}
+
+/// The outcome of a tool conversation in the layout of the `json` response
+/// format, so it is checked and written like any other response.
+pub fn response_text(explanation: &str, files: &[(String, String)]) -> String {
+    json!({
+        "explanation": explanation,
+        "files": files
+            .iter()
+            .map(|(path, content)| json!({ "path": path, "content": content }))
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_file_calls() {
+        let message = json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [
+                {
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {
+                        "name": "write_file",
+                        "arguments": "{\"path\": \"src/a.rs\", \"content\": \"fn a() {}\\n\"}"
+                    }
+                },
+                {
+                    "id": "call_2",
+                    "type": "function",
+                    "function": { "name": "delete_file", "arguments": "{}" }
+                }
+            ]
+        });
+        let calls = tool_calls(&message);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(
+            write_file_args(&calls[0]),
+            Ok(("src/a.rs".to_string(), "fn a() {}\n".to_string()))
+        );
+        assert!(
+            write_file_args(&calls[1])
+                .unwrap_err()
+                .contains("unknown tool")
+        );
+        assert!(tool_calls(&json!({ "content": "done" })).is_empty());
+
+        let text = response_text("Done.", &[("a.rs".to_string(), "x".to_string())]);
+        assert_eq!(
+            serde_json::from_str::<Value>(&text).unwrap(),
+            json!({ "explanation": "Done.", "files": [{ "path": "a.rs", "content": "x" }] })
+        );
+    }
+}
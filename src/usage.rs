@@ -0,0 +1,346 @@
+use crate::config::ModelConfig;
+use serde::Deserialize;
+use std::ops::AddAssign;
+
+/// Token usage as reported in the `usage` object of a chat completion response.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(from = "RawUsage")]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Part of `completion_tokens` spent on reasoning; 0 when not reported.
+    pub reasoning_tokens: u64,
+    /// Part of `prompt_tokens` served from the provider's prompt cache; 0 when not reported.
+    pub cached_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct RawUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    #[serde(default)]
+    completion_tokens_details: Option<CompletionTokensDetails>,
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: Option<u64>,
+}
+
+impl From<RawUsage> for Usage {
+    fn from(raw: RawUsage) -> Self {
+        Usage {
+            prompt_tokens: raw.prompt_tokens,
+            completion_tokens: raw.completion_tokens,
+            reasoning_tokens: raw
+                .completion_tokens_details
+                .and_then(|details| details.reasoning_tokens)
+                .unwrap_or_default(),
+            cached_tokens: raw
+                .prompt_tokens_details
+                .and_then(|details| details.cached_tokens)
+                .unwrap_or_default()
+                .min(raw.prompt_tokens),
+        }
+    }
+}
+
+/// Dollar cost of a request, split like the run summary.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Cost {
+    pub prompt: f64,
+    pub completion: f64,
+    pub total: f64,
+}
+
+impl Usage {
+    /// The usage of `res`, or `None` when the response reports no prompt or
+    /// completion token count.
+    pub fn from_response(res: &serde_json::Value) -> Option<Usage> {
+        Usage::deserialize(&res["usage"]).ok()
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Uncached prompt tokens are billed at `prompt_cost`, cached ones at
+    /// `cached_prompt_cost`.
+    pub fn cost(&self, cfg: &ModelConfig) -> Cost {
+        let prompt = token_cost(self.prompt_tokens - self.cached_tokens, cfg.prompt_cost())
+            + token_cost(self.cached_tokens, cfg.cached_prompt_cost());
+        let completion = token_cost(self.completion_tokens, cfg.completion_cost());
+        Cost {
+            prompt,
+            completion,
+            total: prompt + completion,
+        }
+    }
+
+    /// Prompt tokens for the summary line, e.g. `1000 (800 cached, 80.0% hit)`.
+    pub fn prompt_label(&self) -> String {
+        if self.cached_tokens > 0 {
+            format!(
+                "{} ({} cached, {:.1}% hit)",
+                self.prompt_tokens,
+                self.cached_tokens,
+                self.cached_tokens as f64 * 100.0 / self.prompt_tokens as f64
+            )
+        } else {
+            self.prompt_tokens.to_string()
+        }
+    }
+
+    /// Completion tokens for the summary line, e.g. `120 incl. 80 reasoning`.
+    pub fn completion_label(&self) -> String {
+        if self.reasoning_tokens > 0 {
+            format!(
+                "{} incl. {} reasoning",
+                self.completion_tokens, self.reasoning_tokens
+            )
+        } else {
+            self.completion_tokens.to_string()
+        }
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+        self.cached_tokens += other.cached_tokens;
+    }
+}
+
+/// The usage of every API request made during one invocation, so retries,
+/// follow-ups, continuations, chunks and per-file requests are all paid for
+/// in the summary.
+#[derive(Debug, Default)]
+pub struct UsageLog {
+    requests: Vec<(String, Usage)>,
+}
+
+impl UsageLog {
+    /// Records the `usage` of a request under `label`; requests that report
+    /// no usage are skipped.
+    pub fn record(&mut self, label: impl Into<String>, usage: Usage) {
+        if usage != Usage::default() {
+            self.requests.push((label.into(), usage));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// The usage of the first recorded request.
+    pub fn first(&self) -> Option<Usage> {
+        self.requests.first().map(|(_, usage)| *usage)
+    }
+
+    /// The usage of all recorded requests added up, or `None` when no
+    /// request reported any.
+    pub fn total(&self) -> Option<Usage> {
+        let (_, first) = self.requests.first()?;
+        let mut total = *first;
+        for (_, usage) in &self.requests[1..] {
+            total += *usage;
+        }
+        Some(total)
+    }
+
+    /// The dollar cost of all recorded requests.
+    pub fn cost(&self, cfg: &ModelConfig) -> f64 {
+        self.total().map_or(0.0, |usage| usage.cost(cfg).total)
+    }
+
+    /// One line per request, printed with `--verbose`.
+    pub fn breakdown(&self, cfg: &ModelConfig) -> String {
+        self.requests
+            .iter()
+            .enumerate()
+            .map(|(index, (label, usage))| {
+                format!(
+                    "#   {}. {}: prompt {} | completion {} | ${:.4}\n",
+                    index + 1,
+                    label,
+                    usage.prompt_label(),
+                    usage.completion_label(),
+                    usage.cost(cfg).total
+                )
+            })
+            .collect()
+    }
+}
+
+/// Cost of `tokens` at `cost_per_million` dollars per million tokens.
+pub fn token_cost(tokens: u64, cost_per_million: f64) -> f64 {
+    tokens as f64 * cost_per_million / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_response() {
+        let res = json!({ "usage": {
+            "prompt_tokens": 1000,
+            "completion_tokens": 120,
+            "completion_tokens_details": { "reasoning_tokens": 80 }
+        }});
+        let usage = Usage::from_response(&res).unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 1000,
+                completion_tokens: 120,
+                reasoning_tokens: 80,
+                cached_tokens: 0
+            }
+        );
+        assert_eq!(usage.total_tokens(), 1120);
+        assert_eq!(usage.completion_label(), "120 incl. 80 reasoning");
+
+        let res = json!({ "usage": {
+            "prompt_tokens": 10,
+            "completion_tokens": 5,
+            "completion_tokens_details": { "reasoning_tokens": null }
+        }});
+        assert_eq!(Usage::from_response(&res).unwrap().completion_label(), "5");
+
+        assert_eq!(Usage::from_response(&json!({ "choices": [] })), None);
+        assert_eq!(
+            Usage::from_response(&json!({ "usage": { "prompt_tokens": 10 } })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_usage_log() {
+        let mut log = UsageLog::default();
+        assert_eq!(log.total(), None);
+        log.record(
+            "request",
+            Usage {
+                prompt_tokens: 20,
+                completion_tokens: 7,
+                reasoning_tokens: 4,
+                cached_tokens: 0,
+            },
+        );
+        log.record("retry", Usage::default());
+        log.record(
+            "continuation 1",
+            Usage {
+                prompt_tokens: 30,
+                completion_tokens: 5,
+                reasoning_tokens: 0,
+                cached_tokens: 25,
+            },
+        );
+
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.first().unwrap().prompt_tokens, 20);
+        assert_eq!(
+            log.total(),
+            Some(Usage {
+                prompt_tokens: 50,
+                completion_tokens: 12,
+                reasoning_tokens: 4,
+                cached_tokens: 25
+            })
+        );
+        let cfg = ModelConfig {
+            prompt_cost: Some(1_000_000.0),
+            completion_cost: Some(0.0),
+            cached_prompt_cost: Some(0.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            log.breakdown(&cfg),
+            "#   1. request: prompt 20 | completion 7 incl. 4 reasoning | $20.0000\n\
+             #   2. continuation 1: prompt 30 (25 cached, 83.3% hit) | completion 5 | $5.0000\n"
+        );
+    }
+
+    #[test]
+    fn test_cost() {
+        let cfg = ModelConfig {
+            prompt_cost: Some(2.0),
+            completion_cost: Some(8.0),
+            ..Default::default()
+        };
+        let usage = Usage {
+            prompt_tokens: 500_000,
+            completion_tokens: 250_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            usage.cost(&cfg),
+            Cost {
+                prompt: 1.0,
+                completion: 2.0,
+                total: 3.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_cost_with_cached_tokens() {
+        let res = json!({ "usage": {
+            "prompt_tokens": 1_000_000,
+            "completion_tokens": 0,
+            "prompt_tokens_details": { "cached_tokens": 800_000 }
+        }});
+        let usage = Usage::from_response(&res).unwrap();
+        assert_eq!(usage.prompt_label(), "1000000 (800000 cached, 80.0% hit)");
+
+        let cfg = ModelConfig {
+            prompt_cost: Some(2.0),
+            cached_prompt_cost: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(usage.cost(&cfg).prompt, 0.4 + 0.4);
+
+        let uncached_rate = ModelConfig {
+            prompt_cost: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(usage.cost(&uncached_rate).prompt, 2.0);
+    }
+
+    #[test]
+    fn test_add_assign() {
+        let mut usage = Usage::default();
+        usage += Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            reasoning_tokens: 2,
+            cached_tokens: 4,
+        };
+        usage += Usage {
+            prompt_tokens: 20,
+            completion_tokens: 7,
+            ..Default::default()
+        };
+        assert_eq!(usage.total_tokens(), 42);
+        assert_eq!(usage.reasoning_tokens, 2);
+        assert_eq!(usage.cached_tokens, 4);
+    }
+}
@@ -1,14 +1,386 @@
 use crate::app::LlmpalError;
+use encoding_rs::{Encoding, UTF_8};
 use std::fs;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
 
-pub fn write_diagnostic_log(content: &str) -> Result<(), LlmpalError> {
-    let home_dir = match std::env::var("HOME") {
-        Ok(h) => Path::new(&h).to_path_buf(),
-        Err(_) => return Ok(()),
+const CASE_INSENSITIVE_FS: bool = cfg!(any(windows, target_os = "macos"));
+
+pub fn normalize_path(path: &str, case_insensitive: bool) -> String {
+    let unified = path.replace('\\', "/");
+    let normalized = unified
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<&str>>()
+        .join("/");
+    let normalized = if unified.starts_with('/') {
+        format!("/{}", normalized)
+    } else {
+        normalized
+    };
+
+    if case_insensitive {
+        normalized.to_lowercase()
+    } else {
+        normalized
+    }
+}
+
+pub fn paths_match(a: &str, b: &str) -> bool {
+    normalize_path(a, CASE_INSENSITIVE_FS) == normalize_path(b, CASE_INSENSITIVE_FS)
+}
+
+/// `path` made absolute with `.` and `..` resolved, and its longest existing
+/// ancestor canonicalized so symlinks are followed. `None` when `..` climbs
+/// above the root.
+fn resolve_path(path: &Path) -> Option<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
     };
+    let mut lexical = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !lexical.pop() {
+                    return None;
+                }
+            }
+            other => lexical.push(other),
+        }
+    }
+    let existing = lexical.ancestors().find(|ancestor| ancestor.exists())?;
+    let tail = lexical.strip_prefix(existing).ok()?;
+    Some(existing.canonicalize().ok()?.join(tail))
+}
+
+/// Whether `path` resolves to a location inside directory `dir`. A path with
+/// a `..` component never is: `..` is resolved lexically here, but the OS
+/// resolves it after following symlinks, so `dir/link/../x` may land elsewhere.
+pub fn is_under(path: &str, dir: &str) -> bool {
+    if Path::new(path)
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+    {
+        return false;
+    }
+    match (resolve_path(Path::new(path)), resolve_path(Path::new(dir))) {
+        (Some(path), Some(dir)) => path != dir && path.starts_with(dir),
+        _ => false,
+    }
+}
+
+/// Renders paths as an indented directory tree, directories suffixed with `/`.
+pub fn format_tree(paths: &[String]) -> String {
+    let mut sorted: Vec<Vec<String>> = paths
+        .iter()
+        .map(|path| {
+            normalize_path(path, false)
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .collect();
+    sorted.sort();
+    sorted.dedup();
+
+    let mut tree = String::new();
+    let mut previous_dirs: &[String] = &[];
+    for parts in &sorted {
+        let Some((file, dirs)) = parts.split_last() else {
+            continue;
+        };
+        let common = previous_dirs
+            .iter()
+            .zip(dirs)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            tree.push_str(&format!("{}{}/\n", "  ".repeat(depth), dir));
+        }
+        tree.push_str(&format!("{}{}\n", "  ".repeat(dirs.len()), file));
+        previous_dirs = dirs;
+    }
+    tree
+}
+
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffTag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(DiffTag, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
 
-    let diag_dir = home_dir.join(".llmpal");
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops: Vec<(DiffTag, &str)> =
+        old[..prefix].iter().map(|l| (DiffTag::Equal, *l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push((DiffTag::Equal, a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push((DiffTag::Delete, a[i]));
+            i += 1;
+        } else {
+            ops.push((DiffTag::Insert, b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(a[i..].iter().map(|l| (DiffTag::Delete, *l)));
+    ops.extend(b[j..].iter().map(|l| (DiffTag::Insert, *l)));
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|l| (DiffTag::Equal, *l)),
+    );
+    ops
+}
+
+/// Line-based unified diff of `old` and `new`; empty when they have the same lines.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let hunks = diff_hunks(old, new, false);
+    if hunks.is_empty() {
+        return String::new();
+    }
+    format!("--- a/{}\n+++ b/{}\n{}", path, path, hunks)
+}
+
+/// A `git apply` compatible patch turning `old` into `new`; `old` is `None`
+/// for a file that doesn't exist yet. Empty when nothing changes.
+pub fn git_patch(path: &str, old: Option<&str>, new: &str) -> String {
+    let hunks = diff_hunks(old.unwrap_or_default(), new, true);
+    if hunks.is_empty() && old.is_some() {
+        return String::new();
+    }
+    match old {
+        Some(_) => format!(
+            "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n{1}",
+            path, hunks
+        ),
+        None => format!(
+            "diff --git a/{0} b/{0}\nnew file mode 100644\n--- /dev/null\n+++ b/{0}\n{1}",
+            path, hunks
+        ),
+    }
+}
+
+/// The `@@` hunks of a diff from `old` to `new`. With `exact`, lines keep their
+/// terminator, so a change of the final newline shows up as
+/// `\ No newline at end of file` instead of being ignored.
+fn diff_hunks(old: &str, new: &str, exact: bool) -> String {
+    fn split(text: &str, exact: bool) -> Vec<&str> {
+        if exact {
+            text.split_inclusive('\n').collect()
+        } else {
+            text.lines().collect()
+        }
+    }
+    let (old_lines, new_lines) = (split(old, exact), split(new, exact));
+    let ops = diff_lines(&old_lines, &new_lines);
+
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, (tag, _)) in ops.iter().enumerate() {
+        if *tag == DiffTag::Equal {
+            continue;
+        }
+        match hunks.last_mut() {
+            Some((_, last)) if index <= *last + 2 * DIFF_CONTEXT + 1 => *last = index,
+            _ => hunks.push((index, index)),
+        }
+    }
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_line, mut new_line) = (0, 0);
+    for (tag, _) in &ops {
+        positions.push((old_line, new_line));
+        if *tag != DiffTag::Insert {
+            old_line += 1;
+        }
+        if *tag != DiffTag::Delete {
+            new_line += 1;
+        }
+    }
+
+    let mut diff = String::new();
+    for (first, last) in hunks {
+        let start = first.saturating_sub(DIFF_CONTEXT);
+        let end = (last + DIFF_CONTEXT + 1).min(ops.len());
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(t, _)| *t != DiffTag::Insert).count();
+        let new_len = hunk.iter().filter(|(t, _)| *t != DiffTag::Delete).count();
+        let (old_start, new_start) = positions[start];
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + usize::from(old_len > 0),
+            old_len,
+            new_start + usize::from(new_len > 0),
+            new_len
+        ));
+        for (tag, line) in hunk {
+            let marker = match tag {
+                DiffTag::Equal => ' ',
+                DiffTag::Delete => '-',
+                DiffTag::Insert => '+',
+            };
+            match line.strip_suffix('\n') {
+                Some(line) => diff.push_str(&format!("{}{}\n", marker, line)),
+                None if !exact => diff.push_str(&format!("{}{}\n", marker, line)),
+                None => diff.push_str(&format!(
+                    "{}{}\n\\ No newline at end of file\n",
+                    marker, line
+                )),
+            }
+        }
+    }
+    diff
+}
+
+pub fn line_delta(old: &str, new: &str) -> (usize, usize) {
+    let mut counts: std::collections::HashMap<&str, isize> = std::collections::HashMap::new();
+    for line in old.lines() {
+        *counts.entry(line).or_default() -= 1;
+    }
+    for line in new.lines() {
+        *counts.entry(line).or_default() += 1;
+    }
+
+    counts.values().fold((0, 0), |(added, removed), &count| {
+        if count > 0 {
+            (added + count as usize, removed)
+        } else {
+            (added, removed + (-count) as usize)
+        }
+    })
+}
+
+const CODE_EXTENSIONS: &[&str] = &[
+    "rs", "py", "js", "jsx", "ts", "tsx", "go", "java", "kt", "scala", "c", "h", "cc", "cpp",
+    "hpp", "cs", "rb", "php", "swift", "sh", "bash", "sql", "lua", "zig", "dart", "ex", "exs",
+    "hs", "ml", "clj", "vue", "svelte",
+];
+
+/// Whether the path has the extension of a programming language source file.
+pub fn is_code_file(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| CODE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Expands the `{model}` and `{timestamp}` placeholders of an `--output` path.
+/// Path separators in the model are replaced so it stays one path component.
+pub fn expand_output_template(
+    template: &str,
+    model: &str,
+    timestamp: u64,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in output path '{}'", template))?;
+        match &rest[start + 1..start + end] {
+            "model" => expanded.push_str(&model.replace(['/', '\\', ':'], "_")),
+            "timestamp" => expanded.push_str(&timestamp.to_string()),
+            other => {
+                return Err(format!(
+                    "Unknown placeholder '{{{}}}' in output path '{}' (available: {{model}}, {{timestamp}})",
+                    other, template
+                ));
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// A byte count as `512B`, `1.2KB` or `3.4MB`.
+pub fn format_size(bytes: usize) -> String {
+    const KB: f64 = 1024.0;
+    let bytes_f = bytes as f64;
+    if bytes_f >= KB * KB {
+        format!("{:.1}MB", bytes_f / (KB * KB))
+    } else if bytes_f >= KB {
+        format!("{:.1}KB", bytes_f / KB)
+    } else {
+        format!("{}B", bytes)
+    }
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    let from_env = |name: &str| {
+        std::env::var_os(name)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+
+    from_env("HOME")
+        .or_else(|| {
+            if cfg!(windows) {
+                from_env("USERPROFILE")
+            } else {
+                None
+            }
+        })
+        .or_else(dirs::home_dir)
+}
+
+#[cfg(not(test))]
+pub fn llmpal_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".llmpal"))
+}
+
+/// Unit tests get a directory of their own, so they never write dumps, locks
+/// or caches into the real `~/.llmpal`.
+#[cfg(test)]
+pub fn llmpal_dir() -> Option<PathBuf> {
+    static TEST_HOME: std::sync::LazyLock<tempfile::TempDir> =
+        std::sync::LazyLock::new(|| tempfile::tempdir().unwrap());
+    Some(TEST_HOME.path().join(".llmpal"))
+}
+
+pub fn write_diagnostic_log(content: &str) -> Result<(), LlmpalError> {
+    let diag_dir = match llmpal_dir() {
+        Some(dir) => dir,
+        None => {
+            debug!("No home directory found, skipping diagnostic log");
+            return Ok(());
+        }
+    };
 
     if !diag_dir.exists() {
         fs::create_dir_all(&diag_dir).map_err(|e| {
@@ -21,12 +393,746 @@ pub fn write_diagnostic_log(content: &str) -> Result<(), LlmpalError> {
         .map_err(|e| LlmpalError::FileError(format!("failed to write diagnostic log: {}", e)))
 }
 
+/// Dumps kept in `~/.llmpal/dumps/` longer than this are removed on startup.
+const DUMP_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The `.partial` file of a dump being written.
+static DUMP_IN_PROGRESS: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn dumps_dir() -> PathBuf {
+    llmpal_dir()
+        .map(|dir| dir.join("dumps"))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
 pub fn write_dump_log(content: &str) -> Result<String, String> {
+    write_dump_log_in(&dumps_dir(), content)
+}
+
+fn write_dump_log_in(dir: &Path, content: &str) -> Result<String, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create dump directory: {}", e))?;
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let filename = format!("dump_{}.log", timestamp);
-    fs::write(&filename, content).map_err(|e| format!("Failed to save dump log: {}", e))?;
-    Ok(filename)
+    let path = dir.join(format!("dump_{}.log", timestamp));
+    write_dump_to(&path, content)?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Writes a dump through a `.partial` file, renamed to `path` once complete.
+pub fn write_dump_to(path: &Path, content: &str) -> Result<(), String> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    *DUMP_IN_PROGRESS.lock().unwrap() = Some(partial.clone());
+    let result = fs::write(&partial, content).and_then(|_| fs::rename(&partial, path));
+    DUMP_IN_PROGRESS.lock().unwrap().take();
+    result.map_err(|e| format!("Failed to save dump log: {}", e))
+}
+
+/// Reports a dump interrupted mid-write (e.g. by Ctrl-C). It keeps its
+/// `.partial` name, so it is not mistaken for a complete dump.
+pub fn finalize_dump() {
+    if let Some(partial) = DUMP_IN_PROGRESS
+        .lock()
+        .ok()
+        .and_then(|mut dump| dump.take())
+        && partial.exists()
+    {
+        eprintln!(
+            "> Warning: interrupted while saving a dump; the incomplete dump is {}",
+            partial.display()
+        );
+    }
+}
+
+pub fn cleanup_old_dumps() {
+    let removed = cleanup_dumps_in(&dumps_dir(), DUMP_MAX_AGE);
+    if removed > 0 {
+        debug!("Removed {} old dump file(s)", removed);
+    }
+}
+
+/// Removes `dump_*` files, finished or not, last modified more than `max_age` ago.
+fn cleanup_dumps_in(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("dump_"))
+        .filter(|entry| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= max_age)
+        })
+        .filter(|entry| fs::remove_file(entry.path()).is_ok())
+        .count()
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it over the
+/// target, so an interrupted write leaves either the old or the new content.
+/// A symlink is followed, so the file it points to is replaced, not the link.
+pub fn write_atomic(path: &str, content: &str) -> Result<(), LlmpalError> {
+    write_atomic_bytes(path, content.as_bytes())
+}
+
+fn write_atomic_bytes(path: &str, content: &[u8]) -> Result<(), LlmpalError> {
+    let resolved = fs::canonicalize(path).ok();
+    let target = resolved.as_deref().unwrap_or(Path::new(path));
+    let file_name = target.file_name().ok_or_else(|| {
+        LlmpalError::FileError(format!("writing file '{}': not a file path", path))
+    })?;
+    let temp_path = target.with_file_name(format!(
+        ".{}.llmpal-tmp-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let error =
+        |e: std::io::Error| LlmpalError::FileError(format!("writing file '{}': {}", path, e));
+
+    let result = (|| {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(target) {
+            fs::set_permissions(&temp_path, metadata.permissions())?;
+        }
+        fs::rename(&temp_path, target)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map_err(error)
+}
+
+/// Decodes file content, detecting the encoding when it is not UTF-8.
+fn decode_text(bytes: &[u8]) -> (String, &'static Encoding) {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return (text.to_string(), UTF_8);
+    }
+    let mut detector = chardetng::EncodingDetector::new(chardetng::Iso2022JpDetection::Deny);
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, chardetng::Utf8Detection::Deny);
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    (text.into_owned(), encoding)
+}
+
+/// Reads a text file; non-UTF-8 files (e.g. Windows-1252) are decoded
+/// from their detected encoding.
+pub fn read_text_file(path: &str) -> std::io::Result<String> {
+    fs::read(path).map(|bytes| decode_text(&bytes).0)
+}
+
+/// Splits an input like `src/big.rs:100-200` into the path and its 1-based,
+/// inclusive line range. `None` for plain paths, including existing files
+/// whose name happens to end like a range.
+pub fn split_line_range(spec: &str) -> Option<(&str, usize, usize)> {
+    let (path, range) = spec.rsplit_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    if path.is_empty() || start == 0 || start > end || Path::new(spec).exists() {
+        return None;
+    }
+    Some((path, start, end))
+}
+
+/// Lines `start..=end` (1-based) of the file behind a `path:start-end` input.
+pub fn read_line_range(path: &str, start: usize, end: usize) -> Result<String, String> {
+    let content = read_text_file(path).map_err(|e| e.to_string())?;
+    let lines: Vec<&str> = content.lines().collect();
+    if start > lines.len() {
+        return Err(format!(
+            "line range starts at {} but the file has {} lines",
+            start,
+            lines.len()
+        ));
+    }
+    Ok(lines[start - 1..end.min(lines.len())].join("\n"))
+}
+
+/// The `--output-encoding` label as an encoding.
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, LlmpalError> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| LlmpalError::UsageError(format!("Unknown output encoding '{}'", label)))
+}
+
+/// Writes `content` in `encoding`, or else in the encoding detected for the
+/// existing file, so non-UTF-8 files keep their encoding. New files are UTF-8.
+pub fn write_encoded(
+    path: &str,
+    content: &str,
+    encoding: Option<&'static Encoding>,
+) -> Result<(), LlmpalError> {
+    let encoding = encoding
+        .or_else(|| fs::read(path).ok().map(|bytes| decode_text(&bytes).1))
+        .unwrap_or(UTF_8);
+    if encoding == UTF_8 {
+        return write_atomic(path, content);
+    }
+    let (bytes, _, unmappable) = encoding.encode(content);
+    if unmappable {
+        eprintln!(
+            "> Warning: {} has characters not representable in {}; they were written as &#...; references",
+            path,
+            encoding.name()
+        );
+    }
+    write_atomic_bytes(path, &bytes)
+}
+
+/// Creates the missing parent directories of `path`, for new files inside
+/// an `--allow-under` directory.
+pub fn create_parent_dirs(path: &str) -> Result<(), LlmpalError> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+            fs::create_dir_all(parent).map_err(|e| {
+                LlmpalError::FileError(format!(
+                    "Cannot create directory '{}': {}",
+                    parent.display(),
+                    e
+                ))
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn ensure_output_writable(output: &str, mkdir: bool) -> Result<(), LlmpalError> {
+    let path = Path::new(output);
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+
+    if !parent.is_dir() {
+        if !mkdir {
+            return Err(LlmpalError::FileError(format!(
+                "Output directory '{}' does not exist (use --mkdir to create it)",
+                parent.display()
+            )));
+        }
+        fs::create_dir_all(&parent).map_err(|e| {
+            LlmpalError::FileError(format!(
+                "Cannot create output directory '{}': {}",
+                parent.display(),
+                e
+            ))
+        })?;
+    }
+
+    if path.is_dir() {
+        return Err(LlmpalError::FileError(format!(
+            "Output path '{}' is a directory",
+            output
+        )));
+    }
+
+    let writable = if path.exists() {
+        fs::OpenOptions::new().append(true).open(path).is_ok()
+    } else {
+        let probe = parent.join(format!(".llmpal-write-check-{}", std::process::id()));
+        let created = fs::File::create(&probe).is_ok();
+        let _ = fs::remove_file(&probe);
+        created
+    };
+
+    if !writable {
+        return Err(LlmpalError::FileError(format!(
+            "Output file '{}' is not writable",
+            output
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn git_diff(reference: &str) -> Result<String, LlmpalError> {
+    let output = Command::new("git")
+        .args(["diff", reference])
+        .output()
+        .map_err(|e| LlmpalError::FileError(format!("Cannot run git: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(LlmpalError::FileError(format!(
+            "git diff {} failed: {}",
+            reference,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Uncommitted changes of `path` against `HEAD`, or `None` when the file is
+/// untracked, unchanged, or not inside a git repository.
+pub fn git_file_diff(path: &str) -> Option<String> {
+    let output = Command::new("git")
+        .args(["diff", "HEAD", "--", path])
+        .output()
+        .ok()?;
+    let patch = String::from_utf8_lossy(&output.stdout);
+    (output.status.success() && !patch.trim().is_empty()).then(|| patch.into_owned())
+}
+
+/// Converts CRLF line endings to LF and strips trailing whitespace from every
+/// line, keeping a final newline if there was one.
+pub fn strip_trailing_whitespace(content: &str) -> String {
+    let mut stripped = content
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n");
+    if content.ends_with('\n') {
+        stripped.push('\n');
+    }
+    stripped
+}
+
+pub const PROTECT_START: &str = "llmpal:protect-start";
+pub const PROTECT_END: &str = "llmpal:protect-end";
+
+/// The regions between `llmpal:protect-start` and `llmpal:protect-end` marker
+/// lines, markers included. An unterminated region runs to the end of the file.
+pub fn protected_regions(content: &str) -> Vec<String> {
+    let mut regions = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in content.lines() {
+        if let Some(region) = current.as_mut() {
+            region.push(line);
+            if line.contains(PROTECT_END) {
+                regions.push(region.join("\n"));
+                current = None;
+            }
+        } else if line.contains(PROTECT_START) {
+            current = Some(vec![line]);
+        }
+    }
+    if let Some(region) = current {
+        regions.push(region.join("\n"));
+    }
+    regions
+}
+
+pub fn protected_regions_changed(old: &str, new: &str) -> bool {
+    protected_regions(old) != protected_regions(new)
+}
+
+/// Runs `command` through the shell and returns its stdout, cut to at most
+/// `max_bytes`. A non-zero exit status is only warned about, since failing
+/// commands (e.g. `cargo check`) are often the context being asked for.
+pub fn command_output(command: &str, max_bytes: usize) -> Result<String, LlmpalError> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .args([flag, command])
+        .output()
+        .map_err(|e| LlmpalError::FileError(format!("Cannot run '{}': {}", command, e)))?;
+    if !output.status.success() {
+        eprintln!("> Warning: '{}' exited with {}", command, output.status);
+    }
+
+    let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if stdout.len() > max_bytes {
+        let mut cut = max_bytes;
+        while !stdout.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let dropped = stdout.len() - cut;
+        stdout.truncate(cut);
+        stdout.push_str(&format!("\n[... truncated {} bytes]", dropped));
+        eprintln!(
+            "> Warning: output of '{}' truncated to {} bytes",
+            command, max_bytes
+        );
+    }
+    Ok(stdout)
+}
+
+/// Reads a NUL-delimited list of paths, as produced by `git ls-files -z` or `find -print0`.
+pub fn read_null_delimited_paths<R: Read>(mut reader: R) -> Result<Vec<String>, LlmpalError> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| LlmpalError::FileError(format!("Cannot read file list: {}", e)))?;
+    bytes
+        .split(|byte| *byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            String::from_utf8(path.to_vec()).map_err(|_| {
+                LlmpalError::FileError(format!(
+                    "Path in file list is not valid UTF-8: {}",
+                    String::from_utf8_lossy(path)
+                ))
+            })
+        })
+        .collect()
+}
+
+pub fn read_tar_archive<R: Read>(reader: R) -> Result<Vec<(String, String)>, LlmpalError> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|e| LlmpalError::FileError(format!("Cannot read tar archive: {}", e)))?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let mut entry =
+            entry.map_err(|e| LlmpalError::FileError(format!("Cannot read tar entry: {}", e)))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(|e| LlmpalError::FileError(format!("Invalid tar entry path: {}", e)))?
+            .to_string_lossy()
+            .into_owned();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).map_err(|e| {
+            LlmpalError::FileError(format!("Cannot read tar entry '{}': {}", path, e))
+        })?;
+        files.push((path, content));
+    }
+    Ok(files)
+}
+
+pub fn write_tar_archive<W: Write>(
+    writer: W,
+    files: &[(String, String)],
+) -> Result<(), LlmpalError> {
+    let mut builder = tar::Builder::new(writer);
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .map_err(|e| {
+                LlmpalError::FileError(format!("Cannot write tar entry '{}': {}", path, e))
+            })?;
+    }
+    builder
+        .into_inner()
+        .and_then(|mut w| w.flush())
+        .map_err(|e| LlmpalError::FileError(format!("Cannot write tar archive: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dump_log_and_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumps = dir.path().join("dumps");
+        let path = write_dump_log_in(&dumps, "raw response").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "raw response");
+        fs::write(dumps.join("dump_1.log.partial"), "cut").unwrap();
+        fs::write(dumps.join("notes.txt"), "keep").unwrap();
+
+        assert_eq!(cleanup_dumps_in(&dumps, DUMP_MAX_AGE), 0);
+        assert_eq!(cleanup_dumps_in(&dumps, Duration::ZERO), 2);
+        assert!(dumps.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_strip_trailing_whitespace() {
+        assert_eq!(
+            strip_trailing_whitespace("fn a() {  \r\n    b();\t\r\n}\r\n"),
+            "fn a() {\n    b();\n}\n"
+        );
+        assert_eq!(strip_trailing_whitespace("x \ny"), "x\ny");
+    }
+
+    #[test]
+    fn test_protected_regions_changed() {
+        let old = "fn a() {}\n// llmpal:protect-start\nconst KEY: u8 = 1;\n// llmpal:protect-end\nfn b() {}";
+        let edited_outside = old.replace("fn b() {}", "fn b() { todo!() }");
+        assert!(!protected_regions_changed(old, &edited_outside));
+        assert!(protected_regions_changed(old, &old.replace("= 1", "= 2")));
+        assert!(protected_regions_changed(
+            old,
+            &old.replace("// llmpal:protect-end\n", "")
+        ));
+        assert!(!protected_regions_changed("no markers", "changed"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_output_truncates() {
+        assert_eq!(command_output("echo hello", 1024).unwrap(), "hello\n");
+        let output = command_output("printf 'abcdef'; exit 1", 3).unwrap();
+        assert_eq!(output, "abc\n[... truncated 3 bytes]");
+    }
+
+    #[test]
+    fn test_read_null_delimited_paths() {
+        let input: &[u8] = b"src/main.rs\0dir with space/a b.rs\0line\nbreak.txt\0";
+        assert_eq!(
+            read_null_delimited_paths(input).unwrap(),
+            vec!["src/main.rs", "dir with space/a b.rs", "line\nbreak.txt"]
+        );
+        let input: &[u8] = b"ok.rs\0\xff\xfe\0";
+        assert!(read_null_delimited_paths(input).is_err());
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nb\nc\nd\nE\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(
+            unified_diff("x.txt", old, new),
+            "--- a/x.txt\n+++ b/x.txt\n\
+             @@ -2,9 +2,10 @@\n b\n c\n d\n-e\n+E\n f\n g\n h\n i\n j\n+k\n"
+        );
+        assert_eq!(unified_diff("x.txt", old, old), "");
+        assert_eq!(
+            unified_diff("new.txt", "", "one\n"),
+            "--- a/new.txt\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+one\n"
+        );
+    }
+
+    #[test]
+    fn test_split_line_range() {
+        assert_eq!(
+            split_line_range("src/big.rs:100-200"),
+            Some(("src/big.rs", 100, 200))
+        );
+        assert_eq!(
+            split_line_range("C:/src/a.rs:1-1"),
+            Some(("C:/src/a.rs", 1, 1))
+        );
+        assert_eq!(split_line_range("src/big.rs"), None);
+        assert_eq!(split_line_range("src/big.rs:200-100"), None);
+        assert_eq!(split_line_range("src/big.rs:0-10"), None);
+        assert_eq!(split_line_range("src/big.rs:10"), None);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("big.rs");
+        fs::write(&path, "one\ntwo\nthree\nfour\n").unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(read_line_range(path, 2, 3).unwrap(), "two\nthree");
+        assert_eq!(read_line_range(path, 3, 100).unwrap(), "three\nfour");
+        assert!(read_line_range(path, 5, 6).is_err());
+    }
+
+    #[test]
+    fn test_git_patch() {
+        assert_eq!(git_patch("x.txt", Some("a\n"), "a\n"), "");
+        assert_eq!(
+            git_patch("x.txt", Some("a\nb\n"), "a\nb"),
+            "diff --git a/x.txt b/x.txt\n--- a/x.txt\n+++ b/x.txt\n\
+             @@ -1,2 +1,2 @@\n a\n-b\n+b\n\\ No newline at end of file\n"
+        );
+        assert_eq!(
+            git_patch("src/x.txt", Some("a\n"), "b\n"),
+            "diff --git a/src/x.txt b/src/x.txt\n--- a/src/x.txt\n+++ b/src/x.txt\n\
+             @@ -1,1 +1,1 @@\n-a\n+b\n"
+        );
+        assert_eq!(
+            git_patch("new.txt", None, ""),
+            "diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/new.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.rs");
+        let path = path.to_str().unwrap();
+
+        write_atomic(path, "new file").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "new file");
+
+        write_atomic(path, "replaced").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "replaced");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let missing_dir = dir.path().join("missing").join("file.rs");
+        assert!(write_atomic(missing_dir.to_str().unwrap(), "x").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_through_symlink() {
+        let dir = tempdir().unwrap();
+        let real = dir.path().join("real.rs");
+        let link = dir.path().join("link.rs");
+        fs::write(&real, "old").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        write_atomic(link.to_str().unwrap(), "new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&real).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_format_tree() {
+        let paths = vec![
+            "src/main.rs".to_string(),
+            "./README.md".to_string(),
+            "src/app/run.rs".to_string(),
+            "src/lib.rs".to_string(),
+            "tests/it.rs".to_string(),
+        ];
+        assert_eq!(
+            format_tree(&paths),
+            "README.md\nsrc/\n  app/\n    run.rs\n  lib.rs\n  main.rs\ntests/\n  it.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_ensure_output_writable_existing_dir() {
+        let dir = tempdir().unwrap();
+        let output = dir.path().join("out.txt");
+        assert!(ensure_output_writable(output.to_str().unwrap(), false).is_ok());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_ensure_output_writable_missing_parent() {
+        let dir = tempdir().unwrap();
+        let output = dir.path().join("missing").join("out.txt");
+        assert!(ensure_output_writable(output.to_str().unwrap(), false).is_err());
+        assert!(ensure_output_writable(output.to_str().unwrap(), true).is_ok());
+        assert!(dir.path().join("missing").is_dir());
+    }
+
+    #[test]
+    fn test_is_code_file() {
+        assert!(is_code_file("src/main.rs"));
+        assert!(is_code_file("web/App.TSX"));
+        assert!(!is_code_file("README.md"));
+        assert!(!is_code_file("Makefile"));
+    }
+
+    #[test]
+    fn test_write_encoded_keeps_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.txt");
+        let path = path.to_str().unwrap();
+        // "Café déjà vu, naïve façade" in Windows-1252
+        let (latin1, _, _) = encoding_rs::WINDOWS_1252.encode("Café déjà vu, naïve façade\n");
+        fs::write(path, &latin1).unwrap();
+
+        let text = read_text_file(path).unwrap();
+        assert_eq!(text, "Café déjà vu, naïve façade\n");
+
+        write_encoded(path, "Crème brûlée\n", None).unwrap();
+        let bytes = fs::read(path).unwrap();
+        assert!(std::str::from_utf8(&bytes).is_err());
+        assert_eq!(read_text_file(path).unwrap(), "Crème brûlée\n");
+
+        write_encoded(path, "Crème\n", Some(UTF_8)).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "Crème\n");
+        assert!(parse_encoding("latin1").is_ok());
+        assert!(parse_encoding("klingon").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(1229), "1.2KB");
+        assert_eq!(format_size(3 * 1024 * 1024 + 400 * 1024), "3.4MB");
+    }
+
+    #[test]
+    fn test_is_under() {
+        let root = tempfile::tempdir().unwrap();
+        let tests = root.path().join("tests");
+        fs::create_dir(&tests).unwrap();
+        let dir = tests.to_string_lossy().into_owned();
+        let inside = |rel: &str| format!("{}/{}", dir, rel);
+
+        assert!(is_under(&inside("new_test.rs"), &dir));
+        assert!(is_under(&inside("unit/./nested.rs"), &dir));
+        assert!(!is_under(&inside("../src/main.rs"), &dir));
+        assert!(!is_under(&inside("unit/../../x.rs"), &dir));
+        assert!(!is_under(&dir, &dir));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.path(), tests.join("escape")).unwrap();
+            assert!(!is_under(&inside("escape/secret.rs"), &dir));
+
+            let other = root.path().join("other");
+            fs::create_dir(&other).unwrap();
+            std::os::unix::fs::symlink(&other, tests.join("link")).unwrap();
+            assert!(!is_under(&inside("link/../x.rs"), &dir));
+        }
+    }
+
+    #[test]
+    fn test_expand_output_template() {
+        assert_eq!(
+            expand_output_template(
+                "out/{model}/{timestamp}/result.txt",
+                "openai/gpt-4o",
+                1760000000
+            ),
+            Ok("out/openai_gpt-4o/1760000000/result.txt".to_string())
+        );
+        assert_eq!(
+            expand_output_template("notes.md", "kimi", 1),
+            Ok("notes.md".to_string())
+        );
+        assert!(
+            expand_output_template("out/{date}.md", "kimi", 1)
+                .unwrap_err()
+                .contains("Unknown placeholder '{date}'")
+        );
+        assert!(expand_output_template("out/{model.md", "kimi", 1).is_err());
+    }
+
+    #[test]
+    fn test_line_delta() {
+        assert_eq!(line_delta("a\nb\nc", "a\nb\nc"), (0, 0));
+        assert_eq!(line_delta("a\nb\nc", "a\nx\nc\nd"), (2, 1));
+        assert_eq!(line_delta("", "a\nb"), (2, 0));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("./src//main.rs", false), "src/main.rs");
+        assert_eq!(normalize_path("src\\Main.rs", false), "src/Main.rs");
+        assert_eq!(normalize_path("SRC/Main.rs", true), "src/main.rs");
+        assert_eq!(normalize_path("/tmp/./x.rs", false), "/tmp/x.rs");
+    }
+
+    #[test]
+    fn test_paths_match() {
+        assert!(paths_match("./src/main.rs", "src/main.rs"));
+        assert_eq!(
+            paths_match("SRC/Main.rs", "src/main.rs"),
+            CASE_INSENSITIVE_FS
+        );
+    }
+
+    #[test]
+    fn test_tar_archive_roundtrip() {
+        let files = vec![
+            ("src/main.rs".to_string(), "fn main() {}\n".to_string()),
+            ("README.md".to_string(), "# Title".to_string()),
+        ];
+        let mut archive = Vec::new();
+        write_tar_archive(&mut archive, &files).unwrap();
+        let read_back = read_tar_archive(archive.as_slice()).unwrap();
+        assert_eq!(read_back, files);
+    }
+
+    #[test]
+    fn test_ensure_output_writable_rejects_directory() {
+        let dir = tempdir().unwrap();
+        assert!(ensure_output_writable(dir.path().to_str().unwrap(), false).is_err());
+    }
 }
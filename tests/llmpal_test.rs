@@ -2,6 +2,7 @@
 mod tests {
     use llmpal::app::run;
     use llmpal::config::Cli;
+    use llmpal::format::ResponseFormat;
     use mockito::Mock;
     use std::error::Error;
     use std::fs;
@@ -14,26 +15,26 @@ mod tests {
         fs::write(&test_file_path, "test content")?;
 
         let mut server = mockito::Server::new_async().await;
-        let api_url = server.url();
 
         let config_path = temp_dir.path().join(".llmpal.json");
-        let config_content = format!(
-            r#"{{
-                "models": [{{
+        let config_content = r#"{
+                "models": [{
                     "code": "test-model",
                     "model": "test-model",
                     "prompt_cost": 0.001,
                     "completion_cost": 0.001,
-                    "api_url": "{}",
                     "api_key": "test-key"
-                }}]
-            }}"#,
-            api_url
-        );
+                }]
+            }"#;
         fs::write(&config_path, config_content)?;
 
         let old_cwd = std::env::current_dir()?;
         std::env::set_current_dir(temp_dir.path())?;
+        // Dumps, locks and history go to the temporary home, not the real one.
+        let home = TempDir::new()?;
+        unsafe {
+            std::env::set_var("HOME", home.path());
+        }
 
         let mock_response = serde_json::json!({
             "choices": [{
@@ -61,10 +62,12 @@ mod tests {
             instruction: Option::from("Test instruction".to_string()),
             files: vec![test_file_path.to_str().unwrap().to_string()],
             model: None,
+            api_url: Some(server.url()),
             output: None,
             verbose: false,
             trace: false,
             instruction_file: None,
+            ..Default::default()
         };
 
         let result = run(&args).await;
@@ -73,6 +76,41 @@ mod tests {
         let content = fs::read_to_string(&test_file_path)?;
         assert_eq!(content, "modified content");
 
+        // The legacy `=== ... ===` markers are not part of the default tag format:
+        // such a response is rejected and leaves the file untouched, unless the
+        // markers format is selected explicitly.
+        _mock.remove_async().await;
+        let legacy_response = serde_json::json!({
+            "choices": [{
+                "message": {
+                    "content": format!("=== EXPLAIN START ===\nTest explanation\n=== EXPLAIN END ===\n=== {0} === START ===\nlegacy content\n=== {0} === END ===",
+                        &test_file_path.to_string_lossy(),
+                    )
+                }
+            }],
+            "usage": {
+                "prompt_tokens": 100,
+                "completion_tokens": 50
+            }
+        });
+        let _legacy_mock: Mock = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(legacy_response.to_string())
+            .create_async()
+            .await;
+
+        assert!(run(&args).await.is_err());
+        assert_eq!(fs::read_to_string(&test_file_path)?, "modified content");
+
+        let args = Cli {
+            response_format: Some(ResponseFormat::Markers),
+            ..args
+        };
+        assert!(run(&args).await.is_ok());
+        assert_eq!(fs::read_to_string(&test_file_path)?, "legacy content");
+
         std::env::set_current_dir(old_cwd)?;
         Ok(())
     }